@@ -0,0 +1,573 @@
+//! ACPI table discovery.
+//!
+//! The RSDP is taken from the multiboot2 ACPI tags (new one preferred), or
+//! found by scanning the BIOS area as a fallback. The XSDT (or RSDT for ACPI
+//! 1.0) is walked through PhysicalDirectMap and MADT, FADT and HPET are
+//! parsed into the typed structures below. Tables with a bad checksum are
+//! skipped.
+//!
+//! `\_S5` is dug out of the DSDT without an AML interpreter, by matching the
+//! usual `Name(_S5, Package() {...})` encoding, and handed to `power`.
+
+use core::{mem, ptr, slice, str};
+use collections::Vec;
+use spin::Once;
+
+use ::kern::bootinfo;
+use ::kern::console::LogLevel::*;
+use ::kern::arch::port::Port;
+use ::kern::memory::map_physical;
+use ::kern::memory::paging::{self, PhysicalAddress};
+use ::kern::power::{self, SleepControl, ResetRegister};
+
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+/// BIOS read-only area which may hold the RSDP, on 16 bytes boundary
+const BIOS_AREA: (usize, usize) = (0xE0000, 0x100000);
+
+/// PM1 control SCI_EN bit, set when ACPI mode is on
+const SCI_EN: u16 = 1;
+
+/// FADT flags: reset register is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// GenericAddress address spaces
+pub const GAS_SYSTEM_MEMORY: u8 = 0;
+pub const GAS_SYSTEM_IO: u8 = 1;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3]
+}
+
+/// common header of all system description tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32
+}
+
+/// Generic Address Structure
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64
+}
+
+/// a mapped table, the header plus its body
+#[derive(Clone, Copy)]
+struct Table {
+    paddr: PhysicalAddress,
+    data: &'static [u8]
+}
+
+impl Table {
+    fn header(&self) -> SdtHeader {
+        unsafe { ptr::read_unaligned(self.data.as_ptr() as *const SdtHeader) }
+    }
+
+    fn signature(&self) -> [u8; 4] {
+        self.header().signature
+    }
+
+    /// unaligned read at offset from table start, None if past the end
+    fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        read(self.data, offset)
+    }
+}
+
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    if offset + mem::size_of::<T>() > bytes.len() {
+        return None;
+    }
+    unsafe { Some(ptr::read_unaligned(bytes[offset..].as_ptr() as *const T)) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn map_bytes(paddr: PhysicalAddress, len: usize) -> &'static [u8] {
+    let vaddr = map_physical(paddr, len, paging::NO_EXECUTE);
+    unsafe { slice::from_raw_parts(vaddr as *const u8, len) }
+}
+
+/// map the header first to learn the full length
+fn map_table(paddr: PhysicalAddress) -> Option<Table> {
+    let hdr_len = mem::size_of::<SdtHeader>();
+    let len = match read::<u32>(map_bytes(paddr, hdr_len), 4) {
+        Some(len) if len as usize >= hdr_len => len as usize,
+        _ => return None
+    };
+
+    let table = Table { paddr: paddr, data: map_bytes(paddr, len) };
+    if !checksum_ok(table.data) {
+        printk!(Warn, "acpi: bad checksum for table {:?} at {:#x}\n\r",
+                str::from_utf8(&table.signature()), paddr);
+        return None;
+    }
+    Some(table)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// disabled but can be brought up at runtime
+    pub online_capable: bool
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysicalAddress,
+    /// first global system interrupt it handles
+    pub gsi_base: u32
+}
+
+/// ISA irq routed to some other global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags
+    pub flags: u16
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0x3 == 0x3
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0x3 == 0x3
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// u32::max_value() (0xff in the table) means all processors
+    pub processor_id: u32,
+    pub flags: u16,
+    pub lint: u8
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysicalAddress,
+    /// there are also legacy 8259s, which should be masked
+    pub pcat_compat: bool,
+    pub cpus: Vec<LocalApic>,
+    pub ioapics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>
+}
+
+impl Madt {
+    fn parse(t: &Table) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: t.read::<u32>(36).unwrap_or(0xFEE00000) as PhysicalAddress,
+            pcat_compat: t.read::<u32>(40).unwrap_or(0) & 1 != 0,
+            cpus: Vec::new(),
+            ioapics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new()
+        };
+
+        let mut off = 44;
+        while off + 2 <= t.data.len() {
+            let (typ, len) = (t.data[off], t.data[off + 1] as usize);
+            if len < 2 || off + len > t.data.len() {
+                break;
+            }
+            let e = &t.data[off..off + len];
+
+            match typ {
+                0 if len >= 8 => {
+                    let flags = read::<u32>(e, 4).unwrap();
+                    madt.cpus.push(LocalApic {
+                        processor_id: e[2] as u32,
+                        apic_id: e[3] as u32,
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0
+                    });
+                },
+                1 if len >= 12 => madt.ioapics.push(IoApic {
+                    id: e[2],
+                    address: read::<u32>(e, 4).unwrap() as PhysicalAddress,
+                    gsi_base: read::<u32>(e, 8).unwrap()
+                }),
+                2 if len >= 10 => madt.overrides.push(InterruptOverride {
+                    bus: e[2],
+                    source: e[3],
+                    gsi: read::<u32>(e, 4).unwrap(),
+                    flags: read::<u16>(e, 8).unwrap()
+                }),
+                4 if len >= 6 => madt.nmis.push(LocalApicNmi {
+                    processor_id: if e[2] == 0xff { u32::max_value() } else { e[2] as u32 },
+                    flags: read::<u16>(e, 3).unwrap(),
+                    lint: e[5]
+                }),
+                5 if len >= 12 => {
+                    madt.local_apic_address = read::<u64>(e, 4).unwrap() as PhysicalAddress;
+                },
+                9 if len >= 16 => {
+                    let flags = read::<u32>(e, 8).unwrap();
+                    madt.cpus.push(LocalApic {
+                        processor_id: read::<u32>(e, 12).unwrap(),
+                        apic_id: read::<u32>(e, 4).unwrap(),
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0
+                    });
+                },
+                0xA if len >= 12 => madt.nmis.push(LocalApicNmi {
+                    processor_id: read::<u32>(e, 4).unwrap(),
+                    flags: read::<u16>(e, 2).unwrap(),
+                    lint: e[8]
+                }),
+                _ => {}
+            }
+            off += len;
+        }
+
+        madt
+    }
+
+    /// global system interrupt and flags an ISA irq is wired to
+    pub fn isa_irq(&self, irq: u8) -> (u32, u16) {
+        match self.overrides.iter().find(|o| o.bus == 0 && o.source == irq) {
+            Some(o) => (o.gsi, o.flags),
+            None => (irq as u32, 0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: PhysicalAddress,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm_timer_length: u8,
+    /// CMOS index of the century register, 0 if there is none
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8
+}
+
+impl Fadt {
+    fn parse(t: &Table) -> Fadt {
+        let flags = t.read::<u32>(112).unwrap_or(0);
+        let reset_register = match t.read::<GenericAddress>(116) {
+            Some(gas) if flags & RESET_REG_SUP != 0 && gas.address != 0 => Some(gas),
+            _ => None
+        };
+
+        // 64 bits X_DSDT wins if it's there
+        let dsdt = match t.read::<u64>(140) {
+            Some(x) if x != 0 => x as PhysicalAddress,
+            _ => t.read::<u32>(40).unwrap_or(0) as PhysicalAddress
+        };
+
+        Fadt {
+            dsdt: dsdt,
+            sci_interrupt: t.read(46).unwrap_or(0),
+            smi_command_port: t.read(48).unwrap_or(0),
+            acpi_enable: t.read(52).unwrap_or(0),
+            acpi_disable: t.read(53).unwrap_or(0),
+            pm1a_event_block: t.read(56).unwrap_or(0),
+            pm1b_event_block: t.read(60).unwrap_or(0),
+            pm1a_control_block: t.read(64).unwrap_or(0),
+            pm1b_control_block: t.read(68).unwrap_or(0),
+            pm_timer_block: t.read(76).unwrap_or(0),
+            pm_timer_length: t.read(91).unwrap_or(0),
+            century: t.read(108).unwrap_or(0),
+            iapc_boot_arch: t.read(109).unwrap_or(0),
+            flags: flags,
+            reset_register: reset_register,
+            reset_value: t.read(128).unwrap_or(0)
+        }
+    }
+
+    fn acpi_enabled(&self) -> bool {
+        self.pm1a_control_block == 0 ||
+            Port::<u16>::new(self.pm1a_control_block as u16).read() & SCI_EN != 0
+    }
+
+    /// switch from legacy to ACPI mode if firmware didn't. QEMU boots in ACPI
+    /// mode already.
+    fn enable_acpi(&self) {
+        if self.acpi_enabled() || self.smi_command_port == 0 || self.acpi_enable == 0 {
+            return;
+        }
+
+        Port::<u8>::new(self.smi_command_port as u16).write(self.acpi_enable);
+        for _ in 0..1000000 {
+            if self.acpi_enabled() {
+                return;
+            }
+            ::kern::util::cpu_relax();
+        }
+        printk!(Warn, "acpi: failed to enter ACPI mode\n\r");
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_rev_id: u8,
+    /// number of comparators (timers)
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub address: PhysicalAddress,
+    pub hpet_number: u8,
+    /// minimum clock ticks for periodic mode without losing interrupts
+    pub min_tick: u16
+}
+
+impl Hpet {
+    fn parse(t: &Table) -> Option<Hpet> {
+        let id = match t.read::<u32>(36) {
+            Some(id) => id,
+            None => return None
+        };
+        let gas = match t.read::<GenericAddress>(40) {
+            Some(gas) if gas.address_space == GAS_SYSTEM_MEMORY => gas,
+            _ => return None
+        };
+
+        Some(Hpet {
+            hardware_rev_id: id as u8,
+            comparators: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            address: gas.address as PhysicalAddress,
+            hpet_number: t.read(52).unwrap_or(0),
+            min_tick: t.read(53).unwrap_or(0)
+        })
+    }
+}
+
+/// find SLP_TYPa and SLP_TYPb of \_S5 in DSDT AML
+fn parse_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let body = &dsdt[mem::size_of::<SdtHeader>()..];
+    let pos = match body.windows(4).position(|w| w == b"_S5_") {
+        Some(pos) => pos,
+        None => return None
+    };
+
+    // NameOp, possibly followed by root prefix, then name and PackageOp
+    let name_op = (pos >= 1 && body[pos - 1] == 0x08) ||
+        (pos >= 2 && body[pos - 2] == 0x08 && body[pos - 1] == b'\\');
+    let mut p = pos + 4;
+    if !name_op || body.get(p) != Some(&0x12) {
+        return None;
+    }
+    p += 1;
+
+    // PkgLength: bits 6-7 of the lead byte count the extra bytes
+    let lead = match body.get(p) {
+        Some(&b) => b,
+        None => return None
+    };
+    p += 1 + (lead >> 6) as usize;
+    // NumElements
+    p += 1;
+
+    let mut element = || -> Option<u16> {
+        let v = match body.get(p) {
+            // BytePrefix
+            Some(&0x0A) => {
+                p += 1;
+                body.get(p).map(|&b| b as u16)
+            },
+            // ZeroOp, OneOp or raw byte
+            Some(&b) => Some(b as u16),
+            None => None
+        };
+        p += 1;
+        v
+    };
+
+    match (element(), element()) {
+        (Some(a), Some(b)) => Some((a, b)),
+        _ => None
+    }
+}
+
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// (SLP_TYPa, SLP_TYPb)
+    pub s5: Option<(u16, u16)>
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// parsed tables, None if there is no ACPI or init hasn't run
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.try()
+}
+
+fn rsdp_valid(bytes: &[u8]) -> bool {
+    if bytes.len() < 20 || &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(&bytes[..20]) {
+        return false;
+    }
+    // revision 2+ is covered by extended checksum
+    match read::<u32>(bytes, 20) {
+        Some(len) if bytes[15] >= 2 && len as usize <= bytes.len() => checksum_ok(&bytes[..len as usize]),
+        _ => true
+    }
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let from_tag = bootinfo::find_tag(bootinfo::TAG_ACPI_NEW)
+        .or_else(|| bootinfo::find_tag(bootinfo::TAG_ACPI_OLD))
+        .map(|tag| tag.payload());
+
+    let bytes = match from_tag {
+        Some(rsdp) if rsdp_valid(rsdp) => rsdp,
+        _ => {
+            let area = map_bytes(BIOS_AREA.0, BIOS_AREA.1 - BIOS_AREA.0);
+            match (0..area.len() / 16).map(|i| &area[i * 16..]).find(|b| rsdp_valid(b)) {
+                Some(rsdp) => rsdp,
+                None => return None
+            }
+        }
+    };
+
+    // an ACPI 1.0 RSDP stops after rsdt_address
+    let mut buf = [0u8; 36];
+    let n = ::core::cmp::min(bytes.len(), buf.len());
+    buf[..n].copy_from_slice(&bytes[..n]);
+    if buf[15] < 2 {
+        for b in buf[20..].iter_mut() { *b = 0; }
+    }
+    Some(unsafe { ptr::read_unaligned(buf.as_ptr() as *const Rsdp) })
+}
+
+/// physical addresses of all tables listed in XSDT or RSDT
+fn table_addresses(rsdp: &Rsdp) -> Vec<PhysicalAddress> {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as PhysicalAddress, 8)
+    } else {
+        (rsdp.rsdt_address as PhysicalAddress, 4)
+    };
+
+    let root = match map_table(root) {
+        Some(t) => t,
+        None => return Vec::new()
+    };
+
+    let n = (root.data.len() - mem::size_of::<SdtHeader>()) / entry_size;
+    (0..n).filter_map(|i| {
+        let off = mem::size_of::<SdtHeader>() + i * entry_size;
+        match entry_size {
+            8 => root.read::<u64>(off).map(|a| a as PhysicalAddress),
+            _ => root.read::<u32>(off).map(|a| a as PhysicalAddress)
+        }
+    }).collect()
+}
+
+/// find and parse tables, needs kernel heap. hooks up poweroff and reset.
+pub fn init() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            printk!(Warn, "acpi: no RSDP found\n\r");
+            return;
+        }
+    };
+
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        s5: None
+    };
+    printk!(Info, "acpi: revision {}, oem {:?}\n\r", acpi.revision, str::from_utf8(&acpi.oem_id));
+
+    for table in table_addresses(&rsdp).into_iter().filter_map(map_table) {
+        printk!(Debug, "acpi: {:?} at {:#x}, {} bytes\n\r",
+                str::from_utf8(&table.signature()), table.paddr, table.data.len());
+        match &table.signature() {
+            b"APIC" => acpi.madt = Some(Madt::parse(&table)),
+            b"FACP" => acpi.fadt = Some(Fadt::parse(&table)),
+            b"HPET" => acpi.hpet = Hpet::parse(&table),
+            _ => {}
+        }
+    }
+
+    if let Some(ref fadt) = acpi.fadt {
+        fadt.enable_acpi();
+        if fadt.dsdt != 0 {
+            acpi.s5 = map_table(fadt.dsdt).and_then(|dsdt| parse_s5(dsdt.data));
+        }
+    }
+
+    if let Some(ref madt) = acpi.madt {
+        printk!(Info, "acpi: {} cpus, {} ioapics, {} overrides, lapic at {:#x}\n\r",
+                madt.cpus.iter().filter(|c| c.enabled).count(), madt.ioapics.len(),
+                madt.overrides.len(), madt.local_apic_address);
+    }
+    if let Some(ref hpet) = acpi.hpet {
+        printk!(Info, "acpi: hpet at {:#x}, {} comparators\n\r", hpet.address, hpet.comparators);
+    }
+
+    if let (Some(fadt), Some((slp_typa, slp_typb))) = (acpi.fadt, acpi.s5) {
+        power::register_s5(SleepControl {
+            pm1a_cnt: fadt.pm1a_control_block as u16,
+            pm1b_cnt: fadt.pm1b_control_block as u16,
+            slp_typa: slp_typa,
+            slp_typb: slp_typb
+        });
+    }
+
+    if let Some(fadt) = acpi.fadt {
+        if let Some(reg) = fadt.reset_register {
+            if reg.address_space == GAS_SYSTEM_IO {
+                power::register_reset(ResetRegister { port: reg.address as u16, value: fadt.reset_value });
+            }
+        }
+    }
+
+    ACPI.call_once(|| acpi);
+}
@@ -46,6 +46,25 @@ pub unsafe fn cr0_set(val: usize) {
     asm!("mov $0, %cr0" :: "r" (val) : "memory");
 }
 
+/// time stamp counter
+pub fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) ::: "volatile"); }
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// cpuid with subleaf 0, returns (eax, ebx, ecx, edx)
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (a, b, c, d): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d)
+             : "{eax}"(leaf), "{ecx}"(0)
+             :: "volatile");
+    }
+    (a, b, c, d)
+}
+
 /// enable NXE bit, so page flag NO_EXECUTE is applicable
 pub fn enable_nxe_bit() {
     let nxe_bit = 1 << 11;
@@ -1,6 +1,49 @@
 extern syscall_dispatch
+extern signal_return
 global syscall_entry
 
+; selectors of interrupts::USER_CS_SEL and USER_DS_SEL
+USER_CS equ (4 << 3) | 3
+USER_DS equ (3 << 3) | 3
+
+; the general purpose registers in the order the interrupt handler
+; wrappers push them, so both leave idt::Registers
+%macro PUSH_REGS 0
+	push rax
+	push rcx
+	push rdx
+	push rsi
+	push rdi
+	push r8
+	push r9
+	push r10
+	push r11
+	push rbx
+	push rbp
+	push r12
+	push r13
+	push r14
+	push r15
+%endmacro
+
+%macro POP_REGS 0
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbp
+	pop rbx
+	pop r11
+	pop r10
+	pop r9
+	pop r8
+	pop rdi
+	pop rsi
+	pop rdx
+	pop rcx
+	pop rax
+%endmacro
+
 section .text
 bits 64
 syscall_entry:
@@ -8,18 +51,24 @@ syscall_entry:
 	mov [gs:0], rsp ; save user rsp
 	mov rsp, [gs:8] ; load kern rsp
 
-	push rbp
-	push r11
-	push rcx
+	; the frame an interrupt from user mode would leave, signal delivery
+	; treats both the same. gs:0 belongs to the cpu, not the task. another
+	; task may enter a syscall on this cpu before we return, so keep user
+	; rsp on our stack
+	push USER_DS
+	push qword [gs:0]
+	push r11 ; rflags
+	push USER_CS
+	push rcx ; rip
+	PUSH_REGS
 
 	; args: rdi, rsi, rdx, r8, r9, r10
 	; rax is syscall number, and return value
-	push rax
-    push r10
-    push r9
-    push r8
-    push rdx
-    push rsi
+	push r10
+	push r9
+	push r8
+	push rdx
+	push rsi
 	push rdi
 
 	sti
@@ -28,22 +77,27 @@ syscall_entry:
 	mov rcx, syscall_dispatch
 	call rcx
 	cli
+	add rsp, 6*8
+	mov [rsp + 14*8], rax ; overwrite saved rax with return value
 
-	pop rdi
-    pop rsi
-    pop rdx
-    pop r8
-    pop r9
-    pop r10
-	pop rax
-
-	pop rcx
-	pop r11
-	pop rbp
+	mov rdi, rsp
+	lea rsi, [rsp + 15*8]
+	mov rcx, signal_return
+	call rcx
+	test al, al
+	jnz .iret
 
-	mov rsp, [gs:0]
+	POP_REGS
+	mov rcx, [rsp] ; rip
+	mov r11, [rsp + 2*8] ; rflags
+	mov rsp, [rsp + 3*8]
 	swapgs
 
 	db 0x48
 	sysret
 
+	; sigreturn replaced the whole context, rcx and r11 included
+.iret:
+	POP_REGS
+	swapgs
+	iretq
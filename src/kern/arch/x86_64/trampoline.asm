@@ -0,0 +1,93 @@
+;; real mode entry of application processors, see smp.rs
+;;
+;; the blob is copied to TRAMPOLINE_BASE and started by SIPI at
+;; TRAMPOLINE_BASE >> 4 : 0, so every address is computed relative to
+;; trampoline_start. the boot cpu fills in trampoline_data before each SIPI.
+;; the page must be identity mapped in the page tables loaded from cr3.
+
+global trampoline_start
+global trampoline_end
+
+TRAMPOLINE_BASE equ 0x8000
+%define ABS(x) (TRAMPOLINE_BASE + (x) - trampoline_start)
+
+CODE_SELECTOR equ (ap_gdt.code - ap_gdt)
+DATA_SELECTOR equ (ap_gdt.data - ap_gdt)
+
+section .rodata align=16
+bits 16
+trampoline_start:
+	jmp short real_start
+
+	align 8
+	;; layout is TrampolineData in smp.rs
+trampoline_data:
+.cr3:	dq 0
+.stack:	dq 0
+.entry:	dq 0
+.cpu:	dq 0
+
+real_start:
+	cli
+	cld
+	xor ax, ax
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+
+	lgdt [ABS(ap_gdt_pointer)]
+
+	; PAE, OSFXSR and OSXMMEXCPT, like the boot cpu
+	mov eax, cr4
+	or eax, (1 << 5) | (3 << 9)
+	mov cr4, eax
+
+	mov eax, [ABS(trampoline_data.cr3)]
+	mov cr3, eax
+
+	; long mode and no-execute, the kernel page tables use NX
+	mov ecx, 0xC0000080
+	rdmsr
+	or eax, (1 << 8) | (1 << 11)
+	wrmsr
+
+	; paging, write protect, coprocessor monitoring and protection at once,
+	; the far jump then lands in 64-bit code directly
+	mov eax, cr0
+	and eax, ~(1 << 2)
+	or eax, (1 << 31) | (1 << 16) | (1 << 1) | (1 << 0)
+	mov cr0, eax
+
+	jmp dword CODE_SELECTOR:ABS(long_mode_start)
+
+bits 64
+long_mode_start:
+	mov ax, DATA_SELECTOR
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	xor ax, ax
+	mov fs, ax
+	mov gs, ax
+
+	mov rsp, [ABS(trampoline_data.stack)]
+	mov rdi, [ABS(trampoline_data.cpu)]
+	mov rax, [ABS(trampoline_data.entry)]
+	call rax
+
+.hang:
+	cli
+	hlt
+	jmp .hang
+
+	align 8
+ap_gdt:
+	dq 0
+.code:
+	dq (1<<43) | (1<<44) | (1<<47) | (1<<53)
+.data:
+	dq (1<<41) | (1<<44) | (1<<47)
+ap_gdt_pointer:
+	dw $ - ap_gdt - 1
+	dd ABS(ap_gdt)
+trampoline_end:
@@ -0,0 +1,115 @@
+//! raw walk over multiboot2 tags, for the tags multiboot2 crate doesn't parse.
+//! the boot information area is kept mapped in KernelMap by paging.
+
+use core::slice;
+use core::str;
+use spin::Once;
+use multiboot2::BootInformation;
+
+pub const TAG_END: u32 = 0;
+pub const TAG_CMDLINE: u32 = 1;
+/// copy of ACPI 1.0 RSDP
+pub const TAG_ACPI_OLD: u32 = 14;
+/// copy of ACPI 2.0+ RSDP
+pub const TAG_ACPI_NEW: u32 = 15;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    pub typ: u32,
+    /// including the 8 bytes header
+    pub size: u32,
+    addr: usize
+}
+
+impl Tag {
+    /// virtual address of the tag header
+    pub fn address(&self) -> usize {
+        self.addr
+    }
+
+    /// bytes after the header
+    pub fn payload(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts((self.addr + 8) as *const u8, self.size as usize - 8) }
+    }
+}
+
+pub struct TagIter {
+    current: usize,
+    end: usize
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.current + 8 > self.end {
+            return None;
+        }
+
+        let (typ, size) = unsafe {
+            (*(self.current as *const u32), *((self.current + 4) as *const u32))
+        };
+        if typ == TAG_END || size < 8 {
+            return None;
+        }
+
+        let tag = Tag { typ: typ, size: size, addr: self.current };
+        // tags are 8 bytes aligned
+        self.current += (size as usize + 7) & !7;
+        Some(tag)
+    }
+}
+
+static MBINFO: Once<&'static BootInformation> = Once::new();
+
+pub fn init(mbinfo: &'static BootInformation) {
+    MBINFO.call_once(|| mbinfo);
+}
+
+pub fn initialized() -> bool {
+    MBINFO.try().is_some()
+}
+
+pub fn mbinfo() -> &'static BootInformation {
+    *MBINFO.try().expect("bootinfo is not initialized")
+}
+
+pub fn tags() -> TagIter {
+    let start = mbinfo().start_address();
+    let total_size = unsafe { *(start as *const u32) } as usize;
+    TagIter { current: start + 8, end: start + total_size }
+}
+
+pub fn find_tag(typ: u32) -> Option<Tag> {
+    tags().find(|t| t.typ == typ)
+}
+
+/// kernel command line from bootloader, empty if there is none
+pub fn cmdline() -> &'static str {
+    match find_tag(TAG_CMDLINE) {
+        Some(tag) => {
+            let bytes = tag.payload();
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            str::from_utf8(&bytes[..len]).unwrap_or("")
+        },
+        None => ""
+    }
+}
+
+/// whether a bare word like `quiet` is present in the command line
+pub fn has_flag(name: &str) -> bool {
+    cmdline().split_whitespace().any(|w| w == name)
+}
+
+/// value of `key=value` from the command line
+pub fn option(key: &str) -> Option<&'static str> {
+    cmdline().split_whitespace()
+        .filter_map(|w| {
+            let mut kv = w.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None
+            }
+        })
+        .next()
+}
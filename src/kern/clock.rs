@@ -0,0 +1,334 @@
+//! Monotonic time and one-shot timer events.
+//!
+//! A ClockSource counts nanoseconds from some fixed point. The TSC,
+//! calibrated against the HPET or the PIT, and the HPET main counter are
+//! used when present, the timer tick when nothing else is. An invariant TSC
+//! is preferred over the HPET, any TSC is not. `clocksource=tsc`, `hpet` or
+//! `tick` on the command line picks one.
+//!
+//! Timer events run a callback in interrupt context once their deadline has
+//! passed. With the local APIC, every cpu runs its timer one-shot, armed for
+//! the earlier of its next tick and the next event, so events fire on time
+//! rather than on the next tick. `tickless` on the command line lets an idle
+//! cpu skip ticks and sleep until the next event.
+//!
+//! Wall-clock time is monotonic time plus an offset, set from the RTC.
+
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use collections::BTreeMap;
+use spin::{Mutex, Once};
+
+use ::kern::arch::cpu;
+use ::kern::bootinfo;
+use ::kern::console::LogLevel::*;
+use ::kern::driver::hpet::{self, Hpet};
+use ::kern::interrupts::{apic, timer};
+use ::kern::interrupts::irq::Irqs;
+use ::kern::smp::{self, PerCpu};
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// nanoseconds since an arbitrary point, never going backwards
+    fn nanos(&self) -> u64;
+}
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+pub const NANOS_PER_MS: u64 = 1_000_000;
+pub const TICK_NANOS: u64 = NANOS_PER_SEC / timer::HZ as u64;
+
+/// longest tickless sleep, a 32-bit HPET counter must not wrap unseen
+const MAX_IDLE_NANOS: u64 = NANOS_PER_SEC;
+/// how long the TSC is calibrated
+const CALIBRATE_MS: u32 = 10;
+
+const CPUID_TSC: u32 = 1 << 4; // edx of leaf 1
+const CPUID_INVARIANT_TSC: u32 = 1 << 8; // edx of leaf 0x80000007
+
+/// count * mul / div, without overflowing as long as the result fits
+pub fn scale(count: u64, mul: u64, div: u64) -> u64 {
+    (count / div) * mul + (count % div) * mul / div
+}
+
+pub struct Tsc {
+    khz: u64
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn nanos(&self) -> u64 {
+        scale(cpu::rdtsc(), NANOS_PER_MS, self.khz)
+    }
+}
+
+/// TSC frequency in kHz, measured against the HPET if there is one
+fn calibrate_tsc(hpet: Option<&Hpet>) -> u64 {
+    match hpet {
+        Some(hpet) => {
+            let (t0, n0) = (cpu::rdtsc(), hpet.nanos());
+            while hpet.nanos() - n0 < CALIBRATE_MS as u64 * NANOS_PER_MS {
+                ::kern::util::cpu_relax();
+            }
+            let (t1, n1) = (cpu::rdtsc(), hpet.nanos());
+            (t1 - t0) * NANOS_PER_MS / (n1 - n0)
+        },
+        None => {
+            let t0 = cpu::rdtsc();
+            timer::pit_wait_ms(CALIBRATE_MS);
+            (cpu::rdtsc() - t0) / CALIBRATE_MS as u64
+        }
+    }
+}
+
+/// timer interrupts of the boot cpu, as coarse as timer::HZ
+pub struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "tick"
+    }
+
+    fn nanos(&self) -> u64 {
+        timer::ticks() as u64 * TICK_NANOS
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+static TSC: Once<Tsc> = Once::new();
+static TICK: TickClock = TickClock;
+static CLOCK: Once<&'static ClockSource> = Once::new();
+/// value of the clock source at init, now() counts from there
+static BOOT_NANOS: AtomicUsize = AtomicUsize::new(0);
+/// local APIC timers run one-shot
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+/// unix time in nanoseconds at now() == 0
+static REALTIME_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// clock ids of clock_gettime
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64
+}
+
+impl Timespec {
+    pub fn from_nanos(nanos: u64) -> Timespec {
+        Timespec {
+            tv_sec: (nanos / NANOS_PER_SEC) as i64,
+            tv_nsec: (nanos % NANOS_PER_SEC) as i64
+        }
+    }
+}
+
+impl Timeval {
+    pub fn from_nanos(nanos: u64) -> Timeval {
+        Timeval {
+            tv_sec: (nanos / NANOS_PER_SEC) as i64,
+            tv_usec: (nanos % NANOS_PER_SEC / 1000) as i64
+        }
+    }
+}
+
+/// pick a clock source, after interrupts are up
+pub fn init() {
+    let hpet = match hpet::probe() {
+        Some(h) => Some(HPET.call_once(|| h)),
+        None => None
+    };
+
+    let (_, _, _, edx) = cpu::cpuid(1);
+    let tsc = if edx & CPUID_TSC != 0 {
+        Some(TSC.call_once(|| Tsc { khz: calibrate_tsc(hpet) }))
+    } else {
+        None
+    };
+    let invariant = cpu::cpuid(0x80000000).0 >= 0x80000007 &&
+        cpu::cpuid(0x80000007).3 & CPUID_INVARIANT_TSC != 0;
+
+    let clock: &'static ClockSource = match (bootinfo::option("clocksource"), tsc, hpet) {
+        (Some("tsc"), Some(tsc), _) => tsc,
+        (Some("hpet"), _, Some(hpet)) => hpet,
+        (Some("tick"), _, _) => &TICK,
+        (_, Some(tsc), _) if invariant => tsc,
+        (_, _, Some(hpet)) => hpet,
+        (_, Some(tsc), _) => tsc,
+        _ => &TICK
+    };
+
+    BOOT_NANOS.store(clock.nanos() as usize, Ordering::SeqCst);
+    CLOCK.call_once(|| clock);
+
+    // tick time can't place events between ticks
+    let oneshot = apic::local().is_some() && clock.name() != "tick";
+    ONESHOT.store(oneshot, Ordering::SeqCst);
+    TICKLESS.store(oneshot && bootinfo::has_flag("tickless"), Ordering::SeqCst);
+
+    if let Some(tsc) = tsc {
+        printk!(Info, "clock: tsc {} kHz{}\n\r", tsc.khz, if invariant { ", invariant" } else { "" });
+    }
+    printk!(Info, "clock: using {}, {} timer{}\n\r", clock.name(),
+            if oneshot { "one-shot" } else { "periodic" },
+            if TICKLESS.load(Ordering::SeqCst) { ", tickless idle" } else { "" });
+}
+
+/// the clock source in use, None before init
+pub fn source() -> Option<&'static ClockSource> {
+    CLOCK.try().map(|&clock| clock)
+}
+
+/// nanoseconds since clock init, 0 before
+pub fn now() -> u64 {
+    match CLOCK.try() {
+        Some(clock) => clock.nanos().saturating_sub(BOOT_NANOS.load(Ordering::Relaxed) as u64),
+        None => 0
+    }
+}
+
+/// the wall clock reads unix_nanos now
+pub fn set_realtime(unix_nanos: u64) {
+    let offset = unix_nanos.saturating_sub(now());
+    REALTIME_OFFSET.store(offset as usize, Ordering::SeqCst);
+}
+
+/// nanoseconds since the unix epoch, counting from 0 until the RTC is read
+pub fn realtime() -> u64 {
+    REALTIME_OFFSET.load(Ordering::Relaxed) as u64 + now()
+}
+
+pub type TimerCallback = fn(usize);
+
+struct Event {
+    callback: TimerCallback,
+    data: usize
+}
+
+lazy_static! {
+    /// pending events by (deadline, id)
+    static ref EVENTS: Mutex<BTreeMap<(u64, usize), Event>> = Mutex::new(BTreeMap::new());
+}
+static NEXT_EVENT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// run callback(data) from a timer interrupt once now() reaches deadline.
+/// returns an id for cancel_timer.
+pub fn add_timer(deadline: u64, callback: TimerCallback, data: usize) -> usize {
+    let id = NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let oflags = unsafe { cpu::push_flags() };
+    EVENTS.lock().insert((deadline, id), Event { callback: callback, data: data });
+    // this cpu may be armed for later than deadline
+    if ONESHOT.load(Ordering::Relaxed) {
+        program(smp::this_cpu(), now(), false);
+    }
+    unsafe { cpu::pop_flags(oflags); }
+
+    id
+}
+
+/// false if the event already ran or never existed
+pub fn cancel_timer(id: usize) -> bool {
+    let oflags = unsafe { cpu::push_flags() };
+    let removed = {
+        let mut events = EVENTS.lock();
+        let key = events.keys().find(|k| k.1 == id).cloned();
+        match key {
+            Some(key) => events.remove(&key).is_some(),
+            None => false
+        }
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    removed
+}
+
+fn next_event() -> Option<u64> {
+    match EVENTS.try_lock() {
+        Some(events) => events.keys().next().map(|k| k.0),
+        None => None
+    }
+}
+
+/// run expired events, true if any ran. the lock is not held while a
+/// callback runs, so callbacks may add timers.
+fn run_timers(now: u64) -> bool {
+    let mut ran = false;
+    loop {
+        let event = {
+            let mut events = match EVENTS.try_lock() {
+                Some(events) => events,
+                None => return ran
+            };
+            let key = match events.keys().next() {
+                Some(&key) if key.0 <= now => key,
+                _ => return ran
+            };
+            events.remove(&key)
+        };
+
+        if let Some(event) = event {
+            (event.callback)(event.data);
+            ran = true;
+        }
+    }
+}
+
+/// arm the timer of cpu for its next tick or the next event, whichever
+/// comes first. skip_tick leaves out the tick, for tickless idle.
+fn program(cpu: &PerCpu, now: u64, skip_tick: bool) {
+    let lapic = match apic::local() {
+        Some(lapic) => lapic,
+        None => return
+    };
+
+    let mut deadline = if skip_tick { now + MAX_IDLE_NANOS } else { cpu.next_tick() };
+    if let Some(event) = next_event() {
+        deadline = cmp::min(deadline, event);
+    }
+    lapic.start_oneshot(deadline.saturating_sub(now), Irqs::TIMER as u8);
+}
+
+/// what a timer irq turned out to be
+pub struct TimerIrq {
+    /// a scheduling tick, every irq in periodic mode
+    pub tick: bool,
+    /// run the scheduler: on a tick, after events, or when the cpu is idle
+    pub resched: bool
+}
+
+/// called by the timer irq, runs expired events and arms the next one
+pub fn timer_interrupt() -> TimerIrq {
+    let now = now();
+    let ran = run_timers(now);
+    if !ONESHOT.load(Ordering::Relaxed) {
+        return TimerIrq { tick: true, resched: true };
+    }
+
+    let cpu = smp::this_cpu();
+    let tick = now >= cpu.next_tick();
+    if tick {
+        cpu.set_next_tick(now + TICK_NANOS);
+    }
+    program(cpu, now, false);
+
+    TimerIrq { tick: tick, resched: tick || ran || cpu.current() == cpu.idle() }
+}
+
+/// called by idle tasks with interrupts disabled, right before hlt
+pub fn idle_enter() {
+    if TICKLESS.load(Ordering::Relaxed) {
+        program(smp::this_cpu(), now(), true);
+    }
+}
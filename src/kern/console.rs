@@ -3,6 +3,7 @@ use core::mem::size_of_val;
 use core::ptr::{Unique, write_volatile};
 use core::fmt::{Write, Result};
 use core::intrinsics::transmute;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, Once};
 
 use ::kern::arch::port::{Port};
@@ -373,6 +374,35 @@ impl Console {
         }
     }
 
+    pub fn framebuffer(&mut self) -> Option<&mut Framebuffer> {
+        match *self {
+            Console::TextTerminal(_) => None,
+            Console::FbTerminal(ref mut drv) => Some(drv.drv.framebuffer())
+        }
+    }
+
+    /// stop drawing onto the framebuffer, output still goes to serial
+    pub fn suspend(&mut self) {
+        if let Console::FbTerminal(ref mut drv) = *self {
+            drv.drv.suspend();
+        }
+    }
+
+    /// take the framebuffer back, starting over from a blank screen
+    pub fn resume(&mut self) {
+        if let Console::FbTerminal(ref mut drv) = *self {
+            drv.drv.resume();
+            drv.clear();
+        }
+    }
+
+    /// push pending framebuffer drawing onto screen
+    pub fn refresh(&mut self) {
+        if let Console::FbTerminal(ref mut drv) = *self {
+            drv.drv.flush();
+        }
+    }
+
     /// safely call f without potential deadlock of console
     pub fn with<F>(con: &Mutex<Console>, row: usize, col: usize, f: F) where F: FnOnce() {
         use ::kern::arch::cpu;
@@ -422,10 +452,39 @@ macro_rules! print {
     });
 }
 
+/// like println!, but never waits for the console, see print_nowait!
+macro_rules! println_nowait {
+    ($fmt:expr) => (print_nowait!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print_nowait!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// for code that may interrupt a console holder: SysRq, dumps and panics.
+/// when the console is locked the output goes to serial only.
+macro_rules! print_nowait {
+    ($($arg:tt)*) => ({
+        $crate::kern::console::_print_nowait(format_args!($($arg)*)).unwrap();
+    });
+}
+
+/// irqs are off while the console is held, so an irq handler printing on
+/// the same cpu can't find it locked
 pub fn _print(args: ::core::fmt::Arguments) -> ::core::fmt::Result {
     use core::fmt::Write;
-    let mut con = tty1.lock();
-    con.write_fmt(args)
+    use ::kern::arch::cpu;
+
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = tty1.lock().write_fmt(args);
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
+
+pub fn _print_nowait(args: ::core::fmt::Arguments) -> ::core::fmt::Result {
+    use core::fmt::Write;
+
+    match tty1.try_lock() {
+        Some(mut con) => con.write_fmt(args),
+        None => serial::Emergency.write_fmt(args)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -437,12 +496,41 @@ pub enum LogLevel {
     Critical
 }
 
+impl LogLevel {
+    /// levels above Critical are clamped
+    pub fn from_usize(lv: usize) -> LogLevel {
+        match lv {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Normal,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warn,
+            _ => LogLevel::Critical
+        }
+    }
+}
+
+#[cfg(feature = "kdebug")]
+const DEFAULT_LOG_LEVEL: usize = LogLevel::Debug as usize;
+#[cfg(not(feature = "kdebug"))]
+const DEFAULT_LOG_LEVEL: usize = LogLevel::Normal as usize;
+
+/// messages below this level are dropped by printk, can be changed at runtime
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_LEVEL);
+
+pub fn log_level() -> LogLevel {
+    LogLevel::from_usize(LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_log_level(lv: LogLevel) {
+    LOG_LEVEL.store(lv as usize, Ordering::Relaxed);
+}
+
 macro_rules! printk {
     ($lv:expr, $($arg:tt)*) => ({
         use $crate::kern::console::*;
         use $crate::kern::arch::cpu;
 
-        if $lv != LogLevel::Debug || cfg!(feature = "kdebug") {
+        if $lv as usize >= log_level() as usize {
             let attr = match $lv {
                 LogLevel::Debug => Attribute::new(Color::Green, Color::Black),
                 LogLevel::Normal => Attribute::new(Color::White, Color::Black),
@@ -0,0 +1,128 @@
+//! Demangling of Rust symbol names, legacy `_ZN...E` scheme.
+//!
+//! Names are written straight to the formatter without allocating, so this
+//! works from a panic with a broken heap. Anything that isn't a mangled
+//! name is printed as it is.
+
+use core::char;
+use core::fmt;
+
+/// formats a symbol name demangled, `_ZN4core3fmt5write17h0123456789abcdefE`
+/// comes out as `core::fmt::write`
+pub struct Demangle<'a>(pub &'a str);
+
+/// the path components between `_ZN` and the closing `E`
+fn inner(sym: &str) -> Option<&str> {
+    let sym = if sym.starts_with("__ZN") {
+        &sym[4..]
+    } else if sym.starts_with("_ZN") {
+        &sym[3..]
+    } else if sym.starts_with("ZN") {
+        &sym[2..]
+    } else {
+        return None;
+    };
+
+    if sym.ends_with('E') { Some(&sym[..sym.len() - 1]) } else { None }
+}
+
+/// split off the first length prefixed component
+fn component(s: &str) -> Option<(&str, &str)> {
+    let digits = s.bytes().take_while(|&b| (b as char).is_digit(10)).count();
+    if digits == 0 {
+        return None;
+    }
+
+    let len: usize = match s[..digits].parse() {
+        Ok(len) => len,
+        Err(_) => return None
+    };
+    let end = digits.checked_add(len).unwrap_or(usize::max_value());
+    if len == 0 || end > s.len() || !s.is_char_boundary(end) {
+        return None;
+    }
+    Some((&s[digits..end], &s[end..]))
+}
+
+fn well_formed(mut s: &str) -> bool {
+    while !s.is_empty() {
+        s = match component(s) {
+            Some((_, rest)) => rest,
+            None => return false
+        };
+    }
+    true
+}
+
+/// the trailing `h` + 16 hex digits disambiguator
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h') && ident[1..].chars().all(|c| c.is_digit(16))
+}
+
+/// the character of an escape like `LT` or `u7e`, between `$`s
+fn unescape(esc: &str) -> Option<char> {
+    match esc {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ if esc.starts_with('u') => {
+            u32::from_str_radix(&esc[1..], 16).ok().and_then(char::from_u32)
+        },
+        _ => None
+    }
+}
+
+fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+    // an identifier may not start with `$`, so one gets an underscore first
+    let mut rest = if ident.starts_with("_$") { &ident[1..] } else { ident };
+
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(i) => i + 1,
+                None => return f.write_str(rest)
+            };
+            match unescape(&rest[1..end]) {
+                Some(ch) => fmt::Write::write_char(f, ch)?,
+                None => f.write_str(&rest[..end + 1])?
+            }
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest[1..].find(|c| c == '$' || c == '.').map_or(rest.len(), |i| i + 1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match inner(self.0) {
+            Some(inner) if well_formed(inner) => inner,
+            _ => return f.write_str(self.0)
+        };
+
+        let mut first = true;
+        while let Some((ident, tail)) = component(rest) {
+            rest = tail;
+            if tail.is_empty() && is_hash(ident) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! HPET main counter, as a clock source.
+//!
+//! Only the main counter is used, the comparators are left alone. A 32-bit
+//! counter wraps within a minute at common rates, so reads extend it to 64
+//! bits and the clock must be read more often than that, which the timer
+//! tick and the cap on tickless sleeps take care of.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::kern::acpi;
+use ::kern::clock::{self, ClockSource};
+use ::kern::console::LogLevel::*;
+use ::kern::memory::{map_physical, PAGE_SIZE};
+use ::kern::memory::paging::{self, VirtualAddress};
+
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xF0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+pub struct Hpet {
+    base: VirtualAddress,
+    /// counter period in femtoseconds
+    period: u64,
+    counter_64bit: bool,
+    /// last extended value of a 32-bit counter
+    last: AtomicUsize
+}
+
+impl Hpet {
+    unsafe fn read(&self, reg: usize) -> u64 {
+        read_volatile((self.base + reg) as *const u64)
+    }
+
+    unsafe fn write(&self, reg: usize, val: u64) {
+        write_volatile((self.base + reg) as *mut u64, val);
+    }
+
+    /// main counter, extended to 64 bits
+    pub fn counter(&self) -> u64 {
+        if self.counter_64bit {
+            return unsafe { self.read(HPET_COUNTER) };
+        }
+
+        loop {
+            // read the hardware after last, so a retry never extends a
+            // value older than what someone else stored meanwhile
+            let last = self.last.load(Ordering::Acquire);
+            let low = unsafe { read_volatile((self.base + HPET_COUNTER) as *const u32) };
+            let delta = low.wrapping_sub(last as u32) as usize;
+            let value = last + delta;
+            if self.last.compare_and_swap(last, value, Ordering::AcqRel) == last {
+                return value as u64;
+            }
+        }
+    }
+
+    /// counter frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000 * FEMTOS_PER_NANO / self.period
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn nanos(&self) -> u64 {
+        clock::scale(self.counter(), self.period, FEMTOS_PER_NANO)
+    }
+}
+
+/// map and start the HPET described by ACPI, None if there is none
+pub fn probe() -> Option<Hpet> {
+    let info = match acpi::get().and_then(|acpi| acpi.hpet) {
+        Some(info) => info,
+        None => return None
+    };
+
+    let flags = paging::WRITABLE | paging::DISABLE_CACHE | paging::NO_EXECUTE;
+    let hpet = Hpet {
+        base: map_physical(info.address, PAGE_SIZE, flags),
+        period: 0,
+        counter_64bit: info.counter_64bit,
+        last: AtomicUsize::new(0)
+    };
+
+    // femtoseconds per counter tick, in the upper half of capabilities
+    let period = unsafe { hpet.read(HPET_CAPABILITIES) } >> 32;
+    // the spec caps the period at 100ns
+    if period == 0 || period > 100 * FEMTOS_PER_NANO {
+        printk!(Warn, "hpet: bogus period {}fs\n\r", period);
+        return None;
+    }
+
+    let hpet = Hpet { period: period, ..hpet };
+    unsafe {
+        let config = hpet.read(HPET_CONFIG);
+        hpet.write(HPET_CONFIG, config | CONFIG_ENABLE);
+    }
+
+    printk!(Info, "hpet: {}-bit counter at {} Hz\n\r",
+            if hpet.counter_64bit { 64 } else { 32 }, hpet.frequency());
+    Some(hpet)
+}
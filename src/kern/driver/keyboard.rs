@@ -1,10 +1,17 @@
 use ::kern::arch::port::Port;
-use core::sync::atomic::{AtomicBool, Ordering};
 use ::kern::interrupts::idt::*;
-use ::kern::interrupts::irq::PIC_CHAIN;
+use ::kern::interrupts::irq::{self, Irqs};
 use spin::Mutex;
 use ::kern::console::LogLevel::*;
 use ::kern::console::{Console, tty1};
+use ::kern::interrupts::timer;
+use ::kern::arch::cpu;
+use ::kern::util::RingBuffer;
+use ::kern::task::WaitQueue;
+use ::kern::vfs::Result;
+use ::kern::vfs::dev::{self, Device};
+use super::keymap;
+use ::kern::sysrq;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
@@ -156,6 +163,28 @@ pub enum KeyCode {
     KEY_PAGEDOWN          = 0x400f,
     KEY_SCROLLLOCK        = 0x4010,
     KEY_PAUSE             = 0x4011,
+    KEY_PRINT             = 0x4012,
+    // alt + print screen
+    KEY_SYSRQ             = 0x4013,
+    // ctrl + pause
+    KEY_BREAK             = 0x4014,
+    KEY_MENU              = 0x4015,
+    // extra key left of Z on ISO keyboards
+    KEY_102ND             = 0x4016,
+
+// ACPI and multimedia keys //////////////
+
+    KEY_POWER             = 0x5000,
+    KEY_SLEEP             = 0x5001,
+    KEY_WAKE              = 0x5002,
+    KEY_MUTE              = 0x5003,
+    KEY_VOLUMEDOWN        = 0x5004,
+    KEY_VOLUMEUP          = 0x5005,
+    KEY_PLAYPAUSE         = 0x5006,
+    KEY_STOPCD            = 0x5007,
+    KEY_PREVIOUSSONG      = 0x5008,
+    KEY_NEXTSONG          = 0x5009,
+    KEY_HOMEPAGE          = 0x500a,
 
     KEY_UNKNOWN
 }
@@ -173,13 +202,33 @@ bitflags! {
         const KB_CTRL_DOWN = 0x0200,
         const KB_ALT_DOWN = 0x0400,
         const KB_META_DOWN = 0x0800,
+        // right alt
+        const KB_ALTGR_DOWN = 0x1000,
     }
 }
 
+/// also the record format of /dev/kbd
 #[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct KeyPacket {
-    keycode: KeyCode,
-    status: u16,
+    /// milliseconds since boot
+    pub timestamp: u64,
+    pub keycode: KeyCode,
+    /// KeyStatus bits
+    pub status: u16,
+    /// character from current keymap, 0 if the key produces none
+    pub ch: u8,
+}
+
+impl Default for KeyPacket {
+    fn default() -> KeyPacket {
+        KeyPacket {
+            timestamp: 0,
+            keycode: KeyCode::KEY_INVALID,
+            status: 0,
+            ch: 0
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -189,11 +238,16 @@ pub enum MouseStatus {
     MOUSE_MID_DOWN = 0x0004,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// one decoded mouse report, also the record format of /dev/mouse.
+/// flags are MouseStatus bits, rely grows downwards like screen
+/// coordinates and relz is wheel movement.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
 pub struct MousePacket {
-    flags: u16,
-    relx: u16,
-    rely: i16,
+    pub flags: u16,
+    pub relx: i16,
+    pub rely: i16,
+    pub relz: i16,
 }
 
 // KB_ENCODER_IO 
@@ -370,9 +424,9 @@ static _xtkb_scancode_std: [KeyCode; 89] = [
     KeyCode::KEY_KP_3,       //0x51  //keypad page down
     KeyCode::KEY_KP_0,       //0x52  //keypad insert key
     KeyCode::KEY_KP_DECIMAL, //0x53  //keypad delete key
-    KeyCode::KEY_UNKNOWN,    //0x54
+    KeyCode::KEY_SYSRQ,      //0x54  //alt + print screen
     KeyCode::KEY_UNKNOWN,    //0x55
-    KeyCode::KEY_UNKNOWN,    //0x56
+    KeyCode::KEY_102ND,      //0x56
     KeyCode::KEY_F11,        //0x57
     KeyCode::KEY_F12         //0x58
 ];
@@ -383,17 +437,46 @@ struct ExtendScancode {
     keycode: KeyCode,
 }
 
-static _xtkb_scancode_ex: [ExtendScancode; 10] = [
+// keys prefixed by 0xE0, looked up by make code
+static _xtkb_scancode_ex: [ExtendScancode; 37] = [
+    ExtendScancode {scan: 0x10, keycode: KeyCode::KEY_PREVIOUSSONG},
+    ExtendScancode {scan: 0x19, keycode: KeyCode::KEY_NEXTSONG},
     ExtendScancode {scan: 0x1c, keycode: KeyCode::KEY_KP_ENTER},
     ExtendScancode {scan: 0x1d, keycode: KeyCode::KEY_RCTRL},
+    ExtendScancode {scan: 0x20, keycode: KeyCode::KEY_MUTE},
+    ExtendScancode {scan: 0x22, keycode: KeyCode::KEY_PLAYPAUSE},
+    ExtendScancode {scan: 0x24, keycode: KeyCode::KEY_STOPCD},
+    ExtendScancode {scan: 0x2e, keycode: KeyCode::KEY_VOLUMEDOWN},
+    ExtendScancode {scan: 0x30, keycode: KeyCode::KEY_VOLUMEUP},
+    ExtendScancode {scan: 0x32, keycode: KeyCode::KEY_HOMEPAGE},
     ExtendScancode {scan: 0x35, keycode: KeyCode::KEY_KP_DIVIDE},
+    ExtendScancode {scan: 0x37, keycode: KeyCode::KEY_PRINT},
     ExtendScancode {scan: 0x38, keycode: KeyCode::KEY_RALT},
+    ExtendScancode {scan: 0x46, keycode: KeyCode::KEY_BREAK},
     ExtendScancode {scan: 0x47, keycode: KeyCode::KEY_HOME},
+    ExtendScancode {scan: 0x48, keycode: KeyCode::KEY_UP},
+    ExtendScancode {scan: 0x49, keycode: KeyCode::KEY_PAGEUP},
+    ExtendScancode {scan: 0x4b, keycode: KeyCode::KEY_LEFT},
+    ExtendScancode {scan: 0x4d, keycode: KeyCode::KEY_RIGHT},
     ExtendScancode {scan: 0x4f, keycode: KeyCode::KEY_END},
+    ExtendScancode {scan: 0x50, keycode: KeyCode::KEY_DOWN},
     ExtendScancode {scan: 0x51, keycode: KeyCode::KEY_PAGEDOWN},
     ExtendScancode {scan: 0x52, keycode: KeyCode::KEY_INSERT},
     ExtendScancode {scan: 0x53, keycode: KeyCode::KEY_DELETE},
-    ExtendScancode {scan: 0x9c, keycode: KeyCode::KEY_KP_ENTER},
+    ExtendScancode {scan: 0x5b, keycode: KeyCode::KEY_LWIN},
+    ExtendScancode {scan: 0x5c, keycode: KeyCode::KEY_RWIN},
+    ExtendScancode {scan: 0x5d, keycode: KeyCode::KEY_MENU},
+    ExtendScancode {scan: 0x5e, keycode: KeyCode::KEY_POWER},
+    ExtendScancode {scan: 0x5f, keycode: KeyCode::KEY_SLEEP},
+    ExtendScancode {scan: 0x63, keycode: KeyCode::KEY_WAKE},
+    // browser keys, reported as unknown until we have keycodes for them
+    ExtendScancode {scan: 0x65, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x66, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x67, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x68, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x69, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x6a, keycode: KeyCode::KEY_UNKNOWN},
+    ExtendScancode {scan: 0x6b, keycode: KeyCode::KEY_UNKNOWN},
 ];
 
 fn get_extend_keycode(data: u8) -> KeyCode {
@@ -405,24 +488,126 @@ fn get_extend_keycode(data: u8) -> KeyCode {
     return KeyCode::KEY_UNKNOWN;
 }
 
-static _is_extended: AtomicBool = AtomicBool::new(false);
+/// keypad keys act as navigation keys while num lock is off
+fn keypad_navigation(keycode: KeyCode) -> KeyCode {
+    match keycode {
+        KeyCode::KEY_KP_7 => KeyCode::KEY_HOME,
+        KeyCode::KEY_KP_8 => KeyCode::KEY_UP,
+        KeyCode::KEY_KP_9 => KeyCode::KEY_PAGEUP,
+        KeyCode::KEY_KP_4 => KeyCode::KEY_LEFT,
+        KeyCode::KEY_KP_6 => KeyCode::KEY_RIGHT,
+        KeyCode::KEY_KP_1 => KeyCode::KEY_END,
+        KeyCode::KEY_KP_2 => KeyCode::KEY_DOWN,
+        KeyCode::KEY_KP_3 => KeyCode::KEY_PAGEDOWN,
+        KeyCode::KEY_KP_0 => KeyCode::KEY_INSERT,
+        KeyCode::KEY_KP_DECIMAL => KeyCode::KEY_DELETE,
+        kc => kc
+    }
+}
+
+/// state of set 1 decoder. the controller translates whatever set the
+/// keyboard speaks into set 1, so that is all we need to understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefix {
+    Normal,
+    E0,
+    /// pause sequence, E1 1D 45 for make and E1 9D C5 for break.
+    /// counts bytes seen after E1
+    E1(u8),
+}
+
+/// a decoded key event
+#[derive(Debug, Clone, Copy)]
+struct Scancode {
+    /// make code without prefix
+    code: u8,
+    extended: bool,
+    release: bool,
+    keycode: KeyCode,
+}
+
+impl Scancode {
+    fn new(data: u8, extended: bool) -> Scancode {
+        let code = data & 0x7f;
+        let keycode = match extended {
+            true => get_extend_keycode(code),
+            false if (code as usize) < _xtkb_scancode_std.len() => _xtkb_scancode_std[code as usize],
+            _ => {
+                //printk!(Warn, "weird scancode {}", data);
+                KeyCode::KEY_UNKNOWN
+            }
+        };
+
+        Scancode {
+            code: code,
+            extended: extended,
+            release: data & 0x80 != 0,
+            keycode: keycode
+        }
+    }
+
+    /// identifies the physical key, to spot typematic repeats
+    fn id(&self) -> u16 {
+        (self.extended as u16) << 8 | self.code as u16
+    }
+}
 
 #[derive(Debug)]
 pub struct Keyboard {
     encoder: Port<u8>,
     ctrl: Port<u8>,
     status: Option<KeyStatus>,
+    prefix: Prefix,
+    /// previous pressed key, to tell typematic repeats from new presses
+    last: u16,
+    /// alt + sysrq is held, next key press is a sysrq action
+    sysrq: bool,
     //kb_buf: KeyBuffer,
 }
 
 pub static KBD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
 
+const KEY_QUEUE_SIZE: usize = 128;
+
+lazy_static! {
+    static ref KEY_EVENTS: Mutex<RingBuffer<KeyPacket>> = Mutex::new(RingBuffer::new(KEY_QUEUE_SIZE));
+}
+
+/// readers of /dev/kbd waiting for KEY_EVENTS
+static KEY_WAITERS: WaitQueue = WaitQueue::new();
+
+/// /dev/kbd, raw press and release events as KeyPacket records
+pub struct KeyboardDevice;
+
+pub static KBD_DEV: KeyboardDevice = KeyboardDevice;
+
+impl Device for KeyboardDevice {
+    fn name(&self) -> &'static str {
+        "kbd"
+    }
+
+    /// events queued before open are stale
+    fn open(&self, _flags: usize) -> Result<()> {
+        let oflags = unsafe { cpu::push_flags() };
+        KEY_EVENTS.lock().clear();
+        unsafe { cpu::pop_flags(oflags); }
+        Ok(())
+    }
+
+    fn read(&self, buf: &mut [u8], flags: usize) -> Result<usize> {
+        dev::read_records(&*KEY_EVENTS, &KEY_WAITERS, buf, flags)
+    }
+}
+
 impl Keyboard {
     pub const fn new() -> Keyboard {
         Keyboard {
             encoder: Port::new(KB_ENC_CMD_REG),
             ctrl: Port::new(KB_CTRL_CMD_REG),
-            status: None
+            status: None,
+            prefix: Prefix::Normal,
+            last: 0,
+            sysrq: false
         }
     }
 
@@ -431,10 +616,9 @@ impl Keyboard {
     // 1: resend
     // 2: bad
     fn check_reply(&mut self) -> i8 {
-        let d = self.kbc_read();
-        match d {
-            0xFA => 0, //ACK
-            0xFE => 1,
+        match self.kbe_read_timeout() {
+            Some(0xFA) => 0, //ACK
+            Some(0xFE) => 1,
             _ => 2
         }
     }
@@ -450,13 +634,65 @@ impl Keyboard {
 
         self.kbc_send(KeyboardCtrlCommand::WRITE as u8);
         self.kbe_send(0x47);
-        self.check_reply();
 
         self.set_leds(false, false, false);
+        keymap::init();
+        dev::register(&KBD_DEV);
         //register_isr_handler(IRQ_KBD, keyboard_irq);
         //register_isr_handler(IRQ_MOUSE, mouse_irq);
     }
 
+    /// turn on auxiliary port with its irq (IRQ12) and clock
+    pub fn enable_aux(&mut self) {
+        self.kbc_send(KeyboardCtrlCommand::MOUSE_ENABLE as u8);
+
+        self.kbc_send(KeyboardCtrlCommand::READ as u8);
+        let config = self.kbe_read_timeout().unwrap_or(0x47);
+        // bit 1: aux irq enabled, bit 5: aux clock disabled
+        let config = (config | 0x02) & !0x20;
+        self.kbc_send(KeyboardCtrlCommand::WRITE as u8);
+        self.kbe_send(config);
+    }
+
+    /// send a byte to auxiliary device, true if it got acked
+    pub fn aux_send(&mut self, data: u8) -> bool {
+        for _ in 0..3 {
+            self.kbc_send(KeyboardCtrlCommand::MOUSE_WRITE as u8);
+            self.kbe_send(data);
+            match self.check_reply() {
+                0 => return true,
+                1 => continue,
+                _ => return false
+            }
+        }
+        false
+    }
+
+    /// wait for a reply byte from auxiliary device
+    pub fn aux_read(&mut self) -> Option<u8> {
+        self.kbe_read_timeout()
+    }
+
+    /// data byte if output buffer holds one from auxiliary device
+    pub fn aux_data(&mut self) -> Option<u8> {
+        let status = KeyboardCtrlStatsMask::from_bits_truncate(self.kbc_read());
+        if status.contains(STATS_MASK_OUT_BUF | STATS_MASK_AUX_BUF) {
+            Some(self.kbe_read())
+        } else {
+            None
+        }
+    }
+
+    /// pulse the cpu reset line through the controller output port
+    pub fn reset_system(&mut self) {
+        self.kbc_send(KeyboardCtrlCommand::SYSTEM_RESET as u8);
+    }
+
+    fn aux_pending(&mut self) -> bool {
+        let status = KeyboardCtrlStatsMask::from_bits_truncate(self.kbc_read());
+        status.contains(STATS_MASK_AUX_BUF)
+    }
+
     fn kbe_send(&mut self, cmd: u8) {
         self.poll_aux_status();
         self.encoder.write(cmd);
@@ -498,6 +734,15 @@ impl Keyboard {
         self.kbe_read()
     }
 
+    fn kbe_read_timeout(&mut self) -> Option<u8> {
+        for _ in 0..100000 {
+            if self.can_read() {
+                return Some(self.kbe_read());
+            }
+        }
+        None
+    }
+
     fn kbe_read(&mut self) -> u8 {
         self.encoder.read()
     }
@@ -509,70 +754,84 @@ impl Keyboard {
     //Bit 0: Scroll lock LED (0: off 1:on)
     //Bit 1: Num lock LED (0: off 1:on)
     //Bit 2: Caps lock LED (0: off 1:on)
+    // lock bits of KeyStatus are the same as led bits
     fn set_leds(&mut self, scroll: bool, num: bool, caps: bool) {
-        let mut status = self.status.map_or(KeyStatus::empty(), |st| st);
-        if scroll {
-            status |= KB_SCROLL_LOCK;
-        }
-        if num {
-            status |= KB_NUM_LOCK;
-        }
-        if caps {
-            status |= KB_CAPS_LOCK;
-        }
+        self.set_status(KB_SCROLL_LOCK, scroll);
+        self.set_status(KB_NUM_LOCK, num);
+        self.set_status(KB_CAPS_LOCK, caps);
+
+        let leds = self.status.map_or(0, |st| (st & (KB_SCROLL_LOCK | KB_NUM_LOCK | KB_CAPS_LOCK)).bits());
+        self.kbe_send(KeyboardEncoderCommand::SET_LEDS as u8);
+        self.kbe_send(leds as u8);
+    }
 
-        if status.is_empty() {
-            self.status = None;
+    fn toggle_lock(&mut self, lock: KeyStatus) {
+        let st = self.status.map_or(KeyStatus::empty(), |st| st) ^ lock;
+        self.set_leds(st.contains(KB_SCROLL_LOCK), st.contains(KB_NUM_LOCK), st.contains(KB_CAPS_LOCK));
+    }
+
+    fn set_status(&mut self, flag: KeyStatus, val: bool) {
+        let mut st = self.status.map_or(KeyStatus::empty(), |st| st);
+        if val {
+            st.insert(flag);
         } else {
-            self.status = Some(status);
+            st.remove(flag);
         }
-        let data = status.bits();
-        self.kbe_send(KeyboardEncoderCommand::SET_LEDS as u8);
-        self.kbe_send(data as u8);
+
+        self.status = if st.is_empty() { None } else { Some(st) };
     }
 
     fn set_shift_down(&mut self, val: bool) {
-        match self.status {
-            Some(ref mut st) =>
-                if val {
-                    st.insert(KB_SHIFT_DOWN);
-                } else {
-                    st.remove(KB_SHIFT_DOWN);
-                },
-            None =>
-                if val {
-                    self.status = Some(KB_SHIFT_DOWN);
-                }
-        }
+        self.set_status(KB_SHIFT_DOWN, val);
     }
 
     fn set_ctrl_down(&mut self, val: bool) {
-        match self.status {
-            Some(ref mut st) =>
-                if val {
-                    st.insert(KB_CTRL_DOWN);
-                } else {
-                    st.remove(KB_CTRL_DOWN);
-                },
-            None =>
-                if val {
-                    self.status = Some(KB_CTRL_DOWN);
-                }
-        }
+        self.set_status(KB_CTRL_DOWN, val);
     }
 
     fn set_alt_down(&mut self, val: bool) {
-        match self.status {
-            Some(ref mut st) =>
-                if val {
-                    st.insert(KB_ALT_DOWN);
-                } else {
-                    st.remove(KB_ALT_DOWN);
-                },
-            None =>
-                if val {
-                    self.status = Some(KB_ALT_DOWN);
+        self.set_status(KB_ALT_DOWN, val);
+    }
+
+    fn set_altgr_down(&mut self, val: bool) {
+        self.set_status(KB_ALTGR_DOWN, val);
+    }
+
+    /// feed one byte from controller, return a key event when complete
+    fn decode(&mut self, data: u8) -> Option<Scancode> {
+        match self.prefix {
+            Prefix::Normal => match data {
+                0xE0 => { self.prefix = Prefix::E0; None },
+                0xE1 => { self.prefix = Prefix::E1(0); None },
+                // ack of set_leds
+                0xFA => None,
+                _ => Some(Scancode::new(data, false))
+            },
+            Prefix::E0 => {
+                self.prefix = Prefix::Normal;
+                match data & 0x7f {
+                    // fake shifts sent around print screen and the
+                    // navigation keys, depending on shift and num lock
+                    0x2a | 0x36 => None,
+                    _ => Some(Scancode::new(data, true))
                 }
+            },
+            Prefix::E1(0) => {
+                self.prefix = Prefix::E1(1);
+                None
+            },
+            Prefix::E1(_) => {
+                self.prefix = Prefix::Normal;
+                match data {
+                    0x45 | 0xC5 => Some(Scancode {
+                        code: 0x45,
+                        extended: false,
+                        release: data & 0x80 != 0,
+                        keycode: KeyCode::KEY_PAUSE
+                    }),
+                    _ => None
+                }
+            }
         }
     }
 
@@ -593,132 +852,83 @@ impl Keyboard {
 //FIXME: stub here, need tty later
 fn tty_enqueue() {}
 
-impl KeyCode {
-    fn printable(&self) -> bool {
-        match *self {
-            KeyCode::KEY_SPACE | KeyCode::KEY_0 | KeyCode::KEY_1 | KeyCode::KEY_2 |
-                KeyCode::KEY_3 | KeyCode::KEY_4 | KeyCode::KEY_5 | KeyCode::KEY_6 | 
-                KeyCode::KEY_7 | KeyCode::KEY_8 | KeyCode::KEY_9 | KeyCode::KEY_A |
-                KeyCode::KEY_B | KeyCode::KEY_C | KeyCode::KEY_D | KeyCode::KEY_E | 
-                KeyCode::KEY_F | KeyCode::KEY_G | KeyCode::KEY_H | KeyCode::KEY_I | 
-                KeyCode::KEY_J | KeyCode::KEY_K | KeyCode::KEY_L | KeyCode::KEY_M | 
-                KeyCode::KEY_N | KeyCode::KEY_O | KeyCode::KEY_P | KeyCode::KEY_Q | 
-                KeyCode::KEY_R | KeyCode::KEY_S | KeyCode::KEY_T | KeyCode::KEY_U | 
-                KeyCode::KEY_V | KeyCode::KEY_W | KeyCode::KEY_X | KeyCode::KEY_Y | 
-                KeyCode::KEY_Z | KeyCode::KEY_DOT | KeyCode::KEY_COMMA | KeyCode::KEY_COLON | 
-                KeyCode::KEY_SEMICOLON | KeyCode::KEY_SLASH | KeyCode::KEY_BACKSLASH | 
-                KeyCode::KEY_PLUS | KeyCode::KEY_MINUS | KeyCode::KEY_ASTERISK |
-                KeyCode::KEY_EXCLAMATION | KeyCode::KEY_QUESTION | KeyCode::KEY_QUOTEDOUBLE |
-                KeyCode::KEY_QUOTE | KeyCode::KEY_EQUAL | KeyCode::KEY_HASH | 
-                KeyCode::KEY_PERCENT | KeyCode::KEY_AMPERSAND | KeyCode::KEY_UNDERSCORE |
-                KeyCode::KEY_LEFTPARENTHESIS | KeyCode::KEY_RIGHTPARENTHESIS | 
-                KeyCode::KEY_LEFTBRACKET | KeyCode::KEY_RIGHTBRACKET | KeyCode::KEY_LEFTCURL |
-                KeyCode::KEY_RIGHTCURL | KeyCode::KEY_DOLLAR | KeyCode::KEY_LESS | 
-                KeyCode::KEY_GREATER | KeyCode::KEY_BAR | KeyCode::KEY_GRAVE |
-                KeyCode::KEY_TILDE | KeyCode::KEY_AT | KeyCode::KEY_CARRET | 
-                KeyCode::KEY_RETURN | KeyCode::KEY_BACKSPACE => true,
-            _ => false
-        }
-    }
-}
-
 //FIXME: I use KBD (spin)lock here, so there might be a deadlock
 pub extern "C" fn keyboard_irq(frame: &mut ExceptionStackFrame) {
-    unsafe {
-        PIC_CHAIN.lock().eoi(0);
-    }
+    irq::eoi(Irqs::KBD);
     let mut kbd = KBD.lock();
-    let data = kbd.kbe_wait_and_read();
-
-    if data == 0xE0 {
-        _is_extended.store(true, Ordering::Relaxed);
+    // belongs to mouse irq
+    if kbd.aux_pending() {
         return;
     }
+    let data = kbd.kbe_wait_and_read();
+
+    let sc = match kbd.decode(data) {
+        Some(sc) => sc,
+        None => return
+    };
 
     let mut packet = KeyPacket {
-        keycode: KeyCode::KEY_UNKNOWN,
-        status: 0
+        timestamp: timer::uptime_ms(),
+        keycode: sc.keycode,
+        status: 0,
+        ch: 0
     };
 
-    let extended = _is_extended.load(Ordering::Relaxed);
-    if data & 0x80 != 0 {
+    if !sc.extended && !kbd.status.map_or(false, |st| st.contains(KB_NUM_LOCK)) {
+        packet.keycode = keypad_navigation(packet.keycode);
+    }
+
+    if sc.release {
         packet.status = KB_RELEASE.bits();
-         packet.keycode = match extended {
-            true => get_extend_keycode(data),
-            false if ((data & 0x7f) as usize) < _xtkb_scancode_std.len() =>
-                _xtkb_scancode_std[(data & 0x7f) as usize],
-            _ => {
-                //printk!(Warn, "weird scancode {}", data);
-                KeyCode::KEY_UNKNOWN
-            }
-        };
+        kbd.last = 0;
 
         //Break Code
         match packet.keycode {
             KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT => kbd.set_shift_down(false), 
             KeyCode::KEY_LCTRL | KeyCode::KEY_RCTRL => kbd.set_ctrl_down(false), 
-            KeyCode::KEY_LALT | KeyCode::KEY_RALT => kbd.set_alt_down(false), 
+            KeyCode::KEY_LALT => kbd.set_alt_down(false), 
+            KeyCode::KEY_RALT => kbd.set_altgr_down(false), 
+            KeyCode::KEY_SYSRQ | KeyCode::KEY_PRINT => kbd.sysrq = false,
             _ => {}
         }
 
     } else {
         packet.status = KB_PRESS.bits();
-        packet.keycode = match extended {
-            true =>  get_extend_keycode(data),
-            false if (data as usize) < _xtkb_scancode_std.len() => 
-                _xtkb_scancode_std[data as usize],
-            _ => {
-                //printk!(Warn, "weird scancode {}", data);
-                KeyCode::KEY_UNKNOWN
-            }
-        };
+        let repeat = sc.id() == kbd.last;
+        kbd.last = sc.id();
 
         //Make Code
         match packet.keycode {
-            KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT => {
-                kbd.set_shift_down(true);
-                printk!(Warn, "{:#?}\n\r", kbd.status);
-            },
+            KeyCode::KEY_LSHIFT | KeyCode::KEY_RSHIFT => kbd.set_shift_down(true),
             KeyCode::KEY_LCTRL | KeyCode::KEY_RCTRL => kbd.set_ctrl_down(true),
-            KeyCode::KEY_LALT | KeyCode::KEY_RALT => kbd.set_alt_down(true),
+            KeyCode::KEY_LALT => kbd.set_alt_down(true),
+            KeyCode::KEY_RALT => kbd.set_altgr_down(true),
+            KeyCode::KEY_CAPSLOCK if !repeat => kbd.toggle_lock(KB_CAPS_LOCK),
+            KeyCode::KEY_KP_NUMLOCK if !repeat => kbd.toggle_lock(KB_NUM_LOCK),
+            KeyCode::KEY_SCROLLLOCK if !repeat => kbd.toggle_lock(KB_SCROLL_LOCK),
+            // controller sends 0x54 for print screen while alt is down,
+            // some emulators send the plain print screen sequence
+            KeyCode::KEY_SYSRQ => kbd.sysrq = true,
+            KeyCode::KEY_PRINT if kbd.alt_down() => kbd.sysrq = true,
             _ => {}
         }
-    }
 
-    if kbd.shift_down() {
-        match packet.keycode {
-            KeyCode::KEY_0 =>             packet.keycode = KeyCode::KEY_RIGHTPARENTHESIS,
-            KeyCode::KEY_1 =>             packet.keycode = KeyCode::KEY_EXCLAMATION,
-            KeyCode::KEY_2 =>             packet.keycode = KeyCode::KEY_AT,
-            KeyCode::KEY_3 =>             packet.keycode = KeyCode::KEY_HASH,
-            KeyCode::KEY_4 =>             packet.keycode = KeyCode::KEY_DOLLAR,
-            KeyCode::KEY_5 =>             packet.keycode = KeyCode::KEY_PERCENT,
-            KeyCode::KEY_6 =>             packet.keycode = KeyCode::KEY_CARRET,
-            KeyCode::KEY_7 =>             packet.keycode = KeyCode::KEY_AMPERSAND,
-            KeyCode::KEY_8 =>             packet.keycode = KeyCode::KEY_ASTERISK,
-            KeyCode::KEY_9 =>             packet.keycode = KeyCode::KEY_LEFTPARENTHESIS,
-            KeyCode::KEY_UNDERSCORE =>    packet.keycode = KeyCode::KEY_MINUS,
-            KeyCode::KEY_EQUAL =>         packet.keycode = KeyCode::KEY_PLUS,
-            KeyCode::KEY_GRAVE =>         packet.keycode = KeyCode::KEY_TILDE,
-            KeyCode::KEY_COMMA =>         packet.keycode = KeyCode::KEY_LESS,
-            KeyCode::KEY_DOT =>           packet.keycode = KeyCode::KEY_GREATER,
-            KeyCode::KEY_SLASH =>         packet.keycode = KeyCode::KEY_QUESTION,
-            KeyCode::KEY_LEFTBRACKET =>   packet.keycode = KeyCode::KEY_LEFTCURL,
-            KeyCode::KEY_RIGHTBRACKET =>  packet.keycode = KeyCode::KEY_RIGHTCURL,
-            KeyCode::KEY_BACKSLASH =>     packet.keycode = KeyCode::KEY_BAR,
-            KeyCode::KEY_QUOTE =>         packet.keycode = KeyCode::KEY_QUOTEDOUBLE,
-            _ => {}
+        let status = kbd.status.map_or(KeyStatus::empty(), |st| st);
+        packet.ch = keymap::current().translate(sc.code, sc.extended, status).unwrap_or(0);
+
+        // the action key is eaten, it never reaches tty or /dev/kbd
+        if kbd.sysrq && packet.ch != 0 && !repeat {
+            drop(kbd);
+            sysrq::handle(packet.ch, frame);
+            return;
         }
     }
     packet.status |= kbd.status.map_or(0, |st| st.bits());
 
-    let st = KeyStatus::from_bits(packet.status);
-    if st.is_some() && st.unwrap().contains(KB_PRESS) && packet.keycode.printable() {
-        print!("{}", packet.keycode as u8 as char);
+    if packet.ch != 0 {
+        tty1.lock().putchar(packet.ch);
     }
-    //kbd.kbbuf().write(packet);
+    KEY_EVENTS.lock().push(packet);
+    KEY_WAITERS.wake_all();
     tty_enqueue();
-
-    if extended { _is_extended.store(false, Ordering::Relaxed); }
 }
-
@@ -0,0 +1,250 @@
+//! Keyboard layouts. A keymap turns set 1 make codes into Latin-1
+//! characters through plain, shift and altgr layers, the ctrl layer is
+//! derived from the plain one. Keys with a 0 entry produce no character.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ::kern::console::LogLevel::*;
+use ::kern::vfs::{Error, Result};
+use super::keyboard::{KeyStatus, KB_SHIFT_DOWN, KB_CTRL_DOWN, KB_ALTGR_DOWN,
+    KB_CAPS_LOCK, KB_NUM_LOCK};
+
+/// make codes covered by the tables
+pub const NR_SCANCODES: usize = 0x59;
+
+pub struct Keymap {
+    pub name: &'static str,
+    plain: &'static [u8; NR_SCANCODES],
+    shift: &'static [u8; NR_SCANCODES],
+    altgr: &'static [u8; NR_SCANCODES],
+}
+
+static LAYOUTS: [Keymap; 3] = [
+    Keymap { name: "us", plain: &US_PLAIN, shift: &US_SHIFT, altgr: &US_ALTGR },
+    Keymap { name: "uk", plain: &UK_PLAIN, shift: &UK_SHIFT, altgr: &UK_ALTGR },
+    Keymap { name: "de", plain: &DE_PLAIN, shift: &DE_SHIFT, altgr: &DE_ALTGR },
+];
+
+/// index into LAYOUTS
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+fn is_letter(c: u8) -> bool {
+    match c {
+        b'a'...b'z' | b'A'...b'Z' => true,
+        // latin-1 letters, except the multiply and divide signs and
+        // sharp s which has no upper case
+        0xc0...0xff => c != 0xd7 && c != 0xf7 && c != 0xdf,
+        _ => false
+    }
+}
+
+fn is_keypad(code: u8) -> bool {
+    code >= 0x47 && code < 0x54 && code != 0x4a && code != 0x4e
+}
+
+impl Keymap {
+    /// character for make code `code` under modifier and lock `status`
+    pub fn translate(&self, code: u8, extended: bool, status: KeyStatus) -> Option<u8> {
+        if extended {
+            // the only extended keys producing characters
+            return match code {
+                0x1c => Some(b'\n'),
+                0x35 => Some(b'/'),
+                _ => None
+            };
+        }
+
+        let idx = code as usize;
+        if idx >= NR_SCANCODES {
+            return None;
+        }
+
+        if is_keypad(code) {
+            return match status.contains(KB_NUM_LOCK) {
+                true => Some(self.plain[idx]),
+                false => None
+            };
+        }
+
+        let plain = self.plain[idx];
+        let c = if status.contains(KB_ALTGR_DOWN) {
+            self.altgr[idx]
+        } else if status.contains(KB_CTRL_DOWN) {
+            match plain {
+                b'a'...b'z' => plain & 0x1f,
+                b'[' | b'\\' | b']' => plain & 0x1f,
+                _ => plain
+            }
+        } else {
+            // caps lock only inverts shift for letters
+            let mut shifted = status.contains(KB_SHIFT_DOWN);
+            if status.contains(KB_CAPS_LOCK) && is_letter(plain) {
+                shifted = !shifted;
+            }
+            if shifted { self.shift[idx] } else { plain }
+        };
+
+        match c {
+            0 => None,
+            c => Some(c)
+        }
+    }
+}
+
+pub fn current() -> &'static Keymap {
+    &LAYOUTS[CURRENT.load(Ordering::Relaxed)]
+}
+
+pub fn set_keymap(name: &str) -> Result<()> {
+    match LAYOUTS.iter().position(|km| km.name == name) {
+        Some(i) => {
+            CURRENT.store(i, Ordering::Relaxed);
+            printk!(Info, "keymap: {}\n\r", name);
+            Ok(())
+        },
+        None => Err(Error::NoEntry)
+    }
+}
+
+/// pick layout from `keymap=` on cmdline
+pub fn init() {
+    if let Some(name) = ::kern::bootinfo::option("keymap") {
+        if set_keymap(name).is_err() {
+            printk!(Warn, "unknown keymap {}, using {}\n\r", name, current().name);
+        }
+    }
+}
+
+static US_PLAIN: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'1', b'2', b'3', b'4', b'5', b'6', // 0x00
+    b'7', b'8', b'9', b'0', b'-', b'=', 0x08, b'\t', // 0x08
+    b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', // 0x10
+    b'o', b'p', b'[', b']', b'\n', 0, b'a', b's', // 0x18
+    b'd', b'f', b'g', b'h', b'j', b'k', b'l', b';', // 0x20
+    b'\'', b'`', 0, b'\\', b'z', b'x', b'c', b'v', // 0x28
+    b'b', b'n', b'm', b',', b'.', b'/', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'\\', 0, // 0x50
+    0, // 0x58
+];
+
+static US_SHIFT: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'!', b'@', b'#', b'$', b'%', b'^', // 0x00
+    b'&', b'*', b'(', b')', b'_', b'+', 0x08, b'\t', // 0x08
+    b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I', // 0x10
+    b'O', b'P', b'{', b'}', b'\n', 0, b'A', b'S', // 0x18
+    b'D', b'F', b'G', b'H', b'J', b'K', b'L', b':', // 0x20
+    b'"', b'~', 0, b'|', b'Z', b'X', b'C', b'V', // 0x28
+    b'B', b'N', b'M', b'<', b'>', b'?', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'|', 0, // 0x50
+    0, // 0x58
+];
+
+static US_ALTGR: [u8; NR_SCANCODES] = [
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x00
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x08
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x10
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x18
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x20
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x28
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x30
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x40
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x48
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x50
+    0, // 0x58
+];
+
+static UK_PLAIN: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'1', b'2', b'3', b'4', b'5', b'6', // 0x00
+    b'7', b'8', b'9', b'0', b'-', b'=', 0x08, b'\t', // 0x08
+    b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', // 0x10
+    b'o', b'p', b'[', b']', b'\n', 0, b'a', b's', // 0x18
+    b'd', b'f', b'g', b'h', b'j', b'k', b'l', b';', // 0x20
+    b'\'', b'`', 0, b'#', b'z', b'x', b'c', b'v', // 0x28
+    b'b', b'n', b'm', b',', b'.', b'/', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'\\', 0, // 0x50
+    0, // 0x58
+];
+
+static UK_SHIFT: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'!', b'"', 0xa3, b'$', b'%', b'^', // 0x00
+    b'&', b'*', b'(', b')', b'_', b'+', 0x08, b'\t', // 0x08
+    b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I', // 0x10
+    b'O', b'P', b'{', b'}', b'\n', 0, b'A', b'S', // 0x18
+    b'D', b'F', b'G', b'H', b'J', b'K', b'L', b':', // 0x20
+    b'@', 0xac, 0, b'~', b'Z', b'X', b'C', b'V', // 0x28
+    b'B', b'N', b'M', b'<', b'>', b'?', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'|', 0, // 0x50
+    0, // 0x58
+];
+
+static UK_ALTGR: [u8; NR_SCANCODES] = [
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x00
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x08
+    0, 0, 0xe9, 0, 0, 0, 0xfa, 0xed, // 0x10
+    0xf3, 0, 0, 0, 0, 0, 0xe1, 0, // 0x18
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x20
+    0, 0xa6, 0, 0, 0, 0, 0, 0, // 0x28
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x30
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x40
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x48
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x50
+    0, // 0x58
+];
+
+static DE_PLAIN: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'1', b'2', b'3', b'4', b'5', b'6', // 0x00
+    b'7', b'8', b'9', b'0', 0xdf, 0xb4, 0x08, b'\t', // 0x08
+    b'q', b'w', b'e', b'r', b't', b'z', b'u', b'i', // 0x10
+    b'o', b'p', 0xfc, b'+', b'\n', 0, b'a', b's', // 0x18
+    b'd', b'f', b'g', b'h', b'j', b'k', b'l', 0xf6, // 0x20
+    0xe4, b'^', 0, b'#', b'y', b'x', b'c', b'v', // 0x28
+    b'b', b'n', b'm', b',', b'.', b'-', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'<', 0, // 0x50
+    0, // 0x58
+];
+
+static DE_SHIFT: [u8; NR_SCANCODES] = [
+    0, 0x1b, b'!', b'"', 0xa7, b'$', b'%', b'&', // 0x00
+    b'/', b'(', b')', b'=', b'?', b'`', 0x08, b'\t', // 0x08
+    b'Q', b'W', b'E', b'R', b'T', b'Z', b'U', b'I', // 0x10
+    b'O', b'P', 0xdc, b'*', b'\n', 0, b'A', b'S', // 0x18
+    b'D', b'F', b'G', b'H', b'J', b'K', b'L', 0xd6, // 0x20
+    0xc4, 0xb0, 0, b'\'', b'Y', b'X', b'C', b'V', // 0x28
+    b'B', b'N', b'M', b';', b':', b'_', 0, b'*', // 0x30
+    0, b' ', 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, b'7', // 0x40
+    b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', // 0x48
+    b'2', b'3', b'0', b'.', 0, 0, b'>', 0, // 0x50
+    0, // 0x58
+];
+
+static DE_ALTGR: [u8; NR_SCANCODES] = [
+    0, 0, 0, 0xb2, 0xb3, 0, 0, 0, // 0x00
+    b'{', b'[', b']', b'}', b'\\', 0, 0, 0, // 0x08
+    b'@', 0, 0, 0, 0, 0, 0, 0, // 0x10
+    0, 0, 0, b'~', 0, 0, 0, 0, // 0x18
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x20
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x28
+    0, 0, 0xb5, 0, 0, 0, 0, 0, // 0x30
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x40
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x48
+    0, 0, 0, 0, 0, 0, b'|', 0, // 0x50
+    0, // 0x58
+];
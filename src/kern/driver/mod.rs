@@ -1,3 +1,7 @@
 pub mod serial;
 pub mod keyboard;
+pub mod mouse;
+pub mod keymap;
 pub mod video;
+pub mod hpet;
+pub mod rtc;
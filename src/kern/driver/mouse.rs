@@ -0,0 +1,181 @@
+//! PS/2 mouse on the auxiliary port of keyboard controller.
+//! packets are decoded in IRQ12 and queued for /dev/mouse, every read
+//! returns whole MousePacket records.
+
+use spin::Mutex;
+
+use ::kern::interrupts::idt::*;
+use ::kern::interrupts::irq::{self, Irqs};
+use ::kern::console::LogLevel::*;
+use ::kern::util::RingBuffer;
+use ::kern::task::WaitQueue;
+use ::kern::vfs::Result;
+use ::kern::vfs::dev::{self, Device};
+use super::keyboard::{KBD, MousePacket};
+use super::video::cursor;
+
+const EVENT_QUEUE_SIZE: usize = 128;
+
+#[allow(non_camel_case_types)]
+#[allow(dead_code)]
+pub enum MouseCommand {
+    SET_SCALING_1_1     =   0xE6,
+    SET_RESOLUTION      =   0xE8,
+    GET_ID              =   0xF2,
+    SET_SAMPLE_RATE     =   0xF3,
+    ENABLE_REPORTING    =   0xF4,
+    DISABLE_REPORTING   =   0xF5,
+    SET_DEFAULTS        =   0xF6,
+    RESET               =   0xFF
+}
+
+/// device ids reported by GET_ID
+const ID_STANDARD: u8 = 0;
+const ID_INTELLIMOUSE: u8 = 3;
+const ID_INTELLIMOUSE_5BTN: u8 = 4;
+
+pub struct Mouse {
+    present: bool,
+    bytes: [u8; 4],
+    idx: usize,
+    /// 3 for standard mouse, 4 with wheel
+    packet_size: usize
+}
+
+pub static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+
+lazy_static! {
+    static ref EVENTS: Mutex<RingBuffer<MousePacket>> = Mutex::new(RingBuffer::new(EVENT_QUEUE_SIZE));
+}
+
+/// readers of /dev/mouse waiting for EVENTS
+static WAITERS: WaitQueue = WaitQueue::new();
+
+impl Mouse {
+    pub const fn new() -> Mouse {
+        Mouse {
+            present: false,
+            bytes: [0; 4],
+            idx: 0,
+            packet_size: 3
+        }
+    }
+
+    pub fn has_wheel(&self) -> bool {
+        self.packet_size == 4
+    }
+
+    /// collect one byte, return a packet when complete
+    fn feed(&mut self, data: u8) -> Option<MousePacket> {
+        // bit 3 of the first byte is always set, use it to resync
+        if self.idx == 0 && data & 0x08 == 0 {
+            return None;
+        }
+
+        self.bytes[self.idx] = data;
+        self.idx += 1;
+        if self.idx < self.packet_size {
+            return None;
+        }
+        self.idx = 0;
+
+        let flags = self.bytes[0];
+        // x or y overflow, motion is garbage
+        if flags & 0xC0 != 0 {
+            return None;
+        }
+
+        // 9 bits two's complement, sign bits are 4 and 5 of the first byte
+        let dx = self.bytes[1] as i16 - (((flags as i16) << 4) & 0x100);
+        let dy = self.bytes[2] as i16 - (((flags as i16) << 3) & 0x100);
+        // low 4 bits are wheel motion for both intellimouse variants
+        let dz = match self.packet_size {
+            4 => ((self.bytes[3] << 4) as i8 >> 4) as i16,
+            _ => 0
+        };
+
+        Some(MousePacket {
+            flags: (flags & 0x07) as u16,
+            relx: dx,
+            rely: -dy,
+            relz: dz
+        })
+    }
+}
+
+/// read the id after the magic sample rate sequence, which switches
+/// capable mice into 4-byte packet mode.
+fn detect_intellimouse() -> u8 {
+    let mut kbd = KBD.lock();
+    for &rate in &[200u8, 100, 80] {
+        kbd.aux_send(MouseCommand::SET_SAMPLE_RATE as u8);
+        kbd.aux_send(rate);
+    }
+
+    if !kbd.aux_send(MouseCommand::GET_ID as u8) {
+        return ID_STANDARD;
+    }
+    kbd.aux_read().unwrap_or(ID_STANDARD)
+}
+
+pub fn present() -> bool {
+    MOUSE.lock().present
+}
+
+/// should be called with interrupts disabled, before IRQ12 is enabled
+pub fn init() {
+    {
+        let mut kbd = KBD.lock();
+        kbd.enable_aux();
+        if !kbd.aux_send(MouseCommand::SET_DEFAULTS as u8) {
+            printk!(Warn, "no ps/2 mouse found\n\r");
+            return;
+        }
+    }
+
+    let id = detect_intellimouse();
+    KBD.lock().aux_send(MouseCommand::ENABLE_REPORTING as u8);
+
+    {
+        let mut mouse = MOUSE.lock();
+        mouse.present = true;
+        mouse.packet_size = match id {
+            ID_INTELLIMOUSE | ID_INTELLIMOUSE_5BTN => 4,
+            _ => 3
+        };
+        printk!(Info, "ps/2 mouse id {}, wheel: {}\n\r", id, mouse.has_wheel());
+    }
+
+    dev::register(&MOUSE_DEV);
+}
+
+pub extern "C" fn mouse_irq(_frame: &mut ExceptionStackFrame) {
+    let data = KBD.lock().aux_data();
+    irq::eoi(Irqs::MOUSE);
+
+    let packet = match data {
+        Some(b) => MOUSE.lock().feed(b),
+        None => None
+    };
+
+    if let Some(p) = packet {
+        EVENTS.lock().push(p);
+        WAITERS.wake_all();
+        cursor::move_by(p.relx as i32, p.rely as i32);
+    }
+}
+
+/// /dev/mouse
+pub struct MouseDevice;
+
+pub static MOUSE_DEV: MouseDevice = MouseDevice;
+
+impl Device for MouseDevice {
+    fn name(&self) -> &'static str {
+        "mouse"
+    }
+
+    fn read(&self, buf: &mut [u8], flags: usize) -> Result<usize> {
+        dev::read_records(&*EVENTS, &WAITERS, buf, flags)
+    }
+}
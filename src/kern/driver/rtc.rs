@@ -0,0 +1,282 @@
+//! CMOS real-time clock, the source of wall-clock time.
+//!
+//! The RTC is read once at boot and the result is handed to the clock as
+//! the realtime offset of monotonic time, so reads in between cost nothing.
+//! With `rtc_irq` on the command line the update-ended interrupt resyncs
+//! the offset every second, right after the RTC moved to the next second.
+//! The RTC is assumed to keep UTC.
+
+use spin::Mutex;
+
+use ::kern::acpi;
+use ::kern::arch::cpu;
+use ::kern::arch::port::Port;
+use ::kern::bootinfo;
+use ::kern::clock::{self, NANOS_PER_SEC};
+use ::kern::console::LogLevel::*;
+use ::kern::interrupts::idt::ExceptionStackFrame;
+use ::kern::interrupts::irq::{self, Irqs};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+
+/// status A: an update is in progress, time registers are unstable
+const STATUS_A_UIP: u8 = 0x80;
+/// status B: update-ended interrupt enable
+const STATUS_B_UIE: u8 = 0x10;
+/// status B: registers are binary, not BCD
+const STATUS_B_BINARY: u8 = 0x04;
+/// status B: hours are 24h, not 12h
+const STATUS_B_24H: u8 = 0x02;
+/// status C: update-ended interrupt happened
+const STATUS_C_UF: u8 = 0x10;
+/// 12h mode: PM, in the hours register
+const HOURS_PM: u8 = 0x80;
+
+/// CMOS index of the century register from the FADT, 0 if there is none
+fn century_register() -> u8 {
+    acpi::get().and_then(|acpi| acpi.fadt.as_ref()).map(|f| f.century).unwrap_or(0)
+}
+
+pub struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>
+}
+
+impl Cmos {
+    pub const fn new() -> Cmos {
+        Cmos {
+            index: Port::new(CMOS_INDEX),
+            data: Port::new(CMOS_DATA)
+        }
+    }
+
+    pub fn read(&mut self, reg: u8) -> u8 {
+        self.index.write(reg);
+        self.data.read()
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.index.write(reg);
+        self.data.write(val);
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(RTC_STATUS_A) & STATUS_A_UIP != 0
+    }
+
+    /// time registers as they are, unconverted
+    fn read_raw(&mut self, century: u8) -> [u8; 7] {
+        [
+            self.read(RTC_SECONDS),
+            self.read(RTC_MINUTES),
+            self.read(RTC_HOURS),
+            self.read(RTC_DAY),
+            self.read(RTC_MONTH),
+            self.read(RTC_YEAR),
+            if century != 0 { self.read(century) } else { 0 }
+        ]
+    }
+
+    /// read the time without tearing: wait out an update, then read until
+    /// two reads agree. None if the RTC holds no valid date.
+    pub fn read_time(&mut self) -> Option<DateTime> {
+        let century = century_register();
+
+        while self.update_in_progress() {
+            ::kern::util::cpu_relax();
+        }
+        let mut raw = self.read_raw(century);
+        loop {
+            while self.update_in_progress() {
+                ::kern::util::cpu_relax();
+            }
+            let again = self.read_raw(century);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status = self.read(RTC_STATUS_B);
+        DateTime::from_raw(&raw, century != 0, status)
+    }
+}
+
+pub static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+/// None if v has a nibble above 9
+fn from_bcd(v: u8) -> Option<u8> {
+    if v >> 4 > 9 || v & 0x0f > 9 {
+        return None;
+    }
+    Some((v >> 4) * 10 + (v & 0x0f))
+}
+
+fn is_leap(year: u32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// calendar time, UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32
+}
+
+impl DateTime {
+    /// None for garbage: bad BCD digits, fields out of range or a date
+    /// before 1970, which the conversion to unix time can't take
+    fn from_raw(raw: &[u8; 7], has_century: bool, status: u8) -> Option<DateTime> {
+        let conv = |v: u8| if status & STATUS_B_BINARY != 0 { Some(v) } else { from_bcd(v) };
+        let field = |v: u8| conv(v).map(|v| v as u32);
+
+        let (second, minute, day, month, year) =
+            match (field(raw[0]), field(raw[1]), field(raw[3]), field(raw[4]), field(raw[5])) {
+                (Some(s), Some(mi), Some(d), Some(mo), Some(y)) if y <= 99 => (s, mi, d, mo, y),
+                _ => return None
+            };
+
+        let mut hour = match field(raw[2] & !HOURS_PM) {
+            Some(hour) => hour,
+            None => return None
+        };
+        if status & STATUS_B_24H == 0 {
+            if hour < 1 || hour > 12 {
+                return None;
+            }
+            // 12am is 0, 12pm is 12
+            hour %= 12;
+            if raw[2] & HOURS_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        let year = year + if has_century {
+            match field(raw[6]) {
+                Some(century) => century * 100,
+                None => return None
+            }
+        } else if year < 70 {
+            2000
+        } else {
+            1900
+        };
+
+        let time = DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second
+        };
+
+        let valid = time.year >= 1970 && time.month >= 1 && time.month <= 12 &&
+            time.day >= 1 && time.day <= days_in_month(time.year, time.month) &&
+            time.hour < 24 && time.minute < 60 && time.second < 60;
+        if valid { Some(time) } else { None }
+    }
+
+    /// days since 1970-01-01 of a proleptic gregorian date from 1970 on,
+    /// from_raw makes sure of that
+    fn days_since_epoch(&self) -> u64 {
+        // years start in march, so the leap day is last
+        let (y, m) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = y / 400;
+        let yoe = y - era * 400;
+        let doy = (153 * m + 2) / 5 + self.day as u64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// seconds since the unix epoch
+    pub fn unix_time(&self) -> u64 {
+        self.days_since_epoch() * 86400
+            + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+/// current RTC time, None if the RTC holds garbage
+pub fn read() -> Option<DateTime> {
+    let oflags = unsafe { cpu::push_flags() };
+    let time = CMOS.lock().read_time();
+    unsafe { cpu::pop_flags(oflags); }
+    time
+}
+
+fn sync(time: &DateTime) {
+    clock::set_realtime(time.unix_time() * NANOS_PER_SEC);
+}
+
+/// set wall-clock time from the RTC, after clock::init
+pub fn init() {
+    match read() {
+        Some(time) => {
+            sync(&time);
+            printk!(Info, "rtc: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC\n\r", time.year, time.month,
+                    time.day, time.hour, time.minute, time.second);
+        },
+        None => printk!(Warn, "rtc: invalid date, wall-clock time starts at the epoch\n\r")
+    }
+
+    if bootinfo::has_flag("rtc_irq") {
+        let oflags = unsafe { cpu::push_flags() };
+        {
+            let mut cmos = CMOS.lock();
+            let status = cmos.read(RTC_STATUS_B);
+            cmos.write(RTC_STATUS_B, status | STATUS_B_UIE);
+            // a pending flag would keep the irq from ever firing
+            cmos.read(RTC_STATUS_C);
+        }
+        irq::enable(Irqs::IRQ8);
+        unsafe { cpu::pop_flags(oflags); }
+        printk!(Info, "rtc: update-ended interrupt on\n\r");
+    }
+}
+
+/// IRQ8. every holder of CMOS has interrupts disabled, so lock won't
+/// deadlock on this cpu.
+pub extern "C" fn rtc_irq(_frame: &mut ExceptionStackFrame) {
+    {
+        let mut cmos = CMOS.lock();
+        // status C must be read, or the RTC raises no more interrupts
+        if cmos.read(RTC_STATUS_C) & STATUS_C_UF != 0 {
+            // the update just ended, registers are stable for almost a second
+            let century = century_register();
+            let raw = cmos.read_raw(century);
+            let status = cmos.read(RTC_STATUS_B);
+            if let Some(time) = DateTime::from_raw(&raw, century != 0, status) {
+                sync(&time);
+            }
+        }
+    }
+    irq::eoi(Irqs::IRQ8);
+}
@@ -1,13 +1,25 @@
 use kern::arch::port::Port;
+use kern::interrupts::idt::ExceptionStackFrame;
+use kern::interrupts::irq::{self, Irqs};
+use kern::sysrq;
 use spin::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::fmt;
 
 const SERIAL_PORT: u16 = 0x3f8;   /* COM1 */
 
+/// line status register bits
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_BREAK: u8 = 0x10;
+
 #[derive(Debug)]
 pub struct Serial {
     ports: [Port<u8>; 8]
 }
 
+/// a break condition was seen on COM1, next byte is a sysrq action
+static GOT_BREAK: AtomicBool = AtomicBool::new(false);
+
 pub static COM1: Mutex<Serial> = Mutex::new(Serial::new(SERIAL_PORT));
 
 impl Serial {
@@ -35,6 +47,7 @@ impl Serial {
         self.ports[3].write(0x03);    // 8 bits, no parity, one stop bit
         self.ports[2].write(0xC7);    // Enable FIFO, clear them, with 14-byte threshold
         self.ports[4].write(0x0B);    // IRQs enabled, RTS/DSR set
+        self.ports[1].write(0x05);    // Interrupt on received data and line status
     }
 
     unsafe fn is_transmit_empty(&mut self) -> bool {
@@ -58,7 +71,54 @@ impl Serial {
 
         self.ports[0].read()
     }
+
+    /// drain received bytes, a break followed by a byte gives a sysrq action
+    unsafe fn poll_sysrq(&mut self) -> Option<u8> {
+        let mut action = None;
+        loop {
+            let lsr = self.ports[5].read();
+            if lsr & (LSR_DATA_READY | LSR_BREAK) == 0 {
+                break;
+            }
+
+            // a break comes with a zero byte in the receive buffer
+            let data = self.ports[0].read();
+            if lsr & LSR_BREAK != 0 {
+                GOT_BREAK.store(true, Ordering::Relaxed);
+            } else if GOT_BREAK.swap(false, Ordering::Relaxed) {
+                action = Some(data);
+            }
+        }
+        action
+    }
 }
 
+/// COM1 output that never waits: when COM1 is locked it writes through
+/// own port handles, and may interleave with the interrupted output
+pub struct Emergency;
 
+impl fmt::Write for Emergency {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe {
+            match COM1.try_lock() {
+                Some(mut com1) => for b in s.bytes() { com1.write(b); },
+                None => {
+                    let mut com1 = Serial::new(SERIAL_PORT);
+                    for b in s.bytes() { com1.write(b); }
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
+pub extern "C" fn serial_irq(frame: &mut ExceptionStackFrame) {
+    // COM1 may be locked by the interrupted console output, which only
+    // touches the transmit side. use own port handles for the receive side.
+    let action = unsafe { Serial::new(SERIAL_PORT).poll_sysrq() };
+    irq::eoi(Irqs::IRQ4);
+
+    if let Some(ch) = action {
+        sysrq::handle(ch, frame);
+    }
+}
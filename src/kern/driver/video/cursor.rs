@@ -0,0 +1,92 @@
+//! mouse pointer drawn as a framebuffer overlay above the console
+
+use collections::Vec;
+use core::cmp::{min, max};
+use spin::Mutex;
+
+use ::kern::console::tty1;
+use ::kern::arch::cpu;
+use super::framebuffer::{Image, Point, Rect, Rgba};
+
+const ARROW: [&'static [u8]; 16] = [
+    b"X          ",
+    b"XX         ",
+    b"X.X        ",
+    b"X..X       ",
+    b"X...X      ",
+    b"X....X     ",
+    b"X.....X    ",
+    b"X......X   ",
+    b"X.......X  ",
+    b"X........X ",
+    b"X.....XXXXX",
+    b"X..X..X    ",
+    b"X.X X..X   ",
+    b"XX  X..X   ",
+    b"X    X..X  ",
+    b"     XXXX  ",
+];
+
+lazy_static! {
+    static ref ARROW_PIXELS: Vec<Rgba> = ARROW.iter()
+        .flat_map(|row| row.iter())
+        .map(|&b| match b {
+            b'X' => Rgba::from(0, 0, 0),
+            b'.' => Rgba::from(0xff, 0xff, 0xff),
+            _ => Rgba(0)
+        })
+        .collect();
+}
+
+struct Cursor {
+    pos: Point,
+    /// area the pointer may move in
+    bounds: Rect
+}
+
+static CURSOR: Mutex<Option<Cursor>> = Mutex::new(None);
+
+fn arrow() -> Image<'static> {
+    Image::new(ARROW[0].len() as i32, ARROW.len() as i32, &ARROW_PIXELS[..])
+}
+
+/// show pointer in the middle of screen, does nothing on text console
+pub fn init() {
+    let oflags = unsafe { cpu::push_flags() };
+    {
+        let mut con = tty1.lock();
+        if let Some(fb) = con.framebuffer() {
+            let bounds = fb.bounds();
+            let pos = Point{x: bounds.width / 2, y: bounds.height / 2};
+            fb.set_overlay(arrow(), pos);
+            *CURSOR.lock() = Some(Cursor { pos: pos, bounds: bounds });
+        }
+        con.refresh();
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// move pointer by relative motion, called from mouse irq.
+/// if console is busy, the pointer catches up on next motion.
+pub fn move_by(dx: i32, dy: i32) {
+    let pos = match *CURSOR.lock() {
+        Some(ref mut cursor) => {
+            let b = cursor.bounds;
+            cursor.pos.x = max(b.x, min(b.right() - 1, cursor.pos.x + dx));
+            cursor.pos.y = max(b.y, min(b.bottom() - 1, cursor.pos.y + dy));
+            cursor.pos
+        },
+        None => return
+    };
+
+    if let Some(mut con) = tty1.try_lock() {
+        if let Some(fb) = con.framebuffer() {
+            fb.move_overlay(pos);
+        }
+        con.refresh();
+    }
+}
+
+pub fn position() -> Option<Point> {
+    CURSOR.lock().as_ref().map(|c| c.pos)
+}
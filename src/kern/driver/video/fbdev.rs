@@ -0,0 +1,137 @@
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+use ::kern::vfs::{Error, Result};
+use ::kern::vfs::dev::{self, Device};
+use ::kern::memory::PAGE_SIZE;
+use ::kern::memory::paging::PhysicalAddress;
+use ::kern::console::tty1;
+use ::kern::arch::cpu;
+use ::kern::syscall;
+use ::kern::task;
+
+use super::framebuffer::Framebuffer;
+
+/// ioctl: fill a FbInfo at arg
+pub const FBIO_GET_INFO: usize = 0x4600;
+
+/// mode info for user programs, pixels in mmapped memory are in native format
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// bytes per scanline
+    pub pitch: u32,
+    pub bpp: u32,
+    pub red_pos: u8,
+    pub red_size: u8,
+    pub green_pos: u8,
+    pub green_size: u8,
+    pub blue_pos: u8,
+    pub blue_size: u8,
+    pub reserved: [u8; 2],
+    /// size of video memory in bytes
+    pub size: u32,
+}
+
+/// /dev/fb0, backed by the framebuffer console. only one client may open it,
+/// and the console stops drawing until the client closes it. the client's
+/// mappings of video memory go away with the close.
+pub struct FramebufferDevice {
+    owner: AtomicIsize, // pid of client, 0 if nobody
+}
+
+pub static FB0: FramebufferDevice = FramebufferDevice { owner: AtomicIsize::new(0) };
+
+fn with_framebuffer<F, R>(f: F) -> Option<R> where F: FnOnce(&mut Framebuffer) -> R {
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = tty1.lock().framebuffer().map(f);
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
+
+fn set_console_suspended(val: bool) {
+    let oflags = unsafe { cpu::push_flags() };
+    {
+        let mut con = tty1.lock();
+        if val { con.suspend(); } else { con.resume(); }
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+impl Device for FramebufferDevice {
+    fn name(&self) -> &'static str {
+        "fb0"
+    }
+
+    fn open(&self, _flags: usize) -> Result<()> {
+        let pid = task::current_id();
+        if self.owner.compare_and_swap(0, pid, Ordering::SeqCst) != 0 {
+            return Err(Error::Busy);
+        }
+
+        set_console_suspended(true);
+        Ok(())
+    }
+
+    fn close(&self) {
+        // a task being killed has no address space left to clean up
+        task::with_current(|task| {
+            if let Some(ref mut space) = task.space {
+                space.remove_device(self.name());
+            }
+        });
+        self.owner.store(0, Ordering::SeqCst);
+        set_console_suspended(false);
+    }
+
+    fn ioctl(&self, cmd: usize, arg: usize) -> Result<usize> {
+        use core::mem::size_of;
+
+        match cmd {
+            FBIO_GET_INFO => {
+                let info = with_framebuffer(|fb| FbInfo {
+                    width: fb.width as u32,
+                    height: fb.height as u32,
+                    pitch: fb.pitch as u32,
+                    bpp: fb.format.bpp as u32,
+                    red_pos: fb.format.red_pos,
+                    red_size: fb.format.red_size,
+                    green_pos: fb.format.green_pos,
+                    green_size: fb.format.green_size,
+                    blue_pos: fb.format.blue_pos,
+                    blue_size: fb.format.blue_size,
+                    reserved: [0; 2],
+                    size: fb.size() as u32,
+                }).ok_or(Error::NoEntry)?;
+
+                syscall::verify_user_area_mut(arg, size_of::<FbInfo>())?;
+                unsafe { ::core::ptr::write(arg as *mut FbInfo, info); }
+                Ok(0)
+            },
+            _ => Err(Error::Invalid)
+        }
+    }
+
+    /// only for the owner, so pages can't be faulted in after close
+    fn mmap(&self, offset: usize, len: usize) -> Result<PhysicalAddress> {
+        if self.owner.load(Ordering::SeqCst) != task::current_id() {
+            return Err(Error::NotPermitted);
+        }
+
+        let (base, size) = with_framebuffer(|fb| (fb.phys_addr, fb.size())).ok_or(Error::NoEntry)?;
+        // the last page may be partially used
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if offset % PAGE_SIZE != 0 || offset.checked_add(len).map_or(true, |end| end > size) {
+            return Err(Error::Invalid);
+        }
+
+        Ok(base + offset)
+    }
+}
+
+pub fn init() {
+    if with_framebuffer(|_| ()).is_some() {
+        dev::register(&FB0);
+    }
+}
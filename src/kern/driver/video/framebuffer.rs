@@ -1,26 +1,62 @@
 use core::ptr::{Unique, copy_nonoverlapping, copy, write_volatile};
-use core::cmp::min;
+use core::cmp::{min, max};
 
 use core::slice::from_raw_parts_mut;
 use core::slice::SliceExt;
+use collections::Vec;
 use multiboot2;
 use ::kern::memory::KERNEL_MAPPING;
 use super::builtin_font::{BUILTIN_FONT, BUILTIN_FONTINFO};
 
+/// alpha of an opaque color, the only place it's spelled out
+pub const OPAQUE: u8 = 0xff;
+
+/// 0xAARRGGBB, alpha is opacity: 0 is fully transparent
 #[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 pub struct Rgba(pub u32);
 
 impl Rgba {
+    /// alpha included in val
     pub const fn new(val: u32) -> Rgba {
         Rgba(val)
     }
 
+    /// opaque color from 0xRRGGBB
+    pub const fn rgb(val: u32) -> Rgba {
+        Rgba((OPAQUE as u32) << 24 | (val & 0xffffff))
+    }
+
+    /// opaque color
     pub fn from(r: u8, g: u8, b: u8) -> Rgba {
-        let v = (r as u32) << 16 | (g as u32) << 8 | (b as u32);
+        Rgba::from_rgba(r, g, b, OPAQUE)
+    }
+
+    /// `a` is opacity: 0 is fully transparent and 0xff fully opaque
+    pub fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Rgba {
+        let v = (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | (b as u32);
         Rgba(v)
     }
 
+    pub fn with_alpha(&self, a: u8) -> Rgba {
+        Rgba((self.0 & 0x00ffffff) | (a as u32) << 24)
+    }
+
+    /// composite self over dst (source-over), result is opaque
+    pub fn blend(&self, dst: Rgba) -> Rgba {
+        // x / 255 without division, exact for x in 0..255*255
+        fn div255(x: u32) -> u32 {
+            (x + 1 + (x >> 8)) >> 8
+        }
+
+        let a = self.a() as u32;
+        let na = 255 - a;
+        let r = div255(self.r() as u32 * a + dst.r() as u32 * na);
+        let g = div255(self.g() as u32 * a + dst.g() as u32 * na);
+        let b = div255(self.b() as u32 * a + dst.b() as u32 * na);
+        Rgba::from(r as u8, g as u8, b as u8)
+    }
+
     pub const fn a(&self) -> u8 {
         (self.0 >> 24) as u8
     }
@@ -38,44 +74,463 @@ impl Rgba {
     }
 }
 
+/// pixel layout of video memory. Rgba is always 0xAARRGGBB in memory, it
+/// gets converted into native format when writing into VRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bpp: u8,
+    pub red_pos: u8,
+    pub red_size: u8,
+    pub green_pos: u8,
+    pub green_size: u8,
+    pub blue_pos: u8,
+    pub blue_size: u8,
+}
+
+impl PixelFormat {
+    /// 32 bpp xRGB, the same layout as Rgba
+    pub const fn xrgb8888() -> PixelFormat {
+        PixelFormat {
+            bpp: 32,
+            red_pos: 16, red_size: 8,
+            green_pos: 8, green_size: 8,
+            blue_pos: 0, blue_size: 8,
+        }
+    }
+
+    /// color info follows the fixed part of framebuffer tag (type 1 only):
+    ///   u32 type, u32 size, u64 addr, u32 pitch, u32 width, u32 height,
+    ///   u8 bpp, u8 type, u16 reserved, then 6 bytes of position/mask size
+    /// a tag too short to hold them gets a format guessed from bpp.
+    pub fn from_tag(fb: &multiboot2::FramebufferTag) -> PixelFormat {
+        const SIZE_OFFSET: isize = 4;
+        const COLOR_INFO_OFFSET: isize = 32;
+        const COLOR_INFO_LEN: usize = 6;
+
+        let base = fb as *const multiboot2::FramebufferTag as *const u8;
+        let size = unsafe { *(base.offset(SIZE_OFFSET) as *const u32) } as usize;
+        if size < COLOR_INFO_OFFSET as usize + COLOR_INFO_LEN {
+            return PixelFormat::guess(fb.bpp);
+        }
+
+        let info = unsafe {
+            ::core::slice::from_raw_parts(base.offset(COLOR_INFO_OFFSET), COLOR_INFO_LEN)
+        };
+
+        let fmt = PixelFormat {
+            bpp: fb.bpp,
+            red_pos: info[0], red_size: info[1],
+            green_pos: info[2], green_size: info[3],
+            blue_pos: info[4], blue_size: info[5],
+        };
+
+        // some bioses leave color info empty, guess from bpp
+        if fmt.red_size == 0 || fmt.green_size == 0 || fmt.blue_size == 0 {
+            PixelFormat::guess(fb.bpp)
+        } else {
+            fmt
+        }
+    }
+
+    fn guess(bpp: u8) -> PixelFormat {
+        match bpp {
+            15 => PixelFormat {
+                bpp: 15,
+                red_pos: 10, red_size: 5,
+                green_pos: 5, green_size: 5,
+                blue_pos: 0, blue_size: 5,
+            },
+            16 => PixelFormat {
+                bpp: 16,
+                red_pos: 11, red_size: 5,
+                green_pos: 5, green_size: 6,
+                blue_pos: 0, blue_size: 5,
+            },
+            24 => PixelFormat { bpp: 24, .. PixelFormat::xrgb8888() },
+            _ => PixelFormat::xrgb8888()
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize + 7) / 8
+    }
+
+    /// native layout is identical to Rgba, so rows can be copied directly
+    pub fn is_native_rgba(&self) -> bool {
+        *self == PixelFormat::xrgb8888()
+    }
+
+    #[inline]
+    pub fn encode(&self, c: Rgba) -> u32 {
+        #[inline]
+        fn channel(v: u8, pos: u8, size: u8) -> u32 {
+            let v = if size >= 8 {
+                (v as u32) << (size - 8)
+            } else {
+                (v as u32) >> (8 - size)
+            };
+            v << pos
+        }
+
+        channel(c.r(), self.red_pos, self.red_size) |
+            channel(c.g(), self.green_pos, self.green_size) |
+            channel(c.b(), self.blue_pos, self.blue_size)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Point {
     pub x: i32,
     pub y: i32
 }
 
+/// axis aligned rectangle, (x, y) is the top left corner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect { x: x, y: y, width: width, height: height }
+    }
+
+    pub const fn empty() -> Rect {
+        Rect { x: 0, y: 0, width: 0, height: 0 }
+    }
+
+    /// smallest rect containing both p1 and p2
+    pub fn from_points(p1: Point, p2: Point) -> Rect {
+        let (l, t) = (min(p1.x, p2.x), min(p1.y, p2.y));
+        let (r, b) = (max(p1.x, p2.x), max(p1.y, p2.y));
+        Rect::new(l, t, r - l + 1, b - t + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    /// exclusive
+    pub fn right(&self) -> i32 {
+        self.x + self.width
+    }
+
+    /// exclusive
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height
+    }
+
+    pub fn area(&self) -> i32 {
+        if self.is_empty() { 0 } else { self.width * self.height }
+    }
+
+    pub fn contains(&self, p: Point) -> bool {
+        p.x >= self.x && p.x < self.right() && p.y >= self.y && p.y < self.bottom()
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let (l, t) = (max(self.x, other.x), max(self.y, other.y));
+        let (r, b) = (min(self.right(), other.right()), min(self.bottom(), other.bottom()));
+        if l >= r || t >= b {
+            Rect::empty()
+        } else {
+            Rect::new(l, t, r - l, b - t)
+        }
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+
+        let (l, t) = (min(self.x, other.x), min(self.y, other.y));
+        let (r, b) = (max(self.right(), other.right()), max(self.bottom(), other.bottom()));
+        Rect::new(l, t, r - l, b - t)
+    }
+
+    /// overlapped or adjacent
+    fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right() && other.x <= self.right() &&
+            self.y <= other.bottom() && other.y <= self.bottom()
+    }
+}
+
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// changed areas of back buffer which are not flushed into video memory yet.
+/// touching rects are merged, and when the list is full, everything collapses
+/// into the bounding box.
+struct DirtyRegion {
+    rects: [Rect; MAX_DIRTY_RECTS],
+    count: usize
+}
+
+impl DirtyRegion {
+    const fn new() -> DirtyRegion {
+        DirtyRegion {
+            rects: [Rect::empty(); MAX_DIRTY_RECTS],
+            count: 0
+        }
+    }
+
+    fn add(&mut self, r: Rect) {
+        if r.is_empty() {
+            return;
+        }
+
+        let mut r = r;
+        // merging may make the new rect touch others, so rescan until stable
+        let mut i = 0;
+        while i < self.count {
+            if self.rects[i].touches(&r) {
+                r = r.union(&self.rects[i]);
+                self.count -= 1;
+                self.rects[i] = self.rects[self.count];
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.count == MAX_DIRTY_RECTS {
+            let bbox = self.rects.iter().fold(r, |acc, rc| acc.union(rc));
+            self.rects[0] = bbox;
+            self.count = 1;
+        } else {
+            self.rects[self.count] = r;
+            self.count += 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    fn as_slice(&self) -> &[Rect] {
+        &self.rects[..self.count]
+    }
+}
+
+/// borrowed in-memory RGBA image, row major without padding
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub width: i32,
+    pub height: i32,
+    pub pixels: &'a [Rgba]
+}
+
+impl<'a> Image<'a> {
+    pub fn new(width: i32, height: i32, pixels: &'a [Rgba]) -> Image<'a> {
+        assert!(width >= 0 && height >= 0 && pixels.len() >= (width * height) as usize);
+        Image { width: width, height: height, pixels: pixels }
+    }
+
+    #[inline]
+    pub fn pixel(&self, x: i32, y: i32) -> Rgba {
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+/// image drawn on top while flushing, never stored in back buffer
+#[derive(Debug, Clone, Copy)]
+struct Overlay {
+    image: Image<'static>,
+    pos: Point
+}
+
+impl Overlay {
+    fn bounds(&self) -> Rect {
+        Rect::new(self.pos.x, self.pos.y, self.image.width, self.image.height)
+    }
+}
+
+/// All drawing goes into an off-screen back buffer in RAM, and only dirty
+/// rects are copied into video memory by `flush`. Reading back from VRAM is
+/// extremely slow, so nothing reads it.
 pub struct Framebuffer {
-    buf: Unique<Rgba>,
+    vram: Unique<u8>,
+    back: Vec<Rgba>,
+    dirty: DirtyRegion,
+    /// every write is restricted to this rect, always inside bounds
+    clip: Rect,
+    overlay: Option<Overlay>,
+    pub format: PixelFormat,
+    /// physical address of video memory
+    pub phys_addr: usize,
     pub width: i32,
     pub height: i32,
+    /// bytes per scanline of video memory
     pub pitch: i32
 }
 
 impl Framebuffer {
     pub fn new(fb: &multiboot2::FramebufferTag) -> Framebuffer {
         assert!(fb.frame_type == multiboot2::FramebufferType::Rgb);
-        assert!(fb.bpp == 32);
+        assert!(fb.bpp == 15 || fb.bpp == 16 || fb.bpp == 24 || fb.bpp == 32,
+            "unsupported framebuffer bpp {}", fb.bpp);
 
         let base = fb.addr as usize + KERNEL_MAPPING.KernelMap.start;
+        let (width, height) = (fb.width as i32, fb.height as i32);
 
         unsafe {
             Framebuffer {
-                buf: Unique::new_unchecked(base as *mut Rgba),
-                width: fb.width as i32,
-                height: fb.height as i32,
+                vram: Unique::new_unchecked(base as *mut u8),
+                back: vec![Rgba::rgb(0); (width * height) as usize],
+                dirty: DirtyRegion::new(),
+                clip: Rect::new(0, 0, width, height),
+                overlay: None,
+                format: PixelFormat::from_tag(fb),
+                phys_addr: fb.addr as usize,
+                width: width,
+                height: height,
                 pitch: fb.pitch as i32
             }
         }
     }
 
+    /// pointer to back buffer
     pub unsafe fn get_mut(&mut self) -> *mut Rgba {
-        self.buf.as_mut() as *mut _
+        self.back.as_mut_ptr()
+    }
+
+    /// size of video memory in bytes
+    pub fn size(&self) -> usize {
+        (self.pitch * self.height) as usize
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// restrict all following drawing to r
+    pub fn set_clip(&mut self, r: Rect) {
+        self.clip = r.intersect(&self.bounds());
+    }
+
+    pub fn reset_clip(&mut self) {
+        self.clip = self.bounds();
+    }
+
+    /// mark r as changed, it'll be written into video memory by next flush
+    pub fn invalidate(&mut self, r: Rect) {
+        let r = r.intersect(&self.bounds());
+        self.dirty.add(r);
+    }
+
+    pub fn invalidate_all(&mut self) {
+        let r = self.bounds();
+        self.dirty.add(r);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.count > 0
+    }
+
+    /// copy dirty rects from back buffer into video memory, converting
+    /// into native pixel format on the way. overlay is composited last.
+    pub fn flush(&mut self) {
+        let vram = unsafe { self.vram.as_mut() as *mut u8 };
+        let bytes_pp = self.format.bytes_per_pixel() as isize;
+        let native = self.format.is_native_rgba();
+
+        for r in self.dirty.as_slice() {
+            for y in r.y..r.bottom() {
+                let src = &self.back[(y * self.width + r.x) as usize..(y * self.width + r.right()) as usize];
+                let dst = unsafe { vram.offset(y as isize * self.pitch as isize + r.x as isize * bytes_pp) };
+
+                if native {
+                    unsafe { copy_nonoverlapping(src.as_ptr(), dst as *mut Rgba, src.len()); }
+                    continue;
+                }
+
+                for (i, &c) in src.iter().enumerate() {
+                    unsafe { self.store(dst.offset(i as isize * bytes_pp), c); }
+                }
+            }
+        }
+
+        if let Some(ov) = self.overlay {
+            let area = ov.bounds().intersect(&self.bounds());
+            if self.dirty.as_slice().iter().any(|r| !r.intersect(&area).is_empty()) {
+                self.draw_overlay(vram, &ov, area);
+            }
+        }
+
+        self.dirty.clear();
+    }
+
+    /// write one pixel into video memory at dst
+    #[inline]
+    unsafe fn store(&self, dst: *mut u8, c: Rgba) {
+        let v = self.format.encode(c);
+        match self.format.bytes_per_pixel() {
+            4 => write_volatile(dst as *mut u32, v),
+            3 => {
+                write_volatile(dst, v as u8);
+                write_volatile(dst.offset(1), (v >> 8) as u8);
+                write_volatile(dst.offset(2), (v >> 16) as u8);
+            },
+            _ => write_volatile(dst as *mut u16, v as u16),
+        }
+    }
+
+    fn draw_overlay(&self, vram: *mut u8, ov: &Overlay, area: Rect) {
+        let bytes_pp = self.format.bytes_per_pixel() as isize;
+        for y in area.y..area.bottom() {
+            for x in area.x..area.right() {
+                let c = ov.image.pixel(x - ov.pos.x, y - ov.pos.y);
+                if c.a() == 0 {
+                    continue;
+                }
+
+                let under = self.back[(y * self.width + x) as usize];
+                unsafe {
+                    let dst = vram.offset(y as isize * self.pitch as isize + x as isize * bytes_pp);
+                    self.store(dst, c.blend(under));
+                }
+            }
+        }
+    }
+
+    /// show img above everything at pos (top left), like a mouse cursor.
+    /// the overlay never touches the back buffer.
+    pub fn set_overlay(&mut self, img: Image<'static>, pos: Point) {
+        self.remove_overlay();
+        let ov = Overlay { image: img, pos: pos };
+        self.invalidate(ov.bounds());
+        self.overlay = Some(ov);
+    }
+
+    pub fn move_overlay(&mut self, pos: Point) {
+        if let Some(mut ov) = self.overlay {
+            self.invalidate(ov.bounds());
+            ov.pos = pos;
+            self.invalidate(ov.bounds());
+            self.overlay = Some(ov);
+        }
+    }
+
+    pub fn remove_overlay(&mut self) {
+        if let Some(ov) = self.overlay.take() {
+            self.invalidate(ov.bounds());
+        }
     }
 
     //TODO: optimize situation when dy == 0
-    //TODO: add anti-aliasing based on xiaolin wu's algorithm
-    // based on wikipedia bresenham line algorithm
+    // based on wikipedia bresenham line algorithm. alpha of rgb is respected
     pub fn draw_line(&mut self, p1: Point, p2: Point, rgb: Rgba) {
+        self.invalidate(Rect::from_points(p1, p2));
+
         let dx = (p2.x - p1.x).abs();
         let dy = (p2.y - p1.y).abs();
         let mut e = 0;
@@ -88,7 +543,7 @@ impl Framebuffer {
             let dir = if p2.y >= p1.y {1} else {-1};
 
             for x in p1.x..p2.x+1 {
-                self.draw_pixel(Point{x: x, y: y}, rgb);
+                self.blend_pixel(Point{x: x, y: y}, rgb);
                 e += 2 * dy;
                 if e > 1 {
                     e -= 2 * dx;
@@ -104,7 +559,7 @@ impl Framebuffer {
             let dir = if p2.x >= p1.x {1} else {-1};
 
             for y in p1.y..p2.y+1 {
-                self.draw_pixel(Point{x: x, y: y}, rgb);
+                self.blend_pixel(Point{x: x, y: y}, rgb);
                 e += 2 * dx;
                 if e > 1 {
                     e -= 2 * dy;
@@ -114,16 +569,293 @@ impl Framebuffer {
         }
     }
 
-    fn draw_pixel(&mut self, p: Point, rgb: Rgba) {
-        unsafe {
-            let c = self.get_mut().offset((p.y * self.width as i32 + p.x) as isize);
-            write_volatile(c, rgb);
+    /// write into back buffer without dirty tracking, caller should invalidate
+    #[inline]
+    fn put_pixel(&mut self, p: Point, rgb: Rgba) {
+        if self.clip.contains(p) {
+            let off = (p.y * self.width + p.x) as usize;
+            self.back[off] = rgb;
+        }
+    }
+
+    /// like put_pixel, but composites rgb over what's already there
+    #[inline]
+    fn blend_pixel(&mut self, p: Point, rgb: Rgba) {
+        match rgb.a() {
+            0 => {},
+            OPAQUE => self.put_pixel(p, rgb),
+            _ => if self.clip.contains(p) {
+                let off = (p.y * self.width + p.x) as usize;
+                self.back[off] = rgb.blend(self.back[off]);
+            }
+        }
+    }
+
+    /// composite rgb over pixels [x0, x1) of row y
+    fn blend_span(&mut self, y: i32, x0: i32, x1: i32, rgb: Rgba) {
+        if y < self.clip.y || y >= self.clip.bottom() || rgb.a() == 0 {
+            return;
+        }
+
+        let (x0, x1) = (max(x0, self.clip.x), min(x1, self.clip.right()));
+        if x0 >= x1 {
+            return;
+        }
+
+        let off = (y * self.width) as usize;
+        let row = &mut self.back[off + x0 as usize..off + x1 as usize];
+        if rgb.a() == OPAQUE {
+            for p in row {
+                *p = rgb;
+            }
+        } else {
+            for p in row {
+                *p = rgb.blend(*p);
+            }
+        }
+    }
+
+    pub fn draw_pixel(&mut self, p: Point, rgb: Rgba) {
+        self.blend_pixel(p, rgb);
+        self.invalidate(Rect::new(p.x, p.y, 1, 1));
+    }
+
+    /// read back from the back buffer, black if outside of screen
+    pub fn get_pixel(&self, p: Point) -> Rgba {
+        if self.bounds().contains(p) {
+            self.back[(p.y * self.width + p.x) as usize]
+        } else {
+            Rgba::rgb(0)
+        }
+    }
+
+    /// anti-aliased line based on xiaolin wu's algorithm, alpha of rgb
+    /// is respected. uses 16.16 fixed point, we have no fpu in kernel.
+    pub fn draw_line_aa(&mut self, p1: Point, p2: Point, rgb: Rgba) {
+        self.invalidate(Rect::from_points(p1, p2).union(
+                &Rect::from_points(Point{x: p1.x + 1, y: p1.y + 1}, Point{x: p2.x + 1, y: p2.y + 1})));
+
+        let steep = (p2.y - p1.y).abs() > (p2.x - p1.x).abs();
+        let (mut x0, mut y0, mut x1, mut y1) = match steep {
+            true => (p1.y, p1.x, p2.y, p2.x),
+            false => (p1.x, p1.y, p2.x, p2.y)
+        };
+        if x0 > x1 {
+            ::core::mem::swap(&mut x0, &mut x1);
+            ::core::mem::swap(&mut y0, &mut y1);
+        }
+
+        let dx = x1 - x0;
+        let gradient = match dx {
+            0 => 1 << 16,
+            _ => ((y1 - y0) << 16) / dx
+        };
+
+        let alpha = rgb.a() as u32;
+        let mut y = y0 << 16;
+        for x in x0..x1+1 {
+            let iy = y >> 16;
+            let frac = ((y & 0xffff) >> 8) as u32;
+            let c1 = rgb.with_alpha(((255 - frac) * alpha / 255) as u8);
+            let c2 = rgb.with_alpha((frac * alpha / 255) as u8);
+
+            if steep {
+                self.blend_pixel(Point{x: iy, y: x}, c1);
+                self.blend_pixel(Point{x: iy + 1, y: x}, c2);
+            } else {
+                self.blend_pixel(Point{x: x, y: iy}, c1);
+                self.blend_pixel(Point{x: x, y: iy + 1}, c2);
+            }
+            y += gradient;
+        }
+    }
+
+    /// closed outline through all points
+    pub fn draw_polygon(&mut self, points: &[Point], rgb: Rgba) {
+        if points.len() < 2 {
+            return;
+        }
+
+        for i in 0..points.len() {
+            let next = points[(i + 1) % points.len()];
+            self.draw_line(points[i], next, rgb);
+        }
+    }
+
+    /// scanline fill using even-odd rule, works for concave and self
+    /// intersecting polygons too. alpha of rgb is respected.
+    pub fn fill_polygon(&mut self, points: &[Point], rgb: Rgba) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let (top, bottom) = points.iter().fold((i32::max_value(), i32::min_value()),
+            |(t, b), p| (min(t, p.y), max(b, p.y)));
+        let (left, right) = points.iter().fold((i32::max_value(), i32::min_value()),
+            |(l, r), p| (min(l, p.x), max(r, p.x)));
+
+        let top = max(top, self.clip.y);
+        let bottom = min(bottom, self.clip.bottom() - 1);
+        let bounds = Rect::new(left, top, right - left + 1, bottom - top + 1).intersect(&self.clip);
+        if top > bottom || bounds.is_empty() {
+            return;
+        }
+
+        let mut xs: Vec<i32> = Vec::with_capacity(points.len());
+        for y in top..bottom+1 {
+            xs.clear();
+            for i in 0..points.len() {
+                let (a, b) = (points[i], points[(i + 1) % points.len()]);
+                // half open so that shared vertices are counted once
+                if (a.y <= y && y < b.y) || (b.y <= y && y < a.y) {
+                    xs.push(a.x + (y - a.y) * (b.x - a.x) / (b.y - a.y));
+                }
+            }
+            xs.sort();
+
+            for pair in xs.chunks(2).filter(|pair| pair.len() == 2) {
+                let (x0, x1) = (max(pair[0], bounds.x), min(pair[1] + 1, bounds.right()));
+                if x0 < x1 {
+                    self.blend_span(y, x0, x1, rgb);
+                }
+            }
+        }
+
+        self.invalidate(bounds);
+    }
+
+    fn ellipse_bounds(center: Point, rx: i32, ry: i32) -> Rect {
+        Rect::new(center.x - rx, center.y - ry, 2 * rx + 1, 2 * ry + 1)
+    }
+
+    /// midpoint ellipse algorithm, axis aligned with radii rx and ry
+    pub fn draw_ellipse(&mut self, center: Point, rx: i32, ry: i32, rgb: Rgba) {
+        if rx < 0 || ry < 0 {
+            return;
+        }
+        self.invalidate(Framebuffer::ellipse_bounds(center, rx, ry));
+
+        let Point {x: x0, y: y0} = center;
+        // products overflow i32 for large radii
+        let (rx2, ry2) = (rx as i64 * rx as i64, ry as i64 * ry as i64);
+        let (mut x, mut y) = (0i64, ry as i64);
+        let (mut px, mut py) = (0i64, 2 * rx2 * y);
+
+        let plot4 = |fb: &mut Framebuffer, x: i64, y: i64| {
+            let (x, y) = (x as i32, y as i32);
+            fb.blend_pixel(Point{x: x0 + x, y: y0 + y}, rgb);
+            fb.blend_pixel(Point{x: x0 - x, y: y0 + y}, rgb);
+            fb.blend_pixel(Point{x: x0 + x, y: y0 - y}, rgb);
+            fb.blend_pixel(Point{x: x0 - x, y: y0 - y}, rgb);
+        };
+
+        // region 1: slope > -1
+        let mut p = ry2 - rx2 * ry as i64 + rx2 / 4;
+        while px < py {
+            plot4(self, x, y);
+            x += 1;
+            px += 2 * ry2;
+            if p < 0 {
+                p += ry2 + px;
+            } else {
+                y -= 1;
+                py -= 2 * rx2;
+                p += ry2 + px - py;
+            }
+        }
+
+        // region 2: slope <= -1
+        p = ry2 * (2 * x + 1) * (2 * x + 1) / 4 + rx2 * (y - 1) * (y - 1) - rx2 * ry2;
+        while y >= 0 {
+            plot4(self, x, y);
+            y -= 1;
+            py -= 2 * rx2;
+            if p > 0 {
+                p += rx2 - py;
+            } else {
+                x += 1;
+                px += 2 * ry2;
+                p += rx2 - py + px;
+            }
+        }
+    }
+
+    /// alpha of rgb is respected, every pixel is touched only once
+    pub fn fill_ellipse(&mut self, center: Point, rx: i32, ry: i32, rgb: Rgba) {
+        fn isqrt(v: i64) -> i64 {
+            if v <= 0 {
+                return 0;
+            }
+            let mut x = v;
+            let mut y = (x + 1) / 2;
+            while y < x {
+                x = y;
+                y = (x + v / x) / 2;
+            }
+            x
+        }
+
+        if rx < 0 || ry < 0 {
+            return;
+        }
+
+        let (rx2, ry2) = (rx as i64 * rx as i64, ry as i64 * ry as i64);
+        for dy in -ry..ry+1 {
+            // half width of the row: rx * sqrt(1 - dy^2/ry^2)
+            let hw = match ry {
+                0 => rx as i64,
+                _ => isqrt(rx2 * (ry2 - dy as i64 * dy as i64) / ry2)
+            } as i32;
+            self.blend_span(center.y + dy, center.x - hw, center.x + hw + 1, rgb);
+        }
+
+        self.invalidate(Framebuffer::ellipse_bounds(center, rx, ry));
+    }
+
+    /// composite rgb over the rect
+    pub fn fill_rect_alpha(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) {
+        let r = Rect::new(top_left.x, top_left.y, width, height);
+        for y in r.y..r.bottom() {
+            self.blend_span(y, r.x, r.right(), rgb);
+        }
+        self.invalidate(r);
+    }
+
+    /// draw img with top left corner at dst, compositing by per pixel alpha
+    pub fn blit_image(&mut self, dst: Point, img: &Image) {
+        let r = Rect::new(dst.x, dst.y, img.width, img.height).intersect(&self.clip);
+        for y in r.y..r.bottom() {
+            for x in r.x..r.right() {
+                let c = img.pixel(x - dst.x, y - dst.y);
+                self.blend_pixel(Point{x: x, y: y}, c);
+            }
+        }
+        self.invalidate(r);
+    }
+
+    /// draw img stretched into dst, nearest neighbour sampling
+    pub fn blit_image_scaled(&mut self, dst: Rect, img: &Image) {
+        if dst.is_empty() || img.width == 0 || img.height == 0 {
+            return;
+        }
+
+        let r = dst.intersect(&self.clip);
+        for y in r.y..r.bottom() {
+            let sy = (y - dst.y) * img.height / dst.height;
+            for x in r.x..r.right() {
+                let sx = (x - dst.x) * img.width / dst.width;
+                let c = img.pixel(sx, sy);
+                self.blend_pixel(Point{x: x, y: y}, c);
+            }
         }
+        self.invalidate(r);
     }
 
     // based on http://web.engr.oregonstate.edu/~sllu/bcircle.pdf
     pub fn draw_circle(&mut self, center: Point, radius: i32, rgb: Rgba) {
         let Point {x: x0, y: y0} = center;
+        self.invalidate(Rect::new(x0 - radius, y0 - radius, 2 * radius + 1, 2 * radius + 1));
+
         let mut x = radius;
         let mut y = 0;
         let mut err = 0;
@@ -131,14 +863,14 @@ impl Framebuffer {
         let mut ychange = 1;
 
         while x >= y {
-            self.draw_pixel(Point{x: x0 + x, y: y0 + y}, rgb);
-            self.draw_pixel(Point{x: x0 + y, y: y0 + x}, rgb);
-            self.draw_pixel(Point{x: x0 - y, y: y0 + x}, rgb);
-            self.draw_pixel(Point{x: x0 - x, y: y0 + y}, rgb);
-            self.draw_pixel(Point{x: x0 - x, y: y0 - y}, rgb);
-            self.draw_pixel(Point{x: x0 - y, y: y0 - x}, rgb);
-            self.draw_pixel(Point{x: x0 + y, y: y0 - x}, rgb);
-            self.draw_pixel(Point{x: x0 + x, y: y0 - y}, rgb);
+            self.blend_pixel(Point{x: x0 + x, y: y0 + y}, rgb);
+            self.blend_pixel(Point{x: x0 + y, y: y0 + x}, rgb);
+            self.blend_pixel(Point{x: x0 - y, y: y0 + x}, rgb);
+            self.blend_pixel(Point{x: x0 - x, y: y0 + y}, rgb);
+            self.blend_pixel(Point{x: x0 - x, y: y0 - y}, rgb);
+            self.blend_pixel(Point{x: x0 - y, y: y0 - x}, rgb);
+            self.blend_pixel(Point{x: x0 + y, y: y0 - x}, rgb);
+            self.blend_pixel(Point{x: x0 + x, y: y0 - y}, rgb);
 
             y += 1;
             err += ychange;
@@ -192,6 +924,7 @@ impl Framebuffer {
         self.draw_line(Point{x: l, y: b}, Point{x: r, y: b}, rgb);
     }
 
+    /// vertical gradient, alpha is interpolated along with the color
     pub fn fill_rect_grad(&mut self, top_left: Point, width: i32, height: i32,
                           from: Rgba, to: Rgba) {
 
@@ -203,129 +936,81 @@ impl Framebuffer {
         }
 
         fn interpolate_color(step: i32, from: Rgba, to: Rgba, span: i32) -> Rgba {
-            let (r, g, b) = (
+            let (r, g, b, a) = (
                 interpolate(from.r() as i32, step, to.r() as i32 - from.r() as i32, span), 
                 interpolate(from.g() as i32, step, to.g() as i32 - from.g() as i32, span), 
                 interpolate(from.b() as i32, step, to.b() as i32 - from.b() as i32, span), 
+                interpolate(from.a() as i32, step, to.a() as i32 - from.a() as i32, span), 
             );
 
-            Rgba::from(r as u8, g as u8, b as u8)
+            Rgba::from_rgba(r as u8, g as u8, b as u8, a as u8)
         }
 
-        use core::ptr::copy_nonoverlapping;
-        use core::cmp::min;
-
-        let width = min(self.width - top_left.x, width);
-        let height = min(self.height - top_left.y, height);
-
-        let mut clr = from;
-
-        let base = (top_left.y * self.width) as isize;
-        // our kernel stack is big enough for this whole block of data
+        let r = Rect::new(top_left.x, top_left.y, width, height);
         for i in 0..height {
-            let data = &[clr; 64];
-            let mut off = base + (i * self.width) as isize + top_left.x as isize;
-            let mut w = width;
-            while w >= 64 {
-                unsafe {
-                    copy_nonoverlapping(data,
-                        self.get_mut().offset(off + (width - w) as isize) as *mut _,
-                        1);
-                }
-                w -= 64;
-            }
-            if w > 0 {
-                unsafe {
-                    copy_nonoverlapping(data.as_ptr(),
-                        self.get_mut().offset(off + (width - w) as isize) as *mut Rgba,
-                        w as usize);
-                }
-            }
-
-            clr = interpolate_color(i, from, to, height);
+            let clr = interpolate_color(i, from, to, height);
+            self.blend_span(r.y + i, r.x, r.right(), clr);
         }
+
+        self.invalidate(r);
     }
 
-    // should do sanity check
+    // works on back buffer only, VRAM is never read back
     pub fn blit_copy(&mut self, dst: Point, src: Point, width: i32, height: i32) {
-        let width = min(self.width - src.x, width);
-        let height = min(self.height - src.y, height);
-
-        assert!((src.y + height - 1) < self.height);
-        assert!((dst.y + height - 1) < self.height);
+        // clip destination, then make sure source stays on screen
+        let d = Rect::new(dst.x, dst.y, width, height).intersect(&self.clip);
+        let (sx, sy) = (src.x + d.x - dst.x, src.y + d.y - dst.y);
+        let s = Rect::new(sx, sy, d.width, d.height).intersect(&self.bounds());
+        let d = Rect::new(d.x + s.x - sx, d.y + s.y - sy, s.width, s.height);
+        if d.is_empty() {
+            return;
+        }
 
-        let (dir, mut base, mut dst_base) = match src.y > dst.y {
-            true => (1, src.y * self.width + src.x, dst.y * self.width + dst.x),
-            false => (-1, (src.y + height - 1) * self.width + src.x,
-                (dst.y + height - 1) * self.width + dst.x),
+        let (dir, mut base, mut dst_base) = match s.y > d.y {
+            true => (1, s.y * self.width + s.x, d.y * self.width + d.x),
+            false => (-1, (s.bottom() - 1) * self.width + s.x,
+                (d.bottom() - 1) * self.width + d.x),
         };
 
-        for i in 0..height {
+        for _ in 0..d.height {
             unsafe {
-                copy_nonoverlapping(self.get_mut().offset(base  as isize),
+                copy(self.get_mut().offset(base  as isize),
                     self.get_mut().offset(dst_base as isize),
-                    width as usize);
-                base += self.width * dir;
-                dst_base += self.width * dir;
+                    d.width as usize);
             }
+            base += self.width * dir;
+            dst_base += self.width * dir;
         }
+
+        self.invalidate(d);
     }
 
+    /// plain fill, alpha of rgb is stored as is. see fill_rect_alpha
     pub fn fill_rect(&mut self, top_left: Point, width: i32, height: i32, rgb: Rgba) {
-        let width = min(self.width - top_left.x, width);
-        let height = min(self.height - top_left.y, height);
-
-        let base = (top_left.y * self.width) as isize;
-        // our kernel stack is big enough for this whole block of data
-        let data = &[rgb; 256];
-        {
-            let mut off = base + top_left.x as isize;
-            let mut w = width;
-            while w >= 256 {
-                unsafe {
-                    copy_nonoverlapping(data,
-                        self.get_mut().offset(off + (width - w) as isize) as *mut _,
-                        1);
-                }
-                w -= 256;
-            }
-
-            if w > 0 {
-                unsafe {
-                    copy_nonoverlapping(data.as_ptr(),
-                        self.get_mut().offset(off + (width - w) as isize) as *mut Rgba,
-                        w as usize);
-                }
+        let r = Rect::new(top_left.x, top_left.y, width, height).intersect(&self.clip);
+        for y in r.y..r.bottom() {
+            let off = (y * self.width) as usize;
+            for p in &mut self.back[off + r.x as usize..off + r.right() as usize] {
+                *p = rgb;
             }
         }
 
-        for i in 1..height {
-            let mut off = base + ((i-1) * self.width) as isize + top_left.x as isize;
-            unsafe {
-                copy_nonoverlapping(
-                    self.get_mut().offset(off) as *mut Rgba,
-                    self.get_mut().offset(off + self.width as isize) as *mut Rgba,
-                    width as usize);
-            }
-        }
+        self.invalidate(r);
     }
 
     pub fn draw_char(&mut self, p: Point, c: u8, rgb: Rgba, bg: Rgba) {
-        let base = (p.y * self.width + p.x) as isize;
-
         let glyph = BUILTIN_FONT[c as usize - 1];
         for i in 0..16 {
-            let off = base + (i * self.width) as isize;
             for j in 0..8 {
-                unsafe {
-                    let idx = (i*8+j) as usize;
-                    *self.get_mut().offset(off+j as isize) = match glyph[idx] {
-                        b'*' => rgb,
-                        _ => bg,
-                    };
-                }
+                let clr = match glyph[(i*8+j) as usize] {
+                    b'*' => rgb,
+                    _ => bg,
+                };
+                self.blend_pixel(Point{x: p.x + j, y: p.y + i}, clr);
             }
         }
+
+        self.invalidate(Rect::new(p.x, p.y, 8, 16));
     }
 
     pub fn draw_str(&mut self, p: Point, text: &[u8], rgb: Rgba, bg: Rgba) {
@@ -1,4 +1,7 @@
 pub mod framebuffer;
 pub mod builtin_font;
 pub mod terminal;
-pub use self::framebuffer::{Framebuffer, Point, Rgba};
+pub mod fbdev;
+pub mod splash;
+pub mod cursor;
+pub use self::framebuffer::{Framebuffer, Image, PixelFormat, Point, Rect, Rgba};
@@ -0,0 +1,196 @@
+//! Boot splash, shown when `quiet` is passed on the kernel command line.
+//!
+//! The logo comes from a multiboot module named `logo` if there is one, e.g.
+//! `module2 /logo.img logo` in grub.cfg. The module is a raw image: the magic
+//! `SOSL`, u32 width, u32 height, then width * height 0xAARRGGBB pixels (all
+//! little endian). Without it, a builtin ascii art logo is drawn.
+//!
+//! The splash draws through the console's framebuffer, the console is suspended
+//! meanwhile and only logs to serial. When boot finishes or the kernel panics,
+//! the console is resumed on a cleared screen.
+
+use core::cmp::{min, max};
+use collections::Vec;
+use spin::Mutex;
+use multiboot2;
+
+use ::kern::bootinfo;
+use ::kern::console::tty1;
+use ::kern::arch::cpu;
+use ::kern::memory::KERNEL_MAPPING;
+use super::framebuffer::*;
+
+const LOGO_MAGIC: &'static [u8; 4] = b"SOSL";
+
+const LOGO_ART: [&'static [u8]; 7] = [
+    b" ####   ###    ####   ### ",
+    b"#      #   #  #      #   #",
+    b"#      #   #  #          #",
+    b" ###   #   #   ###     ## ",
+    b"    #  #   #      #   #   ",
+    b"    #  #   #      #  #    ",
+    b"####    ###   ####   #####",
+];
+
+const BACKGROUND: Rgba = Rgba::rgb(0x101820);
+const FOREGROUND: Rgba = Rgba::rgb(0x3fa7d6);
+const BAR_EMPTY: Rgba = Rgba::rgb(0x202a33);
+
+/// boot stages with how far (in percent) the progress bar goes after them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Memory = 30,
+    Interrupts = 60,
+    Tasks = 100
+}
+
+struct Splash {
+    bar: Rect,
+    percent: i32
+}
+
+impl Splash {
+    fn new(fb: &Framebuffer) -> Splash {
+        let (w, h) = (fb.width, fb.height);
+        let bar = Rect::new(w * 3 / 10, h * 2 / 3, w * 4 / 10, 12);
+        Splash { bar: bar, percent: 0 }
+    }
+
+    fn draw_logo(&self, fb: &mut Framebuffer) {
+        let (w, h) = (fb.width, fb.height);
+        let area = Rect::new(0, 0, w, self.bar.y - 20);
+
+        if let Some(img) = module_logo() {
+            let dst = Point{x: (w - img.width) / 2, y: max(0, (area.height - img.height) / 2)};
+            fb.blit_image(dst, &img);
+            return;
+        }
+
+        let (aw, ah) = (LOGO_ART[0].len() as i32, LOGO_ART.len() as i32);
+        let pixels: Vec<Rgba> = LOGO_ART.iter()
+            .flat_map(|row| row.iter())
+            .map(|&b| if b == b'#' { FOREGROUND } else { Rgba(0) })
+            .collect();
+        let img = Image::new(aw, ah, &pixels);
+
+        let scale = max(1, min(w / 2 / aw, h / 4 / ah));
+        let (sw, sh) = (aw * scale, ah * scale);
+        let dst = Rect::new((w - sw) / 2, (area.height - sh) / 2, sw, sh);
+        fb.blit_image_scaled(dst, &img);
+    }
+
+    fn draw(&self, fb: &mut Framebuffer) {
+        let (w, h) = (fb.width, fb.height);
+        fb.fill_rect(Point{x: 0, y: 0}, w, h, BACKGROUND);
+        self.draw_logo(fb);
+
+        let bar = self.bar;
+        fb.draw_rect(Point{x: bar.x - 2, y: bar.y - 2}, bar.width + 4, bar.height + 4, FOREGROUND);
+        fb.fill_rect(Point{x: bar.x, y: bar.y}, bar.width, bar.height, BAR_EMPTY);
+        fb.flush();
+    }
+
+    fn advance(&mut self, fb: &mut Framebuffer, percent: i32) {
+        if percent <= self.percent {
+            return;
+        }
+        self.percent = min(percent, 100);
+
+        let bar = self.bar;
+        let filled = bar.width * self.percent / 100;
+        fb.fill_rect_grad(Point{x: bar.x, y: bar.y}, filled, bar.height,
+            FOREGROUND, Rgba::rgb(0x1d5f80));
+        fb.flush();
+    }
+}
+
+static SPLASH: Mutex<Option<Splash>> = Mutex::new(None);
+
+/// find `logo` module and check it's a sane image
+fn module_logo() -> Option<Image<'static>> {
+    let kernel_base = KERNEL_MAPPING.KernelMap.start;
+    let logo = match bootinfo::mbinfo().module_tags().find(|m| m.name() == "logo") {
+        Some(m) => m,
+        None => return None
+    };
+    let (start, end) = (logo.start_address() as usize + kernel_base, logo.end_address() as usize + kernel_base);
+
+    if end - start < 12 {
+        return None;
+    }
+
+    unsafe {
+        let header = ::core::slice::from_raw_parts(start as *const u8, 4);
+        if header != LOGO_MAGIC {
+            return None;
+        }
+
+        let width = *((start + 4) as *const u32) as usize;
+        let height = *((start + 8) as *const u32) as usize;
+        if width == 0 || height == 0 || (end - start - 12) / 4 < width * height {
+            return None;
+        }
+
+        let pixels = ::core::slice::from_raw_parts((start + 12) as *const Rgba, width * height);
+        Some(Image::new(width as i32, height as i32, pixels))
+    }
+}
+
+fn with_console<F>(f: F) where F: FnOnce(&mut ::kern::console::Console) {
+    let oflags = unsafe { cpu::push_flags() };
+    f(&mut tty1.lock());
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// show splash if asked to, should be called right after the framebuffer console
+/// is created.
+pub fn init(fb: &multiboot2::FramebufferTag) {
+    if !bootinfo::has_flag("quiet") || fb.frame_type != multiboot2::FramebufferType::Rgb {
+        return;
+    }
+
+    // SPLASH is taken before tty1 elsewhere, so don't store it from in here
+    let mut splash = None;
+    with_console(|con| {
+        con.suspend();
+        if let Some(fb) = con.framebuffer() {
+            let s = Splash::new(fb);
+            s.draw(fb);
+            splash = Some(s);
+        }
+    });
+    *SPLASH.lock() = splash;
+}
+
+pub fn progress(stage: Stage) {
+    if let Some(ref mut splash) = *SPLASH.lock() {
+        with_console(|con| {
+            if let Some(fb) = con.framebuffer() {
+                splash.advance(fb, stage as i32);
+            }
+        });
+    }
+}
+
+/// boot done, bring back console. console content is not lost
+pub fn finish() {
+    let splash = SPLASH.lock().take();
+    if splash.is_some() {
+        with_console(|con| con.resume());
+    }
+}
+
+/// called on panic: drop splash without waiting on locks held by the
+/// panicking context, so that the panic message is visible
+pub fn abort() {
+    let active = match SPLASH.try_lock() {
+        Some(mut splash) => splash.take().is_some(),
+        None => true
+    };
+
+    if active {
+        if let Some(mut con) = tty1.try_lock() {
+            con.resume();
+        }
+    }
+}
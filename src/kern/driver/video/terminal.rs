@@ -10,6 +10,8 @@ use spin::Once;
 
 pub struct FramebufferDriver {
     fb: Framebuffer,
+    // nothing is drawn while someone else owns the screen
+    suspended: bool,
     // used cols & rows
     width: usize,
     height: usize,
@@ -21,22 +23,22 @@ pub struct FramebufferDriver {
 
 // map from Console::Color to Rgba
 const COLORMAP: [Rgba; 16] = [
-    Rgba::new(0x000000),
-    Rgba::new(0x0000ff),
-    Rgba::new(0x00ff00),
-    Rgba::new(0x00ffff),
-    Rgba::new(0xff0000),
-    Rgba::new(0xff00ff),
-    Rgba::new(0xa52a2a),
-    Rgba::new(0xd3d3d3),
-    Rgba::new(0xbebebe),
-    Rgba::new(0xadd8e6),
-    Rgba::new(0x90ee90),
-    Rgba::new(0xe0ffff),
-    Rgba::new(0xcd5c5c),
-    Rgba::new(0xee00ee),
-    Rgba::new(0xffff00),
-    Rgba::new(0xffffff),
+    Rgba::rgb(0x000000),
+    Rgba::rgb(0x0000ff),
+    Rgba::rgb(0x00ff00),
+    Rgba::rgb(0x00ffff),
+    Rgba::rgb(0xff0000),
+    Rgba::rgb(0xff00ff),
+    Rgba::rgb(0xa52a2a),
+    Rgba::rgb(0xd3d3d3),
+    Rgba::rgb(0xbebebe),
+    Rgba::rgb(0xadd8e6),
+    Rgba::rgb(0x90ee90),
+    Rgba::rgb(0xe0ffff),
+    Rgba::rgb(0xcd5c5c),
+    Rgba::rgb(0xee00ee),
+    Rgba::rgb(0xffff00),
+    Rgba::rgb(0xffffff),
 ];
 
 impl FramebufferDriver {
@@ -45,6 +47,7 @@ impl FramebufferDriver {
         let h = fb.height / BUILTIN_FONTINFO.yadvance as i32;
         FramebufferDriver {
             fb: fb,
+            suspended: false,
             max_cols: w as usize,
             max_rows: h as usize,
 
@@ -52,6 +55,25 @@ impl FramebufferDriver {
             height: h as usize
         }
     }
+
+    pub fn framebuffer(&mut self) -> &mut Framebuffer {
+        &mut self.fb
+    }
+
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+    }
+
+    /// give the screen back, the caller should clear it
+    pub fn resume(&mut self) {
+        self.suspended = false;
+    }
+
+    pub fn flush(&mut self) {
+        if !self.suspended {
+            self.fb.flush();
+        }
+    }
 }
 
 impl TerminalDriver for FramebufferDriver {
@@ -60,6 +82,9 @@ impl TerminalDriver for FramebufferDriver {
     }
 
     fn draw_byte(&mut self, cursor: usize, byte: Char) {
+        if self.suspended {
+            return;
+        }
         let (ch, fg, bg) = (byte.ascii, byte.attr.fg(), byte.attr.bg());
 
         let p = {
@@ -71,6 +96,7 @@ impl TerminalDriver for FramebufferDriver {
             }
         };
         self.fb.draw_char(p, ch, COLORMAP[fg as usize], COLORMAP[bg as usize]);
+        self.flush();
     }
 
     fn get_max_cols(&self) -> usize {
@@ -94,19 +120,24 @@ impl TerminalDriver for FramebufferDriver {
     fn scroll_up(&mut self, cursor: usize) {
         let (cy, _) = (cursor / self.width, cursor % self.width);
 
-        if cy < self.height - 1 {
+        if self.suspended || cy < self.height - 1 {
             return;
         }
 
         let fh = BUILTIN_FONTINFO.yadvance as i32;
         let (width, height) = (self.fb.width, self.fb.height);
         self.fb.blit_copy(Point{x: 0, y: 0}, Point{x: 0, y: fh}, width, height - fh);
-        self.fb.fill_rect(Point{x: 0, y: height - fh}, width, fh, Rgba(0));
+        self.fb.fill_rect(Point{x: 0, y: height - fh}, width, fh, Rgba::rgb(0));
+        self.flush();
     }
 
     fn clear(&mut self) {
+        if self.suspended {
+            return;
+        }
         let (w, h) = (self.fb.width, self.fb.height);
-        self.fb.fill_rect(Point{x: 0, y: 0}, w, h, Rgba(0));
+        self.fb.fill_rect(Point{x: 0, y: 0}, w, h, Rgba::rgb(0));
+        self.flush();
     }
 }
 
@@ -180,52 +180,471 @@ pub const PF_W: u32 = 1 << 1;
 /// Segment is readable
 pub const PF_R: u32 = 1 << 2;
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct SectionHeader {
+    /// Section name, index into the section header string table
+    pub sh_name: u32,
+    /// Section type
+    pub sh_type: u32,
+    /// Section flags
+    pub sh_flags: u64,
+    /// Section virtual address at execution
+    pub sh_addr: u64,
+    /// Section file offset
+    pub sh_offset: u64,
+    /// Section size in bytes
+    pub sh_size: u64,
+    /// Link to another section
+    pub sh_link: u32,
+    /// Additional section information
+    pub sh_info: u32,
+    /// Section alignment
+    pub sh_addralign: u64,
+    /// Entry size if section holds table
+    pub sh_entsize: u64,
+}
+
+pub const SIZEOF_SHDR: usize = 64;
+
+/// Undefined section index
+pub const SHN_UNDEF: u16 = 0;
+
+/// Section header table entry unused
+pub const SHT_NULL: u32 = 0;
+/// Program data
+pub const SHT_PROGBITS: u32 = 1;
+/// Symbol table
+pub const SHT_SYMTAB: u32 = 2;
+/// String table
+pub const SHT_STRTAB: u32 = 3;
+/// Relocation entries with addends
+pub const SHT_RELA: u32 = 4;
+/// Symbol hash table
+pub const SHT_HASH: u32 = 5;
+/// Dynamic linking information
+pub const SHT_DYNAMIC: u32 = 6;
+/// Notes
+pub const SHT_NOTE: u32 = 7;
+/// Program space with no data (bss)
+pub const SHT_NOBITS: u32 = 8;
+/// Relocation entries, no addends
+pub const SHT_REL: u32 = 9;
+/// Dynamic linker symbol table
+pub const SHT_DYNSYM: u32 = 11;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Sym {
+    /// Symbol name, index into the string table
+    pub st_name: u32,
+    /// Symbol type and binding
+    pub st_info: u8,
+    /// Symbol visibility
+    pub st_other: u8,
+    /// Section index
+    pub st_shndx: u16,
+    /// Symbol value
+    pub st_value: u64,
+    /// Symbol size
+    pub st_size: u64,
+}
+
+pub const SIZEOF_SYM: usize = 24;
+
+/// Symbol type is unspecified
+pub const STT_NOTYPE: u8 = 0;
+/// Symbol is a data object
+pub const STT_OBJECT: u8 = 1;
+/// Symbol is a code object
+pub const STT_FUNC: u8 = 2;
+/// Symbol associated with a section
+pub const STT_SECTION: u8 = 3;
+/// Symbol's name is file name
+pub const STT_FILE: u8 = 4;
+
+/// Local symbol
+pub const STB_LOCAL: u8 = 0;
+/// Global symbol
+pub const STB_GLOBAL: u8 = 1;
+/// Weak symbol
+pub const STB_WEAK: u8 = 2;
+
+impl Sym {
+    pub fn st_type(&self) -> u8 {
+        self.st_info & 0xf
+    }
+
+    pub fn st_bind(&self) -> u8 {
+        self.st_info >> 4
+    }
+
+    /// whether addr falls into [st_value, st_value + st_size)
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.st_value && addr - self.st_value < self.st_size
+    }
+}
+
+/// Data encoding byte index
+pub const EI_DATA: usize = 5;
+/// 2's complement, little endian
+pub const ELFDATA2LSB: u8 = 1;
+/// File version byte index
+pub const EI_VERSION: usize = 6;
+/// Current version
+pub const EV_CURRENT: u8 = 1;
+/// AMD x86-64 architecture
+pub const EM_X86_64: u16 = 62;
+
+/// why bytes are not an ELF image this kernel can use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElfError {
+    /// shorter than the ELF header
+    TooShort,
+    /// no ELFMAG at the start
+    BadMagic,
+    /// not ELFCLASS64
+    BadClass(u8),
+    /// not little endian
+    BadEncoding(u8),
+    BadVersion(u8),
+    /// not for x86_64
+    BadMachine(u16),
+    /// entries of a table have an unexpected size
+    BadEntrySize,
+    /// a table, section or segment lies outside the file or is misaligned
+    OutOfBounds,
+    /// a section index or string offset that doesn't exist
+    BadIndex,
+    /// a loadable segment outside user code space, overlapping another or
+    /// with more bytes in the file than in memory
+    BadSegment,
+}
+
+pub type Result<T> = ::core::result::Result<T, ElfError>;
+
+/// count entries of T at offset of data, bounds and alignment checked
+fn table<'a, T>(data: &'a [u8], offset: usize, count: usize) -> Result<&'a [T]> {
+    use core::mem::{size_of, align_of};
+
+    let end = count.checked_mul(size_of::<T>())
+        .and_then(|len| offset.checked_add(len))
+        .ok_or(ElfError::OutOfBounds)?;
+    if end > data.len() || (data.as_ptr() as usize + offset) % align_of::<T>() != 0 {
+        return Err(ElfError::OutOfBounds);
+    }
+
+    Ok(unsafe { ::core::slice::from_raw_parts(data.as_ptr().offset(offset as isize) as *const T, count) })
+}
+
+/// a string table section
+#[derive(Clone, Copy)]
+pub struct StrTab<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> StrTab<'a> {
+    pub fn new(data: &'a [u8]) -> StrTab<'a> {
+        StrTab { data: data }
+    }
+
+    /// the NUL terminated string at offset
+    pub fn get(&self, offset: usize) -> Option<&'a str> {
+        let rest = match self.data.get(offset..) {
+            Some(rest) => rest,
+            None => return None
+        };
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        ::core::str::from_utf8(&rest[..len]).ok()
+    }
+}
+
+/// a symbol table with its string table, from .symtab or .dynsym
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    syms: &'a [Sym],
+    strtab: StrTab<'a>
+}
+
+impl<'a> SymbolTable<'a> {
+    /// from raw section contents, the kernel gets them from multiboot
+    pub fn from_bytes(syms: &'a [u8], strtab: &'a [u8]) -> Result<SymbolTable<'a>> {
+        if syms.len() % SIZEOF_SYM != 0 {
+            return Err(ElfError::BadEntrySize);
+        }
+
+        Ok(SymbolTable {
+            syms: table(syms, 0, syms.len() / SIZEOF_SYM)?,
+            strtab: StrTab::new(strtab)
+        })
+    }
+
+    pub fn symbols(&self) -> ::core::slice::Iter<'a, Sym> {
+        self.syms.iter()
+    }
+
+    pub fn name(&self, sym: &Sym) -> Option<&'a str> {
+        self.strtab.get(sym.st_name as usize)
+    }
+
+    /// the function addr lies in
+    pub fn lookup(&self, addr: u64) -> Option<&'a Sym> {
+        self.syms.iter().find(|sym| sym.st_type() == STT_FUNC && sym.contains(addr))
+    }
+}
+
+/// an entry of a note section or segment
+#[derive(Debug, Clone, Copy)]
+pub struct Note<'a> {
+    /// owner, without the trailing NUL
+    pub name: &'a [u8],
+    pub n_type: u32,
+    pub desc: &'a [u8]
+}
+
+pub struct NoteIter<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> NoteIter<'a> {
+    pub fn new(data: &'a [u8]) -> NoteIter<'a> {
+        NoteIter { data: data }
+    }
+}
+
+impl<'a> Iterator for NoteIter<'a> {
+    type Item = Note<'a>;
+
+    /// stops at the first truncated note
+    fn next(&mut self) -> Option<Self::Item> {
+        let align4 = |n: usize| (n + 3) & !3;
+        let word = |data: &[u8], i: usize| {
+            (data[i] as u32) | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16 | (data[i + 3] as u32) << 24
+        };
+
+        if self.data.len() < 12 {
+            return None;
+        }
+        let (namesz, descsz) = (word(self.data, 0) as usize, word(self.data, 4) as usize);
+        let n_type = word(self.data, 8);
+
+        let desc_off = 12 + align4(namesz);
+        let next = desc_off + align4(descsz);
+        if desc_off + descsz > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+
+        let name = &self.data[12..12 + namesz];
+        let note = Note {
+            name: match name.last() {
+                Some(&0) => &name[..namesz - 1],
+                _ => name
+            },
+            n_type: n_type,
+            desc: &self.data[desc_off..desc_off + descsz]
+        };
+        self.data = if next < self.data.len() { &self.data[next..] } else { &[] };
+        Some(note)
+    }
+}
+
 pub struct ProgramHeaderIter<'a> {
-    data: &'a [u8],
-    header: &'a Header,
-    next: usize
+    iter: ::core::slice::Iter<'a, ProgramHeader>
+}
+
+impl<'a> Iterator for ProgramHeaderIter<'a> {
+    type Item = &'a ProgramHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+pub struct SectionHeaderIter<'a> {
+    iter: ::core::slice::Iter<'a, SectionHeader>
+}
+
+impl<'a> Iterator for SectionHeaderIter<'a> {
+    type Item = &'a SectionHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct Elf64<'a> {
     pub header: &'a Header,
-    pub data: &'a [u8]
+    pub data: &'a [u8],
+    phdrs: &'a [ProgramHeader],
+    shdrs: &'a [SectionHeader],
 }
 
 impl<'a> Elf64<'a> {
-    pub unsafe fn from(bytes: &'a [u8]) -> Elf64<'a> {
-        let h = &*(bytes.as_ptr() as *const Header);
+    /// check that bytes hold a 64-bit little endian x86_64 ELF image whose
+    /// header tables lie within bytes
+    pub fn parse(bytes: &'a [u8]) -> Result<Elf64<'a>> {
+        if bytes.len() < SIZEOF_EHDR {
+            return Err(ElfError::TooShort);
+        }
+        let header = &table::<Header>(bytes, 0, 1)?[0];
 
-        Elf64 {
-            data: bytes,
-            header: h,
+        let ident = &header.e_ident;
+        if &ident[..SELFMAG] != ELFMAG {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[EI_CLASS] != ELFCLASS64 {
+            return Err(ElfError::BadClass(ident[EI_CLASS]));
+        }
+        if ident[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfError::BadEncoding(ident[EI_DATA]));
+        }
+        if ident[EI_VERSION] != EV_CURRENT {
+            return Err(ElfError::BadVersion(ident[EI_VERSION]));
+        }
+        if header.e_machine != EM_X86_64 {
+            return Err(ElfError::BadMachine(header.e_machine));
+        }
+
+        let (phnum, shnum) = (header.e_phnum as usize, header.e_shnum as usize);
+        if (phnum > 0 && header.e_phentsize as usize != SIZEOF_PHDR) ||
+            (shnum > 0 && header.e_shentsize as usize != SIZEOF_SHDR) {
+            return Err(ElfError::BadEntrySize);
+        }
+        if header.e_shstrndx as usize >= shnum && header.e_shstrndx != SHN_UNDEF {
+            return Err(ElfError::BadIndex);
         }
+
+        Ok(Elf64 {
+            header: header,
+            data: bytes,
+            phdrs: table(bytes, header.e_phoff as usize, phnum)?,
+            shdrs: table(bytes, header.e_shoff as usize, shnum)?,
+        })
     }
 
     pub fn program_headers(&self) -> ProgramHeaderIter<'a> {
-        ProgramHeaderIter {
-            data: self.data,
-            header: self.header,
-            next: 0
+        ProgramHeaderIter { iter: self.phdrs.iter() }
+    }
+
+    pub fn section_headers(&self) -> SectionHeaderIter<'a> {
+        SectionHeaderIter { iter: self.shdrs.iter() }
+    }
+
+    pub fn section(&self, index: usize) -> Result<&'a SectionHeader> {
+        self.shdrs.get(index).ok_or(ElfError::BadIndex)
+    }
+
+    /// file contents of a section, empty for SHT_NOBITS
+    pub fn section_data(&self, sh: &SectionHeader) -> Result<&'a [u8]> {
+        if sh.sh_type == SHT_NOBITS {
+            return Ok(&[][..]);
+        }
+        table(self.data, sh.sh_offset as usize, sh.sh_size as usize)
+    }
+
+    /// file contents of a segment, p_filesz bytes
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8]> {
+        table(self.data, ph.p_offset as usize, ph.p_filesz as usize)
+    }
+
+    pub fn strtab(&self, index: usize) -> Result<StrTab<'a>> {
+        let sh = self.section(index)?;
+        if sh.sh_type != SHT_STRTAB {
+            return Err(ElfError::BadIndex);
+        }
+        Ok(StrTab::new(self.section_data(sh)?))
+    }
+
+    pub fn section_name(&self, sh: &SectionHeader) -> Option<&'a str> {
+        self.strtab(self.header.e_shstrndx as usize).ok()
+            .and_then(|strtab| strtab.get(sh.sh_name as usize))
+    }
+
+    pub fn section_by_name(&self, name: &str) -> Option<&'a SectionHeader> {
+        self.section_headers().find(|sh| self.section_name(sh) == Some(name))
+    }
+
+    /// the first section of type SHT_SYMTAB or SHT_DYNSYM, with the string
+    /// table it links to
+    fn symbol_table(&self, sh_type: u32) -> Result<Option<SymbolTable<'a>>> {
+        let sh = match self.section_headers().find(|sh| sh.sh_type == sh_type) {
+            Some(sh) => sh,
+            None => return Ok(None)
+        };
+        if sh.sh_entsize as usize != SIZEOF_SYM {
+            return Err(ElfError::BadEntrySize);
+        }
+
+        let strtab = self.section(sh.sh_link as usize)?;
+        SymbolTable::from_bytes(self.section_data(sh)?, self.section_data(strtab)?).map(Some)
+    }
+
+    /// .symtab, Ok(None) for stripped files
+    pub fn symbols(&self) -> Result<Option<SymbolTable<'a>>> {
+        self.symbol_table(SHT_SYMTAB)
+    }
+
+    /// .dynsym
+    pub fn dynamic_symbols(&self) -> Result<Option<SymbolTable<'a>>> {
+        self.symbol_table(SHT_DYNSYM)
+    }
+
+    /// notes of every PT_NOTE segment, or of every SHT_NOTE section when
+    /// there are no program headers
+    pub fn notes(&self) -> NoteSections<'a> {
+        NoteSections {
+            elf: *self,
+            index: 0,
+            current: NoteIter::new(&[])
         }
     }
 }
 
-impl<'a> Iterator for ProgramHeaderIter<'a> {
-    type Item = &'a ProgramHeader;
+pub struct NoteSections<'a> {
+    elf: Elf64<'a>,
+    /// next program header, or section header without program headers
+    index: usize,
+    current: NoteIter<'a>
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.next < self.header.e_phnum as usize {
-            let program = unsafe { 
-                &*(self.data.as_ptr().offset(
-                    self.header.e_phoff as isize +
-                    self.header.e_phentsize as isize * self.next as isize)
-                    as *const ProgramHeader)
+impl<'a> NoteSections<'a> {
+    /// contents of the next note segment or section
+    fn next_area(&mut self) -> Option<&'a [u8]> {
+        let (phdrs, shdrs) = (self.elf.phdrs, self.elf.shdrs);
+        let count = if phdrs.is_empty() { shdrs.len() } else { phdrs.len() };
+        while self.index < count {
+            let i = self.index;
+            self.index += 1;
+
+            let data = if !phdrs.is_empty() {
+                if phdrs[i].p_type != PT_NOTE { continue; }
+                self.elf.segment_data(&phdrs[i])
+            } else {
+                if shdrs[i].sh_type != SHT_NOTE { continue; }
+                self.elf.section_data(&shdrs[i])
             };
-            self.next += 1;
-            Some(program)
-        } else {
-            None
+            if let Ok(data) = data {
+                return Some(data);
+            }
         }
+        None
     }
 }
 
+impl<'a> Iterator for NoteSections<'a> {
+    type Item = Note<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(note) = self.current.next() {
+                return Some(note);
+            }
+            match self.next_area() {
+                Some(data) => self.current = NoteIter::new(data),
+                None => return None
+            }
+        }
+    }
+}
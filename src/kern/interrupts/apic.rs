@@ -0,0 +1,477 @@
+//! Local APIC (xAPIC or x2APIC) and IOAPICs, set up from the ACPI MADT.
+//!
+//! ISA irqs keep their vectors (32 + line), IOAPIC redirection entries are
+//! built from the MADT interrupt source overrides. The local APIC timer
+//! replaces the PIT for the timer irq and runs at timer::HZ, calibrated with
+//! PIT channel 2. The 8259 chain is masked once the APIC takes over.
+//!
+//! `noapic` on the command line keeps the 8259.
+
+use core::cmp;
+use core::ptr::{read_volatile, write_volatile};
+use collections::Vec;
+use spin::{Mutex, Once};
+use x86_64::registers::msr;
+
+use ::kern::acpi::{self, Madt};
+use ::kern::arch::cpu;
+use ::kern::bootinfo;
+use ::kern::console::LogLevel::*;
+use ::kern::memory::{map_physical, PAGE_SIZE};
+use ::kern::memory::paging::{self, VirtualAddress};
+use super::idt::ExceptionStackFrame;
+use super::irq::{Irqs, InterruptController, PIC_CHAIN};
+use super::timer;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const CPUID_APIC: u32 = 1 << 9; // edx
+const CPUID_X2APIC: u32 = 1 << 21; // ecx
+
+/// local APIC registers, as xAPIC MMIO offsets. the x2APIC MSR of a
+/// register is 0x800 + offset / 16
+const LAPIC_ID: u32 = 0x20;
+const LAPIC_VERSION: u32 = 0x30;
+const LAPIC_TPR: u32 = 0x80;
+const LAPIC_EOI: u32 = 0xB0;
+const LAPIC_SVR: u32 = 0xF0;
+const LAPIC_ESR: u32 = 0x280;
+const LAPIC_ICR_LOW: u32 = 0x300;
+const LAPIC_ICR_HIGH: u32 = 0x310;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_LVT_LINT0: u32 = 0x350;
+const LAPIC_LVT_LINT1: u32 = 0x360;
+const LAPIC_LVT_ERROR: u32 = 0x370;
+const LAPIC_TIMER_INIT: u32 = 0x380;
+const LAPIC_TIMER_CURRENT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// the x2APIC ICR is a single 64-bit MSR
+const X2APIC_ICR: u32 = 0x830;
+/// divide configuration for divide by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// how long the timer is calibrated against the PIT
+const CALIBRATE_MS: u32 = 10;
+
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 15;
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    XApic(VirtualAddress),
+    X2Apic
+}
+
+/// the register interface is the same on every cpu, each cpu reaches its
+/// own local APIC through it. so one instance serves all of them.
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: Mode,
+    /// timer counts per ms at divide by 16, same on all cpus
+    timer_ticks_per_ms: u32
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        match self.mode {
+            Mode::XApic(base) => read_volatile((base + reg as usize) as *const u32),
+            Mode::X2Apic => msr::rdmsr(0x800 + (reg >> 4)) as u32
+        }
+    }
+
+    unsafe fn write(&self, reg: u32, val: u32) {
+        match self.mode {
+            Mode::XApic(base) => write_volatile((base + reg as usize) as *mut u32, val),
+            Mode::X2Apic => msr::wrmsr(0x800 + (reg >> 4), val as u64)
+        }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        match self.mode {
+            Mode::X2Apic => true,
+            _ => false
+        }
+    }
+
+    /// apic id of the running cpu
+    pub fn id(&self) -> u32 {
+        let id = unsafe { self.read(LAPIC_ID) };
+        match self.mode {
+            Mode::XApic(_) => id >> 24,
+            Mode::X2Apic => id
+        }
+    }
+
+    pub fn eoi(&self) {
+        unsafe { self.write(LAPIC_EOI, 0); }
+    }
+
+    /// software enable and set up LVTs of the running cpu
+    pub unsafe fn init_cpu(&self, madt: &Madt) {
+        let id = self.id();
+        let uid = madt.cpus.iter().find(|c| c.apic_id == id).map(|c| c.processor_id);
+
+        self.write(LAPIC_TPR, 0);
+        self.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_MASKED);
+        self.write(LAPIC_LVT_ERROR, LVT_MASKED);
+
+        // wire up NMI pins, normally LINT1 for all processors
+        let nmis = madt.nmis.iter()
+            .filter(|nmi| nmi.processor_id == u32::max_value() || Some(nmi.processor_id) == uid);
+        for nmi in nmis {
+            let mut lvt = LVT_DELIVERY_NMI;
+            if nmi.flags & 0x3 == 0x3 {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if (nmi.flags >> 2) & 0x3 == 0x3 {
+                lvt |= LVT_LEVEL;
+            }
+            match nmi.lint {
+                0 => self.write(LAPIC_LVT_LINT0, lvt),
+                1 => self.write(LAPIC_LVT_LINT1, lvt),
+                _ => {}
+            }
+        }
+
+        // ESR must be written before reading
+        self.write(LAPIC_ESR, 0);
+        self.write(LAPIC_ESR, 0);
+        self.eoi();
+    }
+
+    /// send an IPI to the cpu with apic id dest, icr holds delivery mode and
+    /// vector
+    pub unsafe fn send_ipi(&self, dest: u32, icr: u32) {
+        match self.mode {
+            Mode::XApic(_) => {
+                self.write(LAPIC_ICR_HIGH, dest << 24);
+                self.write(LAPIC_ICR_LOW, icr);
+                while self.read(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                    ::kern::util::cpu_relax();
+                }
+            },
+            Mode::X2Apic => msr::wrmsr(X2APIC_ICR, ((dest as u64) << 32) | icr as u64)
+        }
+    }
+
+    pub unsafe fn send_init(&self, dest: u32) {
+        self.send_ipi(dest, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// the target starts in real mode at page << 12
+    pub unsafe fn send_startup(&self, dest: u32, page: u8) {
+        self.send_ipi(dest, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+    }
+
+    /// count timer ticks over CALIBRATE_MS of PIT time
+    unsafe fn calibrate_timer(&mut self) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_TIMER_INIT, 0xffffffff);
+
+        timer::pit_wait_ms(CALIBRATE_MS);
+
+        let elapsed = 0xffffffff - self.read(LAPIC_TIMER_CURRENT);
+        self.write(LAPIC_TIMER_INIT, 0);
+        self.timer_ticks_per_ms = elapsed / CALIBRATE_MS;
+    }
+
+    /// periodic timer interrupt on the running cpu
+    pub fn start_timer(&self, hz: u32, vector: u8) {
+        unsafe {
+            self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+            self.write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+            self.write(LAPIC_TIMER_INIT, self.timer_ticks_per_ms * 1000 / hz);
+        }
+    }
+
+    /// single timer interrupt on the running cpu after ns nanoseconds, as
+    /// close as the timer resolution allows
+    pub fn start_oneshot(&self, ns: u64, vector: u8) {
+        let ticks = ::kern::clock::scale(ns, self.timer_ticks_per_ms as u64, 1_000_000);
+        let ticks = cmp::min(cmp::max(ticks, 1), u32::max_value() as u64) as u32;
+        unsafe {
+            self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+            self.write(LAPIC_LVT_TIMER, vector as u32);
+            self.write(LAPIC_TIMER_INIT, ticks);
+        }
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+            self.write(LAPIC_TIMER_INIT, 0);
+        }
+    }
+}
+
+pub struct IoApic {
+    pub id: u8,
+    base: VirtualAddress,
+    gsi_base: u32,
+    /// number of redirection entries
+    entries: u32
+}
+
+impl IoApic {
+    unsafe fn new(info: &acpi::IoApic) -> IoApic {
+        let flags = paging::WRITABLE | paging::DISABLE_CACHE | paging::NO_EXECUTE;
+        let mut ioapic = IoApic {
+            id: info.id,
+            base: map_physical(info.address, PAGE_SIZE, flags),
+            gsi_base: info.gsi_base,
+            entries: 0
+        };
+        ioapic.entries = ((ioapic.read(IOAPIC_VER) >> 16) & 0xff) + 1;
+        for i in 0..ioapic.entries {
+            ioapic.write_entry(i, REDIR_MASKED);
+        }
+        ioapic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+        read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, val: u32) {
+        write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+        write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, val);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    unsafe fn write_entry(&self, index: u32, entry: u64) {
+        // high half (destination) first, the low half may unmask
+        self.write(IOAPIC_REDTBL + index * 2 + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REDTBL + index * 2, entry as u32);
+    }
+
+    unsafe fn set_masked(&self, index: u32, masked: bool) {
+        let low = self.read(IOAPIC_REDTBL + index * 2);
+        let low = if masked { low | REDIR_MASKED as u32 } else { low & !(REDIR_MASKED as u32) };
+        self.write(IOAPIC_REDTBL + index * 2, low);
+    }
+}
+
+/// where an ISA irq ends up
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gsi: u32,
+    /// MPS INTI flags from the override
+    flags: u16
+}
+
+struct IoApics {
+    chips: Vec<IoApic>,
+    isa: [Route; 16]
+}
+
+impl IoApics {
+    fn find(&self, gsi: u32) -> Option<&IoApic> {
+        self.chips.iter().find(|c| c.handles(gsi))
+    }
+
+    /// route irq to its vector on the boot cpu, masked
+    unsafe fn route(&self, irq: Irqs, dest: u32) {
+        let route = self.isa[irq.line()];
+        let ioapic = match self.find(route.gsi) {
+            Some(ioapic) => ioapic,
+            None => {
+                printk!(Warn, "apic: no ioapic for gsi {}\n\r", route.gsi);
+                return;
+            }
+        };
+
+        // ISA default is active high, edge triggered
+        let mut entry = irq as u64 | REDIR_MASKED | ((dest as u64 & 0xff) << 56);
+        if route.flags & 0x3 == 0x3 {
+            entry |= REDIR_ACTIVE_LOW;
+        }
+        if (route.flags >> 2) & 0x3 == 0x3 {
+            entry |= REDIR_LEVEL;
+        }
+        ioapic.write_entry(route.gsi - ioapic.gsi_base, entry);
+    }
+
+    unsafe fn set_masked(&self, irq: Irqs, masked: bool) {
+        let gsi = self.isa[irq.line()].gsi;
+        if let Some(ioapic) = self.find(gsi) {
+            ioapic.set_masked(gsi - ioapic.gsi_base, masked);
+        }
+    }
+}
+
+static LAPIC: Once<LocalApic> = Once::new();
+static IOAPICS: Once<Mutex<IoApics>> = Once::new();
+
+/// the local APIC, None when running on the 8259
+pub fn local() -> Option<&'static LocalApic> {
+    LAPIC.try()
+}
+
+pub struct ApicController;
+
+pub static APIC_CONTROLLER: ApicController = ApicController;
+
+impl InterruptController for ApicController {
+    fn name(&self) -> &'static str {
+        match local() {
+            Some(lapic) if lapic.is_x2apic() => "x2apic",
+            _ => "xapic"
+        }
+    }
+
+    /// the timer irq means the local APIC timer, not the PIT
+    fn enable(&self, irq: Irqs) {
+        if let Irqs::TIMER = irq {
+            local().unwrap().start_timer(timer::HZ, Irqs::TIMER as u8);
+            return;
+        }
+
+        let oflags = unsafe { cpu::push_flags() };
+        unsafe { IOAPICS.try().unwrap().lock().set_masked(irq, false); }
+        unsafe { cpu::pop_flags(oflags); }
+    }
+
+    fn disable(&self, irq: Irqs) {
+        if let Irqs::TIMER = irq {
+            local().unwrap().stop_timer();
+            return;
+        }
+
+        let oflags = unsafe { cpu::push_flags() };
+        unsafe { IOAPICS.try().unwrap().lock().set_masked(irq, true); }
+        unsafe { cpu::pop_flags(oflags); }
+    }
+
+    fn eoi(&self, _irq: Irqs) {
+        local().unwrap().eoi();
+    }
+}
+
+/// spurious interrupts need no EOI
+pub extern "C" fn spurious_irq(_frame: &mut ExceptionStackFrame) {
+}
+
+/// hardware enable the local APIC of the running cpu
+unsafe fn enable(x2apic: bool) {
+    let base = msr::rdmsr(IA32_APIC_BASE);
+    // xAPIC must be enabled before switching to x2APIC
+    msr::wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+    if x2apic {
+        msr::wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    }
+}
+
+fn setup(madt: &Madt, x2apic: bool) {
+    let mode = unsafe {
+        enable(x2apic);
+        if x2apic {
+            Mode::X2Apic
+        } else {
+            let flags = paging::WRITABLE | paging::DISABLE_CACHE | paging::NO_EXECUTE;
+            Mode::XApic(map_physical(madt.local_apic_address, PAGE_SIZE, flags))
+        }
+    };
+
+    let mut lapic = LocalApic { mode: mode, timer_ticks_per_ms: 0 };
+    unsafe {
+        lapic.init_cpu(madt);
+        lapic.calibrate_timer();
+    }
+    printk!(Info, "apic: {} id {} version {:#x}, timer {} ticks/ms\n\r",
+            if x2apic { "x2apic" } else { "xapic" }, lapic.id(),
+            unsafe { lapic.read(LAPIC_VERSION) } & 0xff, lapic.timer_ticks_per_ms);
+    let boot_id = lapic.id();
+    LAPIC.call_once(|| lapic);
+
+    let mut ioapics = IoApics {
+        chips: madt.ioapics.iter().map(|info| unsafe { IoApic::new(info) }).collect(),
+        isa: [Route { gsi: 0, flags: 0 }; 16]
+    };
+    for line in 0..16 {
+        let (gsi, flags) = madt.isa_irq(line as u8);
+        ioapics.isa[line] = Route { gsi: gsi, flags: flags };
+    }
+    for ioapic in &ioapics.chips {
+        printk!(Info, "apic: ioapic {} gsi {}-{}\n\r",
+                ioapic.id, ioapic.gsi_base, ioapic.gsi_base + ioapic.entries - 1);
+    }
+
+    let irqs = [Irqs::KBD, Irqs::IRQ3, Irqs::IRQ4, Irqs::IRQ5, Irqs::IRQ6, Irqs::IRQ7,
+        Irqs::IRQ8, Irqs::IRQ9, Irqs::IRQ10, Irqs::IRQ11, Irqs::MOUSE, Irqs::IRQ13,
+        Irqs::ATA1, Irqs::ATA2];
+    for &irq in irqs.iter() {
+        unsafe { ioapics.route(irq, boot_id); }
+    }
+    IOAPICS.call_once(|| Mutex::new(ioapics));
+}
+
+/// switch to APIC if ACPI found one, true on success. interrupts should be
+/// disabled.
+pub fn init() -> bool {
+    if bootinfo::has_flag("noapic") {
+        return false;
+    }
+
+    let madt = match acpi::get().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) if !madt.ioapics.is_empty() => madt,
+        _ => return false
+    };
+
+    let (_, _, ecx, edx) = cpu::cpuid(1);
+    if edx & CPUID_APIC == 0 {
+        return false;
+    }
+
+    // remap the 8259s away from exception vectors before masking them, in
+    // case one still fires a spurious irq
+    if madt.pcat_compat {
+        unsafe {
+            let mut pic = PIC_CHAIN.lock();
+            pic.init();
+            pic.mask_all();
+        }
+    }
+
+    setup(madt, ecx & CPUID_X2APIC != 0);
+    true
+}
+
+/// set up the local APIC of an application processor the way the boot cpu
+/// set up its own. the timer is started once the cpu takes tasks.
+pub unsafe fn init_ap() {
+    let lapic = local().expect("apic: application processor without local APIC");
+    let madt = acpi::get().and_then(|acpi| acpi.madt.as_ref()).unwrap();
+
+    enable(lapic.is_x2apic());
+    lapic.init_cpu(madt);
+}
@@ -5,6 +5,9 @@ use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
 
 pub type HandlerFunc = extern "C" fn (&mut ExceptionStackFrame);
 pub type HandlerFuncWithErrCode = extern "C" fn (&mut ExceptionStackFrame, u64);
+/// run by the wrappers after the handler, to take signals before
+/// returning to user mode
+pub type ReturnHook = extern "C" fn (&mut Registers, &mut ExceptionStackFrame) -> bool;
 
 #[derive(Debug, Clone, Copy)]
 pub struct EntryOptions(u16);
@@ -74,6 +77,66 @@ pub struct ExceptionStackFrame {
     pub old_ss: u64
 }
 
+/// caller saved registers pushed by the handler wrappers, right below
+/// the ExceptionStackFrame (or below the error code)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct ScratchRegisters {
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64
+}
+
+/// callee saved registers, pushed by the handler wrappers after the
+/// scratch ones
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct PreservedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64
+}
+
+/// every general purpose register of the interrupted context, as the
+/// handler wrappers leave them on the stack
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Registers {
+    pub preserved: PreservedRegisters,
+    pub scratch: ScratchRegisters
+}
+
+impl ExceptionStackFrame {
+    /// whether the interrupted code ran in ring 3
+    pub fn from_user(&self) -> bool {
+        self.cs & 0x3 == 0x3
+    }
+
+    /// registers of the interrupted context. only valid for a frame passed
+    /// into a handler wrapped by define_handler!, or define_handler_with_errno!
+    /// when with_errno is set.
+    pub unsafe fn registers(&self, with_errno: bool) -> &Registers {
+        use core::mem::size_of;
+        let errno = if with_errno { size_of::<u64>() } else { 0 };
+        let addr = self as *const _ as usize - errno - size_of::<Registers>();
+        &*(addr as *const Registers)
+    }
+
+    /// scratch registers of a frame from define_handler!
+    pub unsafe fn scratch_registers(&self) -> &ScratchRegisters {
+        &self.registers(false).scratch
+    }
+}
+
 impl Entry {
     pub fn new(selector: u16, address: u64) -> Entry {
         Entry {
@@ -101,6 +164,10 @@ macro_rules! define_handler {
         extern "C" fn handler_wrapper () -> ! {
             unsafe { 
                 asm!("
+                     test qword ptr [rsp + 8], 3
+                     jz 2f
+                     swapgs
+                2:
                      push rax
                      push rcx
                      push rdx
@@ -110,12 +177,28 @@ macro_rules! define_handler {
                      push r9
                      push r10
                      push r11
+                     push rbx
+                     push rbp
+                     push r12
+                     push r13
+                     push r14
+                     push r15
 
                      mov rdi, rsp
-                     add rdi, 9*8
+                     add rdi, 15*8
 
                      call $0
 
+                     mov rdi, rsp
+                     lea rsi, [rsp + 15*8]
+                     call $1
+
+                     pop r15
+                     pop r14
+                     pop r13
+                     pop r12
+                     pop rbp
+                     pop rbx
                      pop r11
                      pop r10
                      pop r9
@@ -126,9 +209,14 @@ macro_rules! define_handler {
                      pop rcx
                      pop rax
 
+                     test qword ptr [rsp + 8], 3
+                     jz 3f
+                     swapgs
+                3:
                      iretq"
-                     ::"i"($handler as HandlerFunc)
-                     :"rdi"
+                     ::"i"($handler as HandlerFunc),
+                       "i"(::kern::signal::signal_return as ReturnHook)
+                     :"rdi", "rsi"
                      :"intel", "volatile");
                 ::core::intrinsics::unreachable()
             };
@@ -144,6 +232,10 @@ macro_rules! define_handler_with_errno {
         extern "C" fn handler_with_err_wrapper () -> ! {
             unsafe { 
                 asm!("
+                     test qword ptr [rsp + 16], 3
+                     jz 2f
+                     swapgs
+                2:
                      push rax
                      push rcx
                      push rdx
@@ -153,15 +245,30 @@ macro_rules! define_handler_with_errno {
                      push r9
                      push r10
                      push r11
-
-                     mov rsi, [rsp + 9*8]
+                     push rbx
+                     push rbp
+                     push r12
+                     push r13
+                     push r14
+                     push r15
+
+                     mov rsi, [rsp + 15*8]
                      mov rdi, rsp
-                     add rdi, 10*8
+                     add rdi, 16*8
 
                      sub rsp, 8
                      call $0
+                     lea rdi, [rsp + 8]
+                     lea rsi, [rsp + 17*8]
+                     call $1
                      add rsp, 8
 
+                     pop r15
+                     pop r14
+                     pop r13
+                     pop r12
+                     pop rbp
+                     pop rbx
                      pop r11
                      pop r10
                      pop r9
@@ -173,8 +280,13 @@ macro_rules! define_handler_with_errno {
                      pop rax
 
                      add rsp, 8 // remove errno
+                     test qword ptr [rsp + 8], 3
+                     jz 3f
+                     swapgs
+                3:
                      iretq"
-                     ::"i"($handler as HandlerFuncWithErrCode)
+                     ::"i"($handler as HandlerFuncWithErrCode),
+                       "i"(::kern::signal::signal_return as ReturnHook)
                      :"rdi", "rsi"
                      :"intel", "volatile");
                 ::core::intrinsics::unreachable()
@@ -1,5 +1,5 @@
 use ::kern::arch::port::{UnsafePort, Port};
-use spin::Mutex;
+use spin::{Mutex, Once};
 
 /**
  * ref: http://wiki.osdev.org/8259_PIC
@@ -31,6 +31,50 @@ pub enum Irqs {
     ATA2 = 47, // ATA HD2
 }
 
+impl Irqs {
+    /// ISA irq line
+    pub fn line(&self) -> usize {
+        *self as usize - 0x20
+    }
+}
+
+/// where irqs come from: the 8259 chain, or local APIC + IOAPIC.
+/// irqs are named by their legacy ISA line, whatever they are wired to.
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+    fn enable(&self, irq: Irqs);
+    fn disable(&self, irq: Irqs);
+    /// signal end of interrupt, called from irq handlers
+    fn eoi(&self, irq: Irqs);
+}
+
+static CONTROLLER: Once<&'static InterruptController> = Once::new();
+
+/// pick the controller, once during interrupts init
+pub fn set_controller(ctl: &'static InterruptController) {
+    CONTROLLER.call_once(|| ctl);
+}
+
+/// active controller, the 8259 chain until one is set
+pub fn controller() -> &'static InterruptController {
+    match CONTROLLER.try() {
+        Some(ctl) => *ctl,
+        None => &LEGACY_PIC
+    }
+}
+
+pub fn enable(irq: Irqs) {
+    controller().enable(irq)
+}
+
+pub fn disable(irq: Irqs) {
+    controller().disable(irq)
+}
+
+pub fn eoi(irq: Irqs) {
+    controller().eoi(irq)
+}
+
 ///8259A chip
 pub struct Pic8259A {
     offset: u8,
@@ -146,4 +190,39 @@ impl PicChain {
         let mask = self.irqmask & !(1<<irq);
         self.setmask(mask);
     }
+
+    pub unsafe fn disable(&mut self, irq: usize) {
+        assert!(irq >= 0x20 && irq < 0x30);
+        let irq = (irq - 0x20) as u16;
+        let mask = self.irqmask | (1<<irq);
+        self.setmask(mask);
+    }
+
+    /// silence both chips, when the APIC takes over
+    pub unsafe fn mask_all(&mut self) {
+        self.setmask(0xffff);
+    }
+}
+
+/// the 8259 chain as InterruptController
+pub struct LegacyPic;
+
+pub static LEGACY_PIC: LegacyPic = LegacyPic;
+
+impl InterruptController for LegacyPic {
+    fn name(&self) -> &'static str {
+        "8259"
+    }
+
+    fn enable(&self, irq: Irqs) {
+        unsafe { PIC_CHAIN.lock().enable(irq as usize); }
+    }
+
+    fn disable(&self, irq: Irqs) {
+        unsafe { PIC_CHAIN.lock().disable(irq as usize); }
+    }
+
+    fn eoi(&self, irq: Irqs) {
+        unsafe { PIC_CHAIN.lock().eoi(irq.line()); }
+    }
 }
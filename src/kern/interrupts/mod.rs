@@ -1,7 +1,8 @@
 #[macro_use] pub mod idt;
 pub mod irq;
 pub mod timer;
-mod gdt;
+pub mod apic;
+pub mod gdt;
 
 pub use self::idt::*;
 pub use self::irq::{PIC_CHAIN, Irqs};
@@ -9,16 +10,23 @@ pub use self::irq::{PIC_CHAIN, Irqs};
 use self::gdt::{GlobalDescriptorTable, Descriptor};
 use self::timer::{PIT, timer_handler};
 use ::kern::driver::keyboard::{KBD, keyboard_irq};
-use x86_64::structures::tss::TaskStateSegment;
+use ::kern::driver::mouse::{self, mouse_irq};
+use ::kern::driver::serial::serial_irq;
+use ::kern::driver::rtc::rtc_irq;
+use self::apic::spurious_irq;
 use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::instructions::segmentation::*;
 use x86_64::registers::flags;
 
 use ::kern::console::LogLevel::*;
-use ::kern::arch::cpu::cr2;
-use ::kern::memory::MemoryManager;
-use spin::{Once, Mutex};
+use ::kern::arch::cpu::{self, cr2};
+use ::kern::memory::{MemoryManager, USER_SPACE_END};
+use ::kern::task;
+use ::kern::signal;
+use ::kern::ksyms::Symbolize;
+use ::kern::smp::{self, PerCpu};
+use spin::Mutex;
 
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
@@ -30,9 +38,23 @@ lazy_static! {
         idt.double_fault = Entry::new(cs().0, define_handler_with_errno!(double_fault_handler) as u64);
         idt.double_fault.options().set_ist_index(IST_INDEX_DBL_FAULT as u16);
         idt.divide_by_zero = Entry::new(cs().0, define_handler!(divide_by_zero_handler) as u64);
+        idt.invalid_opcode = Entry::new(cs().0, define_handler!(invalid_opcode_handler) as u64);
+        idt.device_not_available =
+            Entry::new(cs().0, define_handler!(device_not_available_handler) as u64);
+        idt.stack_segment_fault =
+            Entry::new(cs().0, define_handler_with_errno!(stack_segment_fault) as u64);
+        idt.alignment_check =
+            Entry::new(cs().0, define_handler_with_errno!(alignment_check_handler) as u64);
+        idt.machine_check = Entry::new(cs().0, define_handler!(machine_check_handler) as u64);
+        idt.simd_floating_point =
+            Entry::new(cs().0, define_handler!(simd_floating_point_handler) as u64);
 
         idt.irqs[Irqs::TIMER as usize-32] = Entry::new(cs().0, define_handler!(timer_handler) as u64);
         idt.irqs[Irqs::KBD as usize-32] = Entry::new(cs().0, define_handler!(keyboard_irq) as u64);
+        idt.irqs[Irqs::MOUSE as usize-32] = Entry::new(cs().0, define_handler!(mouse_irq) as u64);
+        idt.irqs[Irqs::IRQ4 as usize-32] = Entry::new(cs().0, define_handler!(serial_irq) as u64);
+        idt.irqs[Irqs::IRQ8 as usize-32] = Entry::new(cs().0, define_handler!(rtc_irq) as u64);
+        idt.interrupts[apic::SPURIOUS_VECTOR as usize-48] = Entry::new(cs().0, define_handler!(spurious_irq) as u64);
 
         idt
     };
@@ -49,31 +71,109 @@ bitflags! {
     }
 }
 
-extern "C" fn double_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
-    printk!(Debug, "double fault\n\r{:#?}\n\r", frame);
-    loop {
-        unsafe { asm!("hlt"); }
+/// print every register of an interrupted context
+pub fn dump_registers(frame: &ExceptionStackFrame, regs: &Registers) {
+    let (rip, cs, rflags, rsp, ss) = (frame.rip, frame.cs, frame.rflags, frame.old_rsp, frame.old_ss);
+    let (s, p) = (regs.scratch, regs.preserved);
+    let (rax, rbx, rcx, rdx, rsi, rdi, rbp) = (s.rax, p.rbx, s.rcx, s.rdx, s.rsi, s.rdi, p.rbp);
+    let (r8, r9, r10, r11) = (s.r8, s.r9, s.r10, s.r11);
+    let (r12, r13, r14, r15) = (p.r12, p.r13, p.r14, p.r15);
+
+    println_nowait!("RIP: {:04x}:{:016x} RFLAGS: {:016x}", cs, rip, rflags);
+    println_nowait!("RSP: {:04x}:{:016x} RBP: {:016x}", ss, rsp, rbp);
+    println_nowait!("RAX: {:016x} RBX: {:016x} RCX: {:016x}", rax, rbx, rcx);
+    println_nowait!("RDX: {:016x} RSI: {:016x} RDI: {:016x}", rdx, rsi, rdi);
+    println_nowait!("R8:  {:016x} R9:  {:016x} R10: {:016x}", r8, r9, r10);
+    println_nowait!("R11: {:016x} R12: {:016x} R13: {:016x}", r11, r12, r13);
+    println_nowait!("R14: {:016x} R15: {:016x}", r14, r15);
+    println_nowait!("CR0: {:016x} CR2: {:016x} CR3: {:016x}", cpu::cr0(), cpu::cr2(), cpu::cr3());
+}
+
+/// an exception raised by the kernel itself is a bug
+fn kernel_fault(name: &str, frame: &ExceptionStackFrame, regs: &Registers, err_code: Option<u64>) -> ! {
+    match err_code {
+        Some(err) => printk!(Critical, "{} err {:#x} tid {}\n\r", name, err, task::current_id()),
+        None => printk!(Critical, "{} tid {}\n\r", name, task::current_id())
     }
+    dump_registers(frame, regs);
+    panic!("{} in kernel at {:#x} {}", name, frame.rip, Symbolize(frame.rip as usize));
+}
+
+/// an exception raised by user code sends sig to the task, taken on the
+/// way back to user mode. the rest of the system goes on.
+fn fault(name: &str, frame: &ExceptionStackFrame, regs: &Registers, err_code: Option<u64>, sig: usize) {
+    if !frame.from_user() {
+        kernel_fault(name, frame, regs, err_code);
+    }
+
+    printk!(Warn, "{} in task {} ip {:#x} sp {:#x} err {:#x}\n\r",
+            name, task::current_id(), frame.rip, frame.old_rsp, err_code.unwrap_or(0));
+    signal::force(sig);
+}
+
+/// aborts, there is no telling what was interrupted
+extern "C" fn double_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
+    kernel_fault("double fault", frame, unsafe { frame.registers(true) }, Some(err_code));
+}
+
+extern "C" fn machine_check_handler(frame: &mut ExceptionStackFrame) {
+    kernel_fault("machine check", frame, unsafe { frame.registers(false) }, None);
 }
 
 extern "C" fn general_protection_fault(frame: &mut ExceptionStackFrame, err_code: u64) {
-    printk!(Debug, "GPE err code: {:#?}\n\r", err_code);
+    fault("general protection fault", frame, unsafe { frame.registers(true) }, Some(err_code), signal::SIGSEGV);
+}
 
-    loop {
-        unsafe { asm!("hlt"); }
-    }
+extern "C" fn stack_segment_fault(frame: &mut ExceptionStackFrame, err_code: u64) {
+    fault("stack segment fault", frame, unsafe { frame.registers(true) }, Some(err_code), signal::SIGBUS);
 }
 
+extern "C" fn alignment_check_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
+    fault("alignment check", frame, unsafe { frame.registers(true) }, Some(err_code), signal::SIGBUS);
+}
+
+extern "C" fn invalid_opcode_handler(frame: &mut ExceptionStackFrame) {
+    fault("invalid opcode", frame, unsafe { frame.registers(false) }, None, signal::SIGILL);
+}
+
+/// the fpu is never turned off, so this means there is none
+extern "C" fn device_not_available_handler(frame: &mut ExceptionStackFrame) {
+    fault("device not available", frame, unsafe { frame.registers(false) }, None, signal::SIGFPE);
+}
+
+extern "C" fn simd_floating_point_handler(frame: &mut ExceptionStackFrame) {
+    fault("simd floating point exception", frame, unsafe { frame.registers(false) }, None, signal::SIGFPE);
+}
+
+/// not present pages of a task area are mapped on demand. other faults by
+/// user code raise SIGSEGV, faults by the kernel itself panic.
 extern "C" fn page_fault_handler(frame: &mut ExceptionStackFrame, err_code: u64) {
-    use ::kern::task::CURRENT_ID;
-    use core::sync::atomic::Ordering;
+    let err = PageFaultErrorCode::from_bits_truncate(err_code);
+    let addr = cr2();
 
-    let err = PageFaultErrorCode::from_bits(err_code).unwrap();
-    printk!(Debug, "page fault! {:#?}\n\rerr code: {:#?}, cr2: {:#x} tid: {:#x}\n\r",
-            frame, err, cr2(), CURRENT_ID.load(Ordering::SeqCst));
-    loop {
-        unsafe { asm!("hlt"); }
+    if !err.contains(PROTECTION_VIOLATION) && addr < USER_SPACE_END &&
+        task::fault_in(addr, err.contains(CAUSED_BY_WRITE), err.contains(INSTRUCTION_FETCH)) {
+        return;
     }
+
+    if err.contains(USER_MODE) {
+        printk!(Warn, "segfault at {:#x} ip {:#x} err {:?}\n\r",
+                addr, frame.rip, err);
+        signal::force(signal::SIGSEGV);
+        return;
+    }
+
+    // a user address the syscall failed to check, the task pays for it.
+    // user memory is only touched with no locks held, so none leak here
+    if addr < USER_SPACE_END && task::current_id() != 0 &&
+        task::with_current(|task| task.space.is_some()) {
+        printk!(Warn, "kernel fault at user address {:#x} ip {:#x} err {:?}\n\r",
+                addr, frame.rip, err);
+        task::kill_current(signal::SIGSEGV);
+    }
+
+    printk!(Critical, "page fault at {:#x} err {:?}\n\r", addr, err);
+    kernel_fault("page fault", frame, unsafe { frame.registers(true) }, Some(err_code));
 }
 
 extern "C" fn int3_handler(frame: &mut ExceptionStackFrame) {
@@ -81,14 +181,10 @@ extern "C" fn int3_handler(frame: &mut ExceptionStackFrame) {
 }
 
 extern "C" fn divide_by_zero_handler(frame: &mut ExceptionStackFrame) {
-    printk!(Debug, "divide_by_zero!! {:#?}\n\r", frame);
-    loop {}
+    fault("divide error", frame, unsafe { frame.registers(false) }, None, signal::SIGFPE);
 }
 
 const IST_INDEX_DBL_FAULT: usize = 0;
-// single tss
-pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
-static GDT: Once<GlobalDescriptorTable> = Once::new();
 
 pub const KERN_CS_SEL: SegmentSelector = SegmentSelector(1<<3);
 pub const KERN_DS_SEL: SegmentSelector = SegmentSelector(2<<3);
@@ -96,37 +192,38 @@ pub const USER_DS_SEL: SegmentSelector = SegmentSelector((3<<3) | 3);
 pub const USER_CS_SEL: SegmentSelector = SegmentSelector((4<<3) | 3);
 pub const TSS_SEL: SegmentSelector = SegmentSelector(5<<3);
 
-pub fn init(mm: &mut MemoryManager) {
+/// build the GDT and TSS of cpu, with a double fault stack from mm. the boot
+/// cpu does this for every cpu before waking it.
+pub fn init_cpu_tables(cpu: &'static PerCpu, mm: &mut MemoryManager) {
     use x86_64;
-    use x86_64::instructions::tables::load_tss;
-    use x86_64::registers::msr;
 
     {
         let dbl_fault_stack = mm.alloc_stack(1).expect("alloc double_fault stack failed\n\r");
-        printk!(Info, "alloc dbl_fault_stack {:#x}\n\r", dbl_fault_stack.bottom());
+        printk!(Info, "cpu {}: alloc dbl_fault_stack {:#x}\n\r", cpu.id, dbl_fault_stack.bottom());
         unsafe {
-            TSS.interrupt_stack_table[IST_INDEX_DBL_FAULT] = x86_64::VirtualAddress(dbl_fault_stack.top());
+            cpu.tss_mut().interrupt_stack_table[IST_INDEX_DBL_FAULT] = x86_64::VirtualAddress(dbl_fault_stack.top());
         }
     }
 
-    let mut tss_sel = SegmentSelector(0);
-    let mut user_cs_sel = SegmentSelector(0);
-    let gdt = GDT.call_once(|| {
-        let mut gdt = GlobalDescriptorTable::new();
-        gdt.add_entry(Descriptor::kernel_code_segment());
-        gdt.add_entry(Descriptor::kernel_data_segment());
-        gdt.add_entry(Descriptor::user_data_segment());
-        gdt.add_entry(Descriptor::user_code_segment());
-        unsafe {
-            gdt.add_entry(Descriptor::tss_segment(&TSS));
-        }
-        gdt
-    });
+    let gdt = unsafe { cpu.gdt_mut() };
+    *gdt = GlobalDescriptorTable::new();
+    gdt.add_entry(Descriptor::kernel_code_segment());
+    gdt.add_entry(Descriptor::kernel_data_segment());
+    gdt.add_entry(Descriptor::user_data_segment());
+    gdt.add_entry(Descriptor::user_code_segment());
+    gdt.add_entry(Descriptor::tss_segment(cpu.tss()));
+}
 
-    gdt.load();
+/// load the tables of cpu on the running cpu, set up fast syscalls and point
+/// GS at cpu
+pub unsafe fn load_cpu_tables(cpu: &'static PerCpu) {
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::registers::msr;
+
+    cpu.gdt().load();
 
     // setup for fast syscalls (64-bit submode only)
-    unsafe {
+    {
         use bit_field::BitField;
         extern { fn syscall_entry(); }
 
@@ -139,23 +236,42 @@ pub fn init(mm: &mut MemoryManager) {
         ::kern::arch::cpu::enable_sce_bit();
     }
 
-    unsafe {
-        load_ds(KERN_DS_SEL);
-        load_gs(KERN_DS_SEL);
-        set_cs(KERN_CS_SEL);
-        load_tss(TSS_SEL);
-    }
+    load_ds(KERN_DS_SEL);
+    load_gs(KERN_DS_SEL);
+    set_cs(KERN_CS_SEL);
+    load_tss(TSS_SEL);
+
+    // loading gs clears its base, so this comes after
+    smp::install(cpu);
 
     IDT.load();
+}
+
+pub fn init(mm: &mut MemoryManager) {
+    let bsp = smp::bsp();
+    init_cpu_tables(bsp, mm);
+    unsafe { load_cpu_tables(bsp); }
 
     unsafe {
         PIT.lock().init();
         KBD.lock().init();
+        mouse::init();
 
-        PIC_CHAIN.lock().init();
-        PIC_CHAIN.lock().enable(Irqs::IRQ2 as usize);
-        PIC_CHAIN.lock().enable(Irqs::TIMER as usize);
-        PIC_CHAIN.lock().enable(Irqs::KBD as usize);
+        if apic::init() {
+            irq::set_controller(&apic::APIC_CONTROLLER);
+        } else {
+            // IRQ2 (cascade) is unmasked by init
+            PIC_CHAIN.lock().init();
+            irq::set_controller(&irq::LEGACY_PIC);
+        }
+        printk!(Info, "interrupt controller: {}\n\r", irq::controller().name());
+
+        irq::enable(Irqs::TIMER);
+        irq::enable(Irqs::KBD);
+        irq::enable(Irqs::IRQ4);
+        if mouse::present() {
+            irq::enable(Irqs::MOUSE);
+        }
         let mut oflags = ::kern::arch::cpu::push_flags();
         printk!(Debug, "oflags {:#?}\n\r", oflags);
         interrupts::enable();
@@ -1,7 +1,7 @@
 use ::kern::arch::port::Port;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use super::idt::*;
-use super::irq::PIC_CHAIN;
+use super::irq::{self, Irqs};
 use spin::Mutex;
 use ::kern::console::LogLevel::*;
 use ::kern::console::{Console, tty1};
@@ -9,7 +9,7 @@ use ::kern::console::{Console, tty1};
 use ::kern::task::*;
 
 const FREQ: u32 = 1193180;
-const HZ: u32 = 100;
+pub const HZ: u32 = 100;
 
 static TIMER_TICKS: AtomicUsize = AtomicUsize::new(0);
 pub static PIT: Mutex<Timer> = Mutex::new(Timer::new());
@@ -17,6 +17,9 @@ pub static PIT: Mutex<Timer> = Mutex::new(Timer::new());
 // common ports for PIT
 const TIMER_DATA: u16 = 0x40;
 const TIMER_CMD: u16 = 0x43;
+const TIMER_CH2_DATA: u16 = 0x42;
+/// bit 0 gates channel 2, bit 5 is its output
+const TIMER_CH2_GATE: u16 = 0x61;
 
 pub struct Timer {
     ports: [Port<u8>; 2]
@@ -46,13 +49,55 @@ impl Timer {
 
 }
 
+/// busy wait on PIT channel 2 in one-shot mode, needs no interrupts.
+/// the counter is 16 bits, so at most 54ms.
+pub fn pit_wait_ms(ms: u32) {
+    let count = FREQ * ms / 1000;
+    assert!(count > 0 && count <= 0xffff, "pit_wait_ms: {}ms is out of range", ms);
+
+    let mut gate = Port::<u8>::new(TIMER_CH2_GATE);
+    let mut data = Port::<u8>::new(TIMER_CH2_DATA);
+    // gate low and speaker off while loading
+    let old = gate.read() & !0x03;
+    gate.write(old);
+
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    Port::<u8>::new(TIMER_CMD).write(0xB0);
+    data.write(count as u8);
+    data.write((count >> 8) as u8);
+
+    gate.write(old | 0x01);
+    while gate.read() & 0x20 == 0 {
+        ::kern::util::cpu_relax();
+    }
+    gate.write(old);
+}
+
+/// timer interrupts since boot
+pub fn ticks() -> usize {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+/// milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    ::kern::clock::now() / ::kern::clock::NANOS_PER_MS
+}
+
 pub extern "C" fn timer_handler(frame: &mut ExceptionStackFrame) {
     use ::kern::console::tty1;
 
-    unsafe { PIC_CHAIN.lock().eoi(0); }
+    irq::eoi(Irqs::TIMER);
+
+    let irq = ::kern::clock::timer_interrupt();
     //printk!(Critical, "{}\n", TIMER_TICKS.load(Ordering::Acquire));
     
-    let old = TIMER_TICKS.fetch_add(1, Ordering::SeqCst);
+    // every cpu has its own timer, the boot cpu counts ticks
+    if irq.tick && ::kern::smp::this_cpu().is_bsp() {
+        TIMER_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    if !irq.resched {
+        return;
+    }
     //if (old + 1) % HZ as usize == 0 {
         //Console::with(&tty1, 0, 60, || {
             //printk!(Critical, "{}", TIMER_TICKS.load(Ordering::SeqCst));
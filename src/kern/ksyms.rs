@@ -0,0 +1,85 @@
+//! Kernel symbols, to put names on addresses in backtraces.
+//!
+//! The boot loader hands over the section headers of the kernel ELF in the
+//! multiboot ELF sections tag and loads `.symtab` and `.strtab` along with
+//! the rest, though they are not mapped. They get mapped into the physical
+//! direct map at boot and stay there.
+
+use core::fmt;
+use core::slice;
+use collections::Vec;
+use multiboot2::BootInformation;
+use spin::Once;
+
+use ::kern::console::LogLevel::*;
+use ::kern::demangle::Demangle;
+use ::kern::elf64::{SectionHeader, SymbolTable, SHT_NULL, SHT_SYMTAB};
+use ::kern::memory::{map_physical, KERNEL_MAPPING};
+use ::kern::memory::paging;
+
+static KSYMS: Once<SymbolTable<'static>> = Once::new();
+
+/// contents of a section the boot loader put into memory
+fn section_bytes(sh: &SectionHeader) -> &'static [u8] {
+    let kernel_base = KERNEL_MAPPING.KernelMap.start as u64;
+    let paddr = if sh.sh_addr >= kernel_base { sh.sh_addr - kernel_base } else { sh.sh_addr };
+    let vaddr = map_physical(paddr as usize, sh.sh_size as usize, paging::NO_EXECUTE);
+    unsafe { slice::from_raw_parts(vaddr as *const u8, sh.sh_size as usize) }
+}
+
+/// load the kernel symbol table, after memory is up
+pub fn init(mbinfo: &BootInformation) {
+    let elf = match mbinfo.elf_sections_tag() {
+        Some(elf) => elf,
+        None => return
+    };
+
+    // the tag holds plain ELF section headers
+    let headers: Vec<*const SectionHeader> = elf.sections()
+        .map(|sect| sect as *const _ as *const SectionHeader)
+        .collect();
+    let symtab = match headers.iter().map(|&sh| unsafe { &*sh }).find(|sh| sh.sh_type == SHT_SYMTAB) {
+        Some(symtab) if symtab.sh_size > 0 => symtab,
+        _ => {
+            printk!(Warn, "ksyms: kernel has no symbol table\n\r");
+            return;
+        }
+    };
+
+    // sh_link counts from the null section, which the iterator may skip
+    let first = *headers.iter().min().unwrap();
+    let table = if unsafe { (*first).sh_type } == SHT_NULL { first } else { unsafe { first.offset(-1) } };
+    let strtab = unsafe { &*table.offset(symtab.sh_link as isize) };
+
+    match SymbolTable::from_bytes(section_bytes(symtab), section_bytes(strtab)) {
+        Ok(syms) => {
+            printk!(Info, "ksyms: {} symbols\n\r", syms.symbols().len());
+            KSYMS.call_once(|| syms);
+        },
+        Err(e) => printk!(Warn, "ksyms: bad symbol table {:?}\n\r", e)
+    }
+}
+
+/// name of the function addr lies in and the offset into it
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    let syms = match KSYMS.try() {
+        Some(syms) => syms,
+        None => return None
+    };
+
+    syms.lookup(addr as u64).and_then(|sym| {
+        syms.name(sym).map(|name| (name, addr - sym.st_value as usize))
+    })
+}
+
+/// formats an address as `function+offset`, demangled, or `??`
+pub struct Symbolize(pub usize);
+
+impl fmt::Display for Symbolize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", Demangle(name), offset),
+            None => f.write_str("??")
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! Address space of a user task: its page tables and the areas in use.
+//!
+//! Areas are kept by start address and never overlap. An area that is not
+//! mapped up front gets its pages on first access, from the page fault
+//! handler, out of its backing.
+//!
+//! Operations that touch page tables work on the active ones, so they are
+//! meant for the address space of the running task.
+
+use collections::{BTreeMap, Vec};
+use collections::btree_map::Values;
+
+use super::{PAGE_SIZE, USER_MMAP_BASE, page_align};
+use super::paging::{self, EntryFlags, Page, PageRange, VirtualAddress};
+use super::frame::{self, Frame};
+use super::inactive::{InactivePML4Table, TemporaryPage};
+use ::kern::console::LogLevel::*;
+use ::kern::vfs::OpenFile;
+
+/// where the pages of an area come from
+#[derive(Debug, Clone, Copy)]
+pub enum Backing {
+    /// zero-filled
+    Anonymous,
+    /// device memory of file, from offset at the area's start
+    File { file: OpenFile, offset: usize }
+}
+
+impl Backing {
+    /// the backing delta bytes further in
+    fn advance(&self, delta: usize) -> Backing {
+        match *self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { file, offset } => Backing::File { file: file, offset: offset + delta }
+        }
+    }
+
+    /// whether next, starting delta bytes after self, carries on from it
+    fn continued_by(&self, next: &Backing, delta: usize) -> bool {
+        match (*self, *next) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (Backing::File { file: a, offset: off_a }, Backing::File { file: b, offset: off_b }) =>
+                a.dev as *const _ as *const u8 == b.dev as *const _ as *const u8 &&
+                    off_a + delta == off_b,
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualMemoryArea {
+    pub start: VirtualAddress,
+    pub size: usize,
+    /// mapped up front rather than on demand
+    pub mapped: bool,
+    pub flags: EntryFlags,
+    pub backing: Backing,
+    /// MAP_SHARED: writes go to the backing and are seen by other mappings
+    pub shared: bool,
+}
+
+impl VirtualMemoryArea {
+    /// anonymous private area
+    pub fn new(start: VirtualAddress, size: usize, flags: EntryFlags) -> VirtualMemoryArea {
+        assert!(!flags.contains(paging::PRESENT));
+        assert!(start % PAGE_SIZE == 0 && size % PAGE_SIZE == 0 && size > 0,
+                "unaligned area [{:#x}, {:#x})", start, start + size);
+
+        VirtualMemoryArea {
+            start: start,
+            size: size,
+            mapped: false,
+            flags: flags,
+            backing: Backing::Anonymous,
+            shared: false
+        }
+    }
+
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtualAddress) -> bool {
+        addr >= self.start && addr - self.start < self.size
+    }
+
+    pub fn get_pages(&self) -> PageRange {
+        PageRange::new(self.start, self.end())
+    }
+
+    /// whether a faulting access may get a page, PROT_NONE areas have no
+    /// USER flag and permit nothing
+    pub fn permits(&self, write: bool, exec: bool) -> bool {
+        self.flags.contains(paging::USER) &&
+            (!write || self.flags.contains(paging::WRITABLE)) &&
+            (!exec || !self.flags.contains(paging::NO_EXECUTE))
+    }
+
+    /// map every page into inactive, zeroed, with data copied offset bytes
+    /// in. pages are writable while filled and get the area's flags after.
+    pub fn map_with_data(&self, inactive: &mut InactivePML4Table, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.size);
+
+        let mut active = paging::ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        active.with(inactive, &mut temp_page, |mapper| {
+            for page in self.get_pages() {
+                mapper.map(page, paging::WRITABLE | paging::NO_EXECUTE);
+            }
+        });
+
+        // switching pml4 is heavy
+        let cur_pml4 = paging::switch(inactive.clone());
+        unsafe {
+            ::core::ptr::write_bytes(self.start as *mut u8, 0, self.size);
+            ::core::ptr::copy_nonoverlapping(data.as_ptr(), (self.start + offset) as *mut u8, data.len());
+        }
+        let mut active = paging::ActivePML4Table::new();
+        for page in self.get_pages() {
+            active.set_flags(page, self.flags);
+        }
+        paging::switch(cur_pml4);
+    }
+
+    pub fn map(&self, inactive: &mut InactivePML4Table) {
+        let mut active = paging::ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        printk!(Debug, "mapping VirtualMemoryArea {:?} {:?}\n\r", self.get_pages(), self.flags);
+        active.with(inactive, &mut temp_page, |mapper| {
+            for page in self.get_pages() {
+                mapper.map(page, self.flags);
+            }
+        });
+    }
+
+    /// back the page at addr. addr must be in this area and the area's
+    /// address space must be the active one. false if the backing has no
+    /// page there.
+    pub fn fault_in(&self, addr: VirtualAddress) -> bool {
+        let mut active = paging::ActivePML4Table::new();
+        let page = Page::from_vaddress(addr);
+
+        match self.backing.advance(page.start_address() - self.start) {
+            Backing::Anonymous => {
+                // zero through a kernel writable mapping, then hand it over
+                active.map(page, paging::WRITABLE | paging::NO_EXECUTE);
+                unsafe { ::core::ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE); }
+                active.set_flags(page, self.flags);
+                true
+            },
+            Backing::File { file, offset } => {
+                match file.dev.mmap(offset, PAGE_SIZE) {
+                    Ok(paddr) => {
+                        active.map_to(page, Frame::from_paddress(paddr), self.flags);
+                        true
+                    },
+                    Err(_) => false
+                }
+            }
+        }
+    }
+
+    /// drop the pages mapped so far, anonymous frames are freed
+    fn unmap_present(&self) {
+        let mut active = paging::ActivePML4Table::new();
+        for page in self.get_pages() {
+            let paddr = match active.translate(page.start_address()) {
+                Some(paddr) => paddr,
+                None => continue
+            };
+            active.unmap(page);
+            if let Backing::Anonymous = self.backing {
+                frame::dealloc_frame(Frame::from_paddress(paddr));
+            }
+        }
+    }
+
+    /// new flags for the area and the pages mapped so far
+    fn protect(&mut self, flags: EntryFlags) {
+        let mut active = paging::ActivePML4Table::new();
+        for page in self.get_pages() {
+            if active.translate(page.start_address()).is_some() {
+                active.set_flags(page, flags);
+            }
+        }
+        self.flags = flags;
+    }
+
+    /// cut at addr, self keeps the lower part and the upper one is returned
+    fn split_off(&mut self, addr: VirtualAddress) -> VirtualMemoryArea {
+        assert!(addr > self.start && addr < self.end() && addr % PAGE_SIZE == 0);
+
+        let delta = addr - self.start;
+        let upper = VirtualMemoryArea {
+            start: addr,
+            size: self.size - delta,
+            backing: self.backing.advance(delta),
+            ..self.clone()
+        };
+        self.size = delta;
+        upper
+    }
+
+    /// whether next directly follows self and behaves the same
+    fn mergeable(&self, next: &VirtualMemoryArea) -> bool {
+        self.end() == next.start && self.flags == next.flags && self.shared == next.shared &&
+            self.mapped == next.mapped && self.backing.continued_by(&next.backing, self.size)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AddressSpace {
+    table: InactivePML4Table,
+    /// by start address
+    areas: BTreeMap<VirtualAddress, VirtualMemoryArea>,
+    /// the heap is [heap_start, brk), in areas up to the next page boundary
+    heap_start: VirtualAddress,
+    brk: VirtualAddress,
+}
+
+impl AddressSpace {
+    pub fn new(table: InactivePML4Table) -> AddressSpace {
+        AddressSpace {
+            table: table,
+            areas: BTreeMap::new(),
+            heap_start: 0,
+            brk: 0
+        }
+    }
+
+    pub fn table(&self) -> InactivePML4Table {
+        self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut InactivePML4Table {
+        &mut self.table
+    }
+
+    pub fn areas(&self) -> Values<VirtualAddress, VirtualMemoryArea> {
+        self.areas.values()
+    }
+
+    /// the program break, end of the heap
+    pub fn brk(&self) -> VirtualAddress {
+        self.brk
+    }
+
+    /// the heap starts out empty at addr, past the loaded segments
+    pub fn set_heap_start(&mut self, addr: VirtualAddress) {
+        assert!(self.heap_start == self.brk, "heap moved after use");
+        self.heap_start = page_align(addr);
+        self.brk = self.heap_start;
+    }
+
+    /// move the break to brk, whole pages are added to or taken from the
+    /// heap as it crosses page boundaries. new pages are faulted in. false
+    /// if the heap would run into another area or the mmap region.
+    pub fn set_brk(&mut self, brk: VirtualAddress) -> bool {
+        if brk < self.heap_start || brk > USER_MMAP_BASE {
+            return false;
+        }
+
+        let (old_end, new_end) = (page_align(self.brk), page_align(brk));
+        if new_end > old_end {
+            if !self.is_free(old_end, new_end) {
+                return false;
+            }
+            let flags = paging::USER | paging::WRITABLE | paging::NO_EXECUTE;
+            self.insert(VirtualMemoryArea::new(old_end, new_end - old_end, flags));
+            self.merge(old_end);
+        } else if new_end < old_end {
+            self.remove(new_end, old_end);
+        }
+
+        self.brk = brk;
+        true
+    }
+
+    /// the area addr lies in
+    pub fn find(&self, addr: VirtualAddress) -> Option<&VirtualMemoryArea> {
+        match self.areas.range(..addr + 1).next_back() {
+            Some((_, vma)) if vma.contains(addr) => Some(vma),
+            _ => None
+        }
+    }
+
+    /// whether no area overlaps [start, end)
+    pub fn is_free(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        if self.find(start).is_some() {
+            return false;
+        }
+        self.areas.range(start..end).next().is_none()
+    }
+
+    /// whether areas cover [start, end) without holes
+    pub fn covers(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        let mut addr = start;
+        while addr < end {
+            addr = match self.find(addr) {
+                Some(vma) => vma.end(),
+                None => return false
+            };
+        }
+        true
+    }
+
+    /// whether areas cover [start, end) and all of them allow user access,
+    /// for the kernel touching user memory on behalf of a task
+    pub fn permits(&self, start: VirtualAddress, end: VirtualAddress, write: bool) -> bool {
+        let mut addr = start;
+        while addr < end {
+            addr = match self.find(addr) {
+                Some(vma) if vma.permits(write, false) => vma.end(),
+                _ => return false
+            };
+        }
+        true
+    }
+
+    /// lowest gap of len bytes in [from, limit)
+    pub fn find_free(&self, len: usize, from: VirtualAddress, limit: VirtualAddress) -> Option<VirtualAddress> {
+        let mut start = match self.find(from) {
+            Some(vma) => vma.end(),
+            None => from
+        };
+        for vma in self.areas.range(start..).map(|(_, vma)| vma) {
+            match start.checked_add(len) {
+                Some(end) if vma.start >= end => break,
+                Some(_) => start = vma.end(),
+                None => return None
+            }
+        }
+
+        match start.checked_add(len) {
+            Some(end) if end <= limit => Some(start),
+            _ => None
+        }
+    }
+
+    /// add vma, false if it overlaps an existing area
+    pub fn insert(&mut self, vma: VirtualMemoryArea) -> bool {
+        if !self.is_free(vma.start, vma.end()) {
+            return false;
+        }
+        self.areas.insert(vma.start, vma);
+        true
+    }
+
+    /// make addr an area boundary, cutting the area across it in two.
+    /// nothing to do when addr is already a boundary or in no area.
+    pub fn split(&mut self, addr: VirtualAddress) {
+        let start = match self.find(addr) {
+            Some(vma) if vma.start != addr => vma.start,
+            _ => return
+        };
+
+        let upper = self.areas.get_mut(&start).unwrap().split_off(addr);
+        self.areas.insert(addr, upper);
+    }
+
+    /// join the area at addr with its neighbours where they are adjacent
+    /// and alike, undoing splits that are no longer needed
+    pub fn merge(&mut self, addr: VirtualAddress) {
+        let mut start = match self.find(addr) {
+            Some(vma) => vma.start,
+            None => return
+        };
+
+        let prev = self.areas.range(..start).next_back().map(|(&s, _)| s);
+        if let Some(prev) = prev {
+            if self.areas[&prev].mergeable(&self.areas[&start]) {
+                let vma = self.areas.remove(&start).unwrap();
+                self.areas.get_mut(&prev).unwrap().size += vma.size;
+                start = prev;
+            }
+        }
+
+        let next = self.areas[&start].end();
+        let join = match self.areas.get(&next) {
+            Some(vma) => self.areas[&start].mergeable(vma),
+            None => false
+        };
+        if join {
+            let vma = self.areas.remove(&next).unwrap();
+            self.areas.get_mut(&start).unwrap().size += vma.size;
+        }
+    }
+
+    /// start addresses of the areas in [start, end)
+    fn starts(&self, start: VirtualAddress, end: VirtualAddress) -> Vec<VirtualAddress> {
+        self.areas.range(start..end).map(|(&s, _)| s).collect()
+    }
+
+    /// unmap [start, end), areas across the edges are cut. returns the
+    /// areas, or their parts, that were removed.
+    pub fn remove(&mut self, start: VirtualAddress, end: VirtualAddress) -> Vec<VirtualMemoryArea> {
+        self.split(start);
+        self.split(end);
+
+        let mut removed = Vec::new();
+        for s in self.starts(start, end) {
+            let vma = self.areas.remove(&s).unwrap();
+            vma.unmap_present();
+            removed.push(vma);
+        }
+        removed
+    }
+
+    /// unmap every area backed by the device named name, for devices that
+    /// take their memory back on close
+    pub fn remove_device(&mut self, name: &str) {
+        let ranges: Vec<(VirtualAddress, VirtualAddress)> = self.areas.values()
+            .filter(|vma| match vma.backing {
+                Backing::File { file, .. } => file.dev.name() == name,
+                Backing::Anonymous => false
+            })
+            .map(|vma| (vma.start, vma.end()))
+            .collect();
+
+        for (start, end) in ranges {
+            self.remove(start, end);
+        }
+    }
+
+    /// change flags of [start, end), which areas should cover. alike
+    /// neighbours are merged afterwards.
+    pub fn protect(&mut self, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags) {
+        self.split(start);
+        self.split(end);
+
+        let starts = self.starts(start, end);
+        for s in starts.iter() {
+            self.areas.get_mut(s).unwrap().protect(flags);
+        }
+
+        // from the top down, so every merge leaves the lower area in place
+        if self.areas.contains_key(&end) {
+            self.merge(end);
+        }
+        for s in starts.iter().rev() {
+            if self.areas.contains_key(s) {
+                self.merge(*s);
+            }
+        }
+    }
+
+    /// unmap every area, freeing anonymous frames, then free the page
+    /// tables. the space must not be loaded on any cpu.
+    pub fn destroy(mut self) {
+        let mut active = paging::ActivePML4Table::new();
+        let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+        let areas = &self.areas;
+        active.with(&mut self.table, &mut temp_page, |_| {
+            for vma in areas.values() {
+                vma.unmap_present();
+            }
+        });
+        paging::free_tables(self.table);
+    }
+
+    /// resolve a fault at addr, this address space being active. false
+    /// if addr is in no area, the area forbids the access or has no page.
+    pub fn fault_in(&self, addr: VirtualAddress, write: bool, exec: bool) -> bool {
+        match self.find(addr) {
+            Some(vma) if vma.permits(write, exec) => vma.fault_in(addr),
+            _ => false
+        }
+    }
+}
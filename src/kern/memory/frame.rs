@@ -7,6 +7,10 @@ use super::KERNEL_MAPPING;
 use spin::Mutex;
 use super::frame_allocator::BuddyAllocator;
 
+/// frames below 1M are never handed out: the real mode IVT, BIOS data and
+/// the SMP trampoline live there
+const LOW_MEMORY_END: usize = 0x100000;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     pub number: usize,
@@ -141,8 +145,9 @@ impl AreaFrameAllocator {
     //NOTE: I assume areas are already sorted by base addr
     pub fn next_area(&mut self) {
         if let Some(area) = self.areas.next() {
+            let low_end = Frame::from_paddress(LOW_MEMORY_END);
             self.current_area = Some(Range {
-                start: Frame::from_paddress(area.base_addr as usize),
+                start: ::core::cmp::max(Frame::from_paddress(area.base_addr as usize), low_end),
                 end: Frame::from_paddress((area.base_addr + area.length - 1) as usize) + 1,
             });
             self.next_free_frame = self.current_area.as_ref().unwrap().start;
@@ -204,6 +209,26 @@ pub fn dealloc_frame(frame: Frame) {
     }
 }
 
+/// print allocator state, does not wait if the allocator is in use
+pub fn dump_stats() {
+    let guard = match FRAME_ALLOCATOR.try_lock() {
+        Some(guard) => guard,
+        None => {
+            println_nowait!("frame allocator is busy");
+            return;
+        }
+    };
+
+    match *guard {
+        Some(ref proxy) if proxy.initial => {
+            println_nowait!("area frame allocator, next free frame {:#x}",
+                     proxy.allocator.next_free_frame.start_address());
+        },
+        Some(ref proxy) => proxy.alternative.as_ref().unwrap().dump(),
+        None => println_nowait!("frame allocator is not initialized")
+    }
+}
+
 pub fn upgrade_allocator(mbinfo: &'static BootInformation) {
     use ::kern::console as con;
     use con::LogLevel::*;
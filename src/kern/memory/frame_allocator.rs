@@ -27,14 +27,27 @@ impl BuddyAllocator {
         BuddyAllocator { start, size, tree }
     }
 
+    /// print usage and the number of free blocks of each order
     pub fn dump(&self) {
-        for i in 1..self.tree.len() {
-            if i.is_power_of_two() {
-                printk!(Debug, "\n");
-            }
-            printk!(Debug, "{} ", self.tree[i]);
+        let mut free = [0usize; 64];
+        self.count_free(1, self.size, &mut free);
+
+        let total: usize = free.iter().enumerate().map(|(order, &n)| n << order).sum();
+        println!("buddy [{:#x}, {:#x}): {} frames, {} free, largest free block {} frames",
+                 self.start, self.start + self.size * UNIT, self.size, total, self.tree[1]);
+        for (order, &n) in free.iter().enumerate().filter(|&(_, &n)| n > 0) {
+            println!("  order {:>2}: {} free", order, n);
+        }
+    }
+
+    /// collect fully free blocks under node n, which spans size frames
+    fn count_free(&self, n: usize, size: usize, free: &mut [usize; 64]) {
+        if self.tree[n] == size {
+            free[size.trailing_zeros() as usize] += 1;
+        } else if self.tree[n] != 0 && size > 1 {
+            self.count_free(n*2, size / 2, free);
+            self.count_free(n*2+1, size / 2, free);
         }
-        printk!(Debug, "\n");
     }
 
     fn address_of(&self, n: usize) -> Option<usize> {
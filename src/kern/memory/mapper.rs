@@ -104,6 +104,20 @@ impl Mapper {
         self.map_to(page, frame, flags)
     }
 
+    /// change the flags of a mapped 4K page, keeping its frame
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags) {
+        let vaddr = page.start_address() as VirtualAddress;
+
+        let pt = self.next_level_table_mut(vaddr.pml4t_index())
+            .and_then(|p3| p3.next_level_table_mut(vaddr.pdpt_index()))
+            .and_then(|p2| p2.next_level_table_mut(vaddr.pdt_index()))
+            .expect("set_flags: page table missing");
+        let frame = pt[vaddr.pt_index()].pointed_frame().expect("set_flags: page not mapped");
+        pt[vaddr.pt_index()].set(frame, flags | PRESENT);
+
+        ::kern::arch::cpu::tlb_flush(vaddr);
+    }
+
     //TODO: support huge page
     pub fn unmap(&mut self, page: Page) {
         let vaddr = page.start_address() as VirtualAddress;
@@ -4,8 +4,10 @@ pub mod inactive;
 pub mod mapper;
 pub mod stack_allocator;
 pub mod frame_allocator;
+pub mod address_space;
 
 pub use self::stack_allocator::Stack;
+pub use self::paging::map_physical;
 
 use self::paging::*;
 use core::ops::Range;
@@ -20,6 +22,22 @@ use con::LogLevel::*;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// round up to a page boundary
+pub fn page_align(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// round up to a page boundary, None if that runs past the address space
+pub fn checked_page_align(addr: usize) -> Option<usize> {
+    addr.checked_add(PAGE_SIZE - 1).map(|addr| addr / PAGE_SIZE * PAGE_SIZE)
+}
+
+/// end of the user half of the address space, UserStack lies right below
+pub const USER_SPACE_END: usize = 0x8000_00000000;
+
+/// where mmap starts looking for a free gap when no address is given
+pub const USER_MMAP_BASE: usize = 0x2000_00000000;
+
 /// concrete page mapping schema of memory areas, inspired from linux x86_64
 /// ref: https://www.kernel.org/doc/Documentation/x86/x86_64/mm.txt
 /// 0000000000000000 - 00007fffffffffff (=47 bits) user space, different per mm
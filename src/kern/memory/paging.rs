@@ -6,6 +6,8 @@ use super::inactive::{InactivePML4Table, TemporaryPage};
 #[macro_use] use kern::console as con;
 use con::LogLevel::*;
 use multiboot2::*;
+use collections::Vec;
+use spin::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -310,6 +312,41 @@ impl ActivePML4Table {
     }
 }
 
+lazy_static! {
+    /// physical ranges mapped into PhysicalDirectMap after boot, they are
+    /// replayed by create_address_space so that every address space has them
+    static ref PHYS_MAPPINGS: Mutex<Vec<(PhysicalAddress, PhysicalAddress, EntryFlags)>> = Mutex::new(Vec::new());
+}
+
+fn map_physical_range(mapper: &mut Mapper, start: PhysicalAddress, end: PhysicalAddress, flags: EntryFlags) {
+    let base = KERNEL_MAPPING.PhysicalDirectMap.start;
+    for f in FrameRange::new(start, end) {
+        let page = Page::from_vaddress(base + f.start_address());
+        if mapper.translate(page.start_address()).is_none() {
+            mapper.map_to(page, f, flags);
+        }
+    }
+}
+
+/// map physical [paddr, paddr + size) into PhysicalDirectMap, for firmware
+/// tables and device registers. pages already mapped are kept as they are.
+/// returns the virtual address of paddr.
+pub fn map_physical(paddr: PhysicalAddress, size: usize, flags: EntryFlags) -> VirtualAddress {
+    let base = KERNEL_MAPPING.PhysicalDirectMap.start;
+    assert!(size > 0 && paddr + size <= KERNEL_MAPPING.PhysicalDirectMap.end - base,
+            "physical range [{:#x}, {:#x}) can not be direct mapped", paddr, paddr + size);
+
+    let oflags = unsafe { ::kern::arch::cpu::push_flags() };
+    {
+        let mut mappings = PHYS_MAPPINGS.lock();
+        map_physical_range(&mut ActivePML4Table::new(), paddr, paddr + size, flags);
+        mappings.push((paddr, paddr + size, flags));
+    }
+    unsafe { ::kern::arch::cpu::pop_flags(oflags); }
+
+    base + paddr
+}
+
 pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
     let kernel_base = KERNEL_MAPPING.KernelMap.start;
     let mut active = ActivePML4Table::new();
@@ -452,6 +489,10 @@ pub fn create_address_space(mbinfo: &BootInformation) -> InactivePML4Table {
             }
 
         }
+
+        for &(start, end, flags) in PHYS_MAPPINGS.lock().iter() {
+            map_physical_range(mapper, start, end, flags);
+        }
     });
 
     printk!(Info, "create_address_space {:?}\n\r", new_map);
@@ -481,6 +522,42 @@ pub fn switch(new_map: InactivePML4Table) -> InactivePML4Table {
     }
 }
 
+/// free the frames of inactive's page tables, PML4 included. the pages
+/// they map are left alone, and inactive must not be loaded on any cpu.
+pub fn free_tables(mut inactive: InactivePML4Table) {
+    let mut active = ActivePML4Table::new();
+    let mut temp_page = TemporaryPage::new(Page::from_vaddress(0xfffff_cafe_beef_000));
+
+    // collected first, no table may be handed out while it is still walked
+    let mut frames = Vec::new();
+    active.with(&mut inactive, &mut temp_page, |mapper| {
+        let p4 = mapper.get();
+        // 511 is the recursive entry
+        for i in 0..ENTRY_COUNT - 1 {
+            let p3 = match p4.next_level_table(i) {
+                Some(p3) => p3,
+                None => continue
+            };
+            for j in 0..ENTRY_COUNT {
+                if let Some(p2) = p3.next_level_table(j) {
+                    for k in 0..ENTRY_COUNT {
+                        if p2.next_level_table(k).is_some() {
+                            frames.push(p2[k].pointed_frame().unwrap());
+                        }
+                    }
+                    frames.push(p3[j].pointed_frame().unwrap());
+                }
+            }
+            frames.push(p4[i].pointed_frame().unwrap());
+        }
+    });
+    frames.push(inactive.pml4_frame);
+
+    for frame in frames {
+        super::frame::dealloc_frame(frame);
+    }
+}
+
 pub fn test_paging_before_remap() {
     let mut pml4 = ActivePML4Table::new();
     printk!(Debug, "test_paging_before_remap\n\r");
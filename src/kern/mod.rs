@@ -12,7 +12,16 @@ pub mod interrupts;
 pub mod task;
 pub mod syscall;
 pub mod vfs;
+pub mod bootinfo;
+pub mod sysrq;
+pub mod power;
+pub mod acpi;
+pub mod smp;
+pub mod clock;
 pub mod elf64;
+pub mod demangle;
+pub mod ksyms;
+pub mod signal;
 
 
 pub use self::syscall::syscall_dispatch;
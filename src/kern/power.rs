@@ -0,0 +1,149 @@
+//! reboot and poweroff.
+//!
+//! reboot uses the ACPI reset register if there is one, then pulses the reset
+//! line through the keyboard controller and falls back to a triple fault.
+//!
+//! poweroff enters ACPI S5 when the ACPI tables gave us the PM1 control ports,
+//! then tries the ports QEMU, Bochs and VirtualBox listen on. with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04`, QEMU can also be left
+//! with an exit code for automated runs, see also `panic=` in on_panic.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use ::kern::arch::port::Port;
+use ::kern::bootinfo;
+use ::kern::console::LogLevel::*;
+use ::kern::driver::keyboard::KBD;
+
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// SLP_EN bit of PM1 control registers
+const SLP_EN: u16 = 1 << 13;
+
+/// what ACPI tells us about entering S5 (soft off)
+#[derive(Debug, Clone, Copy)]
+pub struct SleepControl {
+    pub pm1a_cnt: u16,
+    /// 0 if there is no PM1b block
+    pub pm1b_cnt: u16,
+    pub slp_typa: u16,
+    pub slp_typb: u16
+}
+
+/// FADT reset register, only the I/O port flavour is supported
+#[derive(Debug, Clone, Copy)]
+pub struct ResetRegister {
+    pub port: u16,
+    pub value: u8
+}
+
+static S5: Mutex<Option<SleepControl>> = Mutex::new(None);
+static RESET: Mutex<Option<ResetRegister>> = Mutex::new(None);
+
+/// called by ACPI once FADT and \_S5 are parsed
+pub fn register_s5(ctl: SleepControl) {
+    printk!(Info, "acpi s5: {:?}\n\r", ctl);
+    *S5.lock() = Some(ctl);
+}
+
+pub fn register_reset(reg: ResetRegister) {
+    printk!(Info, "acpi reset register: {:?}\n\r", reg);
+    *RESET.lock() = Some(reg);
+}
+
+fn spin_wait() {
+    for _ in 0..1000000 {
+        ::kern::util::cpu_relax();
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt":::: "volatile"); }
+    }
+}
+
+/// load an empty IDT, the next exception can not be delivered
+unsafe fn triple_fault() {
+    let null_idt: [u64; 2] = [0, 0];
+    asm!("lidt ($0); int3" :: "r"(&null_idt) : "memory" : "volatile");
+}
+
+pub fn reboot() -> ! {
+    printk!(Critical, "rebooting\n\r");
+    unsafe { interrupts::disable(); }
+
+    let reset = match RESET.try_lock() {
+        Some(reg) => *reg,
+        None => None
+    };
+    if let Some(reg) = reset {
+        Port::<u8>::new(reg.port).write(reg.value);
+        spin_wait();
+    }
+
+    // the lock may be held by whoever we interrupted, don't wait on it
+    if let Some(mut kbd) = KBD.try_lock() {
+        kbd.reset_system();
+    }
+    spin_wait();
+
+    unsafe { triple_fault(); }
+    halt()
+}
+
+fn acpi_poweroff() {
+    let ctl = match S5.try_lock() {
+        Some(s5) => *s5,
+        None => None
+    };
+
+    if let Some(ctl) = ctl {
+        Port::<u16>::new(ctl.pm1a_cnt).write((ctl.slp_typa << 10) | SLP_EN);
+        if ctl.pm1b_cnt != 0 {
+            Port::<u16>::new(ctl.pm1b_cnt).write((ctl.slp_typb << 10) | SLP_EN);
+        }
+        spin_wait();
+    }
+}
+
+pub fn poweroff() -> ! {
+    printk!(Critical, "powering off\n\r");
+    unsafe { interrupts::disable(); }
+
+    acpi_poweroff();
+
+    // QEMU (newer machine types), Bochs and old QEMU, VirtualBox
+    Port::<u16>::new(0x604).write(0x2000);
+    Port::<u16>::new(0xB004).write(0x2000);
+    Port::<u16>::new(0x4004).write(0x3400);
+    spin_wait();
+
+    printk!(Critical, "poweroff failed, halting\n\r");
+    halt()
+}
+
+/// what to do after a panic, from `panic=reboot` or `panic=poweroff` on the
+/// command line. poweroff reports exit code 1 through isa-debug-exit first.
+/// the default is to halt.
+pub fn on_panic() {
+    if !bootinfo::initialized() {
+        return;
+    }
+
+    match bootinfo::option("panic") {
+        Some("reboot") => reboot(),
+        Some("poweroff") => {
+            exit_qemu(1);
+            poweroff()
+        },
+        _ => {}
+    }
+}
+
+/// leave QEMU through isa-debug-exit, QEMU exits with (code << 1) | 1.
+/// returns if the device isn't there.
+pub fn exit_qemu(code: u32) {
+    Port::<u32>::new(ISA_DEBUG_EXIT_PORT).write(code);
+}
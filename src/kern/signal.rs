@@ -0,0 +1,619 @@
+//! POSIX style signals.
+//!
+//! Every user task has a pending and a blocked set and an action for each
+//! signal. Signals are taken on the way back to user mode, at the end of
+//! every syscall and of every interrupt or exception that came from user
+//! mode. A caught signal runs its handler on the user stack, below a
+//! SignalFrame with the interrupted context. The handler returns into the
+//! restorer given with sigaction, which calls sigreturn to pick the context
+//! up again. Fpu state is not saved, just as on task switches.
+//!
+//! Kernel tasks take no signals.
+
+use core::mem::size_of;
+use core::ptr;
+
+use ::kern::arch::cpu;
+use ::kern::console::LogLevel::*;
+use ::kern::interrupts::idt::{ExceptionStackFrame, Registers};
+use ::kern::memory::{KERNEL_MAPPING, USER_SPACE_END};
+use ::kern::task::{self, ProcId, Task, TaskList, TaskState};
+use ::kern::vfs::{Error, Result};
+
+/// signal numbers, as in linux
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGSTKFLT: usize = 16;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGXCPU: usize = 24;
+pub const SIGXFSZ: usize = 25;
+pub const SIGVTALRM: usize = 26;
+pub const SIGPROF: usize = 27;
+pub const SIGWINCH: usize = 28;
+pub const SIGIO: usize = 29;
+pub const SIGPWR: usize = 30;
+pub const SIGSYS: usize = 31;
+
+/// signals are 1 to NSIG - 1
+pub const NSIG: usize = 32;
+
+/// a set of signals, bit sig - 1 for sig
+pub type SigSet = u64;
+
+/// handler values with a meaning of their own
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+/// sigaction flags
+pub const SA_RESTORER: usize = 0x04000000;
+pub const SA_NODEFER: usize = 0x40000000;
+pub const SA_RESETHAND: usize = 0x80000000;
+
+/// sigprocmask how
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// below the user rsp, left alone by signal frames
+const RED_ZONE: usize = 128;
+
+/// rflags bits user code may change through sigreturn:
+/// CF, PF, AF, ZF, SF, TF, DF, OF and AC
+const FLAGS_USER: u64 = 0x40dd5;
+/// IF and the always set bit 1
+const FLAGS_FIXED: u64 = 0x202;
+const FLAGS_TF: u64 = 0x100;
+const FLAGS_DF: u64 = 0x400;
+
+pub fn sigmask(sig: usize) -> SigSet {
+    1 << (sig - 1)
+}
+
+/// signals that can be neither caught, blocked nor ignored
+const UNCATCHABLE: SigSet = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+const STOP_SIGNALS: SigSet =
+    (1 << (SIGSTOP - 1)) | (1 << (SIGTSTP - 1)) | (1 << (SIGTTIN - 1)) | (1 << (SIGTTOU - 1));
+
+/// what a signal does when there is no handler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    /// terminate with a core dump, there are no core dumps yet
+    Core,
+    Stop,
+    Continue,
+    Ignore
+}
+
+pub fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV |
+        SIGXCPU | SIGXFSZ | SIGSYS => DefaultAction::Core,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate
+    }
+}
+
+/// disposition of a signal, as passed to sigaction
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigAction {
+    /// SIG_DFL, SIG_IGN or the handler, called with the signal number and
+    /// a pointer to the saved SigContext
+    pub handler: usize,
+    pub flags: usize,
+    /// where the handler returns to, it has to call sigreturn
+    pub restorer: usize,
+    /// blocked in addition while the handler runs
+    pub mask: SigSet
+}
+
+const SIG_DFL_ACTION: SigAction = SigAction {
+    handler: SIG_DFL,
+    flags: 0,
+    restorer: 0,
+    mask: 0
+};
+
+/// user context interrupted by a signal, in the order of linux' sigcontext
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigContext {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64
+}
+
+impl SigContext {
+    fn save(regs: &Registers, frame: &ExceptionStackFrame) -> SigContext {
+        let (s, p) = (regs.scratch, regs.preserved);
+        SigContext {
+            r8: s.r8,
+            r9: s.r9,
+            r10: s.r10,
+            r11: s.r11,
+            r12: p.r12,
+            r13: p.r13,
+            r14: p.r14,
+            r15: p.r15,
+            rdi: s.rdi,
+            rsi: s.rsi,
+            rbp: p.rbp,
+            rbx: p.rbx,
+            rdx: s.rdx,
+            rax: s.rax,
+            rcx: s.rcx,
+            rsp: frame.old_rsp,
+            rip: frame.rip,
+            rflags: frame.rflags
+        }
+    }
+
+    fn restore(&self, regs: &mut Registers, frame: &mut ExceptionStackFrame) {
+        regs.scratch.r8 = self.r8;
+        regs.scratch.r9 = self.r9;
+        regs.scratch.r10 = self.r10;
+        regs.scratch.r11 = self.r11;
+        regs.preserved.r12 = self.r12;
+        regs.preserved.r13 = self.r13;
+        regs.preserved.r14 = self.r14;
+        regs.preserved.r15 = self.r15;
+        regs.scratch.rdi = self.rdi;
+        regs.scratch.rsi = self.rsi;
+        regs.preserved.rbp = self.rbp;
+        regs.preserved.rbx = self.rbx;
+        regs.scratch.rdx = self.rdx;
+        regs.scratch.rax = self.rax;
+        regs.scratch.rcx = self.rcx;
+        frame.old_rsp = self.rsp;
+        frame.rip = self.rip;
+        frame.rflags = (self.rflags & FLAGS_USER) | FLAGS_FIXED;
+    }
+}
+
+/// pushed onto the user stack for a handler, rsp points at restorer when
+/// the handler starts
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    /// return address of the handler
+    pub restorer: usize,
+    pub signo: usize,
+    pub context: SigContext,
+    /// blocked set to go back to
+    pub mask: SigSet
+}
+
+/// signal state of a task
+#[derive(Debug, Clone)]
+pub struct Signals {
+    pub pending: SigSet,
+    pub blocked: SigSet,
+    pub actions: [SigAction; NSIG],
+    /// blocked set sigsuspend replaced, back once a handler returns
+    saved_mask: Option<SigSet>,
+    /// the context on the user stack is restored on the way back
+    sigreturn: bool
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [SIG_DFL_ACTION; NSIG],
+            saved_mask: None,
+            sigreturn: false
+        }
+    }
+
+    fn ignored(&self, sig: usize) -> bool {
+        match self.actions[sig].handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(sig) == DefaultAction::Ignore,
+            _ => false
+        }
+    }
+
+    /// make sig pending, false if it is ignored and dropped instead
+    fn post(&mut self, sig: usize) -> bool {
+        // a stop cancels a pending continue and the other way round
+        if sig == SIGCONT {
+            self.pending &= !STOP_SIGNALS;
+        } else if sigmask(sig) & STOP_SIGNALS != 0 {
+            self.pending &= !sigmask(SIGCONT);
+        }
+
+        if self.ignored(sig) {
+            return false;
+        }
+        self.pending |= sigmask(sig);
+        true
+    }
+
+    /// pending and not blocked
+    pub fn deliverable(&self) -> SigSet {
+        self.pending & !self.blocked
+    }
+
+    pub fn set_blocked(&mut self, mask: SigSet) {
+        self.blocked = mask & !UNCATCHABLE;
+    }
+
+    /// take the lowest deliverable signal, SIGKILL first
+    fn dequeue(&mut self) -> Option<(usize, SigAction)> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+
+        let sig = if deliverable & sigmask(SIGKILL) != 0 {
+            SIGKILL
+        } else {
+            deliverable.trailing_zeros() as usize + 1
+        };
+        self.pending &= !sigmask(sig);
+        Some((sig, self.actions[sig]))
+    }
+
+    /// mask the handler of sig runs with, returns the one to restore after
+    fn enter_handler(&mut self, sig: usize, action: &SigAction) -> SigSet {
+        let old = self.saved_mask.take().unwrap_or(self.blocked);
+
+        let mut mask = self.blocked | action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            mask |= sigmask(sig);
+        }
+        self.set_blocked(mask);
+
+        if action.flags & SA_RESETHAND != 0 {
+            self.actions[sig] = SIG_DFL_ACTION;
+        }
+        old
+    }
+}
+
+fn valid(sig: usize) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+/// post sig to task, waking it up if it waits
+fn post_to(task: &mut Task, sig: usize) {
+    if task.space.is_none() {
+        return;
+    }
+
+    if sig == SIGKILL || sig == SIGCONT {
+        if let TaskState::Stopped = task.state {
+            task.state = TaskState::Ready;
+        }
+    }
+
+    if task.signals.post(sig) && task.signals.deliverable() != 0 {
+        if let TaskState::Sleep = task.state {
+            task.wake_at = 0;
+        }
+    }
+}
+
+/// send sig to task pid
+pub fn send(pid: ProcId, sig: usize) -> Result<()> {
+    if !valid(sig) {
+        return Err(Error::Invalid);
+    }
+
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = {
+        let tasks = TaskList::get();
+        match tasks.get_task(pid) {
+            Some(task) => {
+                post_to(&mut *task.write(), sig);
+                Ok(())
+            },
+            None => Err(Error::NoProcess)
+        }
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
+
+/// kill(2). signal 0 only checks pid exists. there are no process groups,
+/// so pid has to name a task.
+pub fn kill(pid: ProcId, sig: usize) -> Result<()> {
+    if pid <= 0 || (sig != 0 && !valid(sig)) {
+        return Err(Error::Invalid);
+    }
+
+    let user = {
+        let oflags = unsafe { cpu::push_flags() };
+        let user = TaskList::get().get_task(pid).map(|task| task.read().space.is_some());
+        unsafe { cpu::pop_flags(oflags); }
+        user
+    };
+    match user {
+        None => Err(Error::NoProcess),
+        Some(false) => Err(Error::NotPermitted),
+        Some(true) if sig == 0 => Ok(()),
+        Some(true) => send(pid, sig)
+    }
+}
+
+/// raise sig on the current task for a fault it caused. blocking or ignoring
+/// it would just retry the faulting instruction, so then the default action
+/// applies.
+pub fn force(sig: usize) {
+    task::with_current(|task| {
+        let signals = &mut task.signals;
+        if signals.blocked & sigmask(sig) != 0 || signals.actions[sig].handler == SIG_IGN {
+            signals.actions[sig] = SIG_DFL_ACTION;
+            signals.blocked &= !sigmask(sig);
+        }
+        signals.pending |= sigmask(sig);
+    });
+}
+
+/// sigaction(2) for the current task, returns the old action
+pub fn set_action(sig: usize, action: Option<SigAction>) -> Result<SigAction> {
+    if !valid(sig) {
+        return Err(Error::Invalid);
+    }
+
+    if let Some(ref action) = action {
+        if sigmask(sig) & UNCATCHABLE != 0 {
+            return Err(Error::Invalid);
+        }
+        if action.handler != SIG_DFL && action.handler != SIG_IGN {
+            if action.flags & SA_RESTORER == 0 || action.restorer == 0 {
+                return Err(Error::Invalid);
+            }
+            if action.handler >= KERNEL_MAPPING.UserMap.end ||
+                action.restorer >= KERNEL_MAPPING.UserMap.end {
+                return Err(Error::Fault);
+            }
+        }
+    }
+
+    Ok(task::with_current(|task| {
+        let signals = &mut task.signals;
+        let old = signals.actions[sig];
+        if let Some(action) = action {
+            signals.actions[sig] = action;
+            // pending ones are dropped once ignored
+            if signals.ignored(sig) {
+                signals.pending &= !sigmask(sig);
+            }
+        }
+        old
+    }))
+}
+
+/// sigprocmask(2) for the current task, returns the old blocked set
+pub fn procmask(how: usize, set: Option<SigSet>) -> Result<SigSet> {
+    task::with_current(|task| {
+        let signals = &mut task.signals;
+        let old = signals.blocked;
+        if let Some(set) = set {
+            let mask = match how {
+                SIG_BLOCK => old | set,
+                SIG_UNBLOCK => old & !set,
+                SIG_SETMASK => set,
+                _ => return Err(Error::Invalid)
+            };
+            signals.set_blocked(mask);
+        }
+        Ok(old)
+    })
+}
+
+pub fn pending() -> SigSet {
+    task::with_current(|task| task.signals.pending)
+}
+
+/// sigsuspend(2): wait with mask blocked until a signal is taken
+pub fn suspend(mask: SigSet) {
+    let oflags = unsafe { cpu::push_flags() };
+
+    task::with_current(|task| {
+        let blocked = task.signals.blocked;
+        task.signals.saved_mask = Some(blocked);
+        task.signals.set_blocked(mask);
+    });
+
+    loop {
+        let waiting = task::with_current(|task| {
+            if task.signals.deliverable() != 0 {
+                return false;
+            }
+            task.state = TaskState::Sleep;
+            task.wake_at = u64::max_value();
+            true
+        });
+        if !waiting {
+            break;
+        }
+        unsafe { task::sched(); }
+    }
+
+    task::with_current(|task| task.state = TaskState::Running);
+    unsafe { cpu::pop_flags(oflags); }
+}
+
+/// sigreturn(2), the context is restored on the way back to user mode
+pub fn sigreturn() {
+    task::with_current(|task| task.signals.sigreturn = true);
+}
+
+/// whether the current task has a signal to take, for waits to give up
+pub fn interrupted() -> bool {
+    task::with_current(|task| task.signals.deliverable() != 0)
+}
+
+fn user_area_ok(start: usize, len: usize, write: bool) -> bool {
+    let end = match start.checked_add(len) {
+        Some(end) if start != 0 && end <= USER_SPACE_END => end,
+        _ => return false
+    };
+    task::with_current(|task| {
+        task.space.as_ref().map_or(false, |space| space.permits(start, end, write))
+    })
+}
+
+/// stay off cpus until SIGCONT or SIGKILL comes
+fn stop_current(sig: usize) {
+    let pid = task::with_current(|task| {
+        task.state = TaskState::Stopped;
+        task.pid
+    });
+    printk!(Info, "task {} stopped by signal {}\n\r", pid, sig);
+
+    loop {
+        unsafe { task::sched(); }
+        let stopped = task::with_current(|task| match task.state {
+            TaskState::Stopped => true,
+            _ => false
+        });
+        if !stopped {
+            break;
+        }
+    }
+    task::with_current(|task| task.state = TaskState::Running);
+}
+
+/// pick the interrupted context up from the SignalFrame the handler
+/// returned from
+fn restore_frame(regs: &mut Registers, frame: &mut ExceptionStackFrame) -> bool {
+    // the handler's ret took the restorer off
+    let addr = (frame.old_rsp as usize).wrapping_sub(size_of::<usize>());
+    if !user_area_ok(addr, size_of::<SignalFrame>(), false) {
+        return false;
+    }
+
+    let sf = unsafe { ptr::read(addr as *const SignalFrame) };
+    if sf.context.rip as usize >= KERNEL_MAPPING.UserMap.end {
+        return false;
+    }
+    sf.context.restore(regs, frame);
+    task::with_current(|task| task.signals.set_blocked(sf.mask));
+    true
+}
+
+/// build a SignalFrame on the user stack and point the context at handler
+fn setup_frame(sig: usize, action: &SigAction, mask: SigSet,
+               regs: &mut Registers, frame: &mut ExceptionStackFrame) -> bool {
+    // rsp + 8 is 16 byte aligned when the handler starts
+    let sp = (frame.old_rsp as usize).wrapping_sub(RED_ZONE + size_of::<SignalFrame>()) & !0xf;
+    let sp = sp.wrapping_sub(size_of::<usize>());
+    if !user_area_ok(sp, size_of::<SignalFrame>(), true) {
+        return false;
+    }
+
+    let sf = SignalFrame {
+        restorer: action.restorer,
+        signo: sig,
+        context: SigContext::save(regs, frame),
+        mask: mask
+    };
+    unsafe { ptr::write(sp as *mut SignalFrame, sf); }
+
+    let context = sp + 2 * size_of::<usize>();
+    regs.scratch.rdi = sig as u64;
+    regs.scratch.rsi = context as u64;
+    frame.rip = action.handler as u64;
+    frame.old_rsp = sp as u64;
+    frame.rflags &= !(FLAGS_TF | FLAGS_DF);
+    true
+}
+
+/// on the way back to user mode, from syscall_entry and the interrupt
+/// handler wrappers, with interrupts disabled. finishes a sigreturn, then
+/// takes pending signals until one runs a handler. true when the whole
+/// context was replaced, so rcx and r11 have to be restored too.
+#[no_mangle]
+pub extern "C" fn signal_return(regs: &mut Registers, frame: &mut ExceptionStackFrame) -> bool {
+    if !frame.from_user() {
+        return false;
+    }
+
+    let restored = task::with_current(|task| {
+        let sigreturn = task.signals.sigreturn;
+        task.signals.sigreturn = false;
+        sigreturn
+    });
+    if restored && !restore_frame(regs, frame) {
+        printk!(Warn, "task {}: bad signal frame at {:#x}\n\r", task::current_id(), frame.old_rsp);
+        task::kill_current(SIGSEGV);
+    }
+
+    loop {
+        let (sig, action) = match task::with_current(|task| task.signals.dequeue()) {
+            Some(next) => next,
+            None => break
+        };
+
+        match action.handler {
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Terminate => task::kill_current(sig),
+                DefaultAction::Core => {
+                    printk!(Warn, "task {}: no core dump for signal {}\n\r", task::current_id(), sig);
+                    task::kill_current(sig);
+                },
+                DefaultAction::Stop => stop_current(sig),
+                DefaultAction::Continue | DefaultAction::Ignore => {}
+            },
+            SIG_IGN => {},
+            _ => {
+                let mask = task::with_current(|task| task.signals.enter_handler(sig, &action));
+                if !setup_frame(sig, &action, mask, regs, frame) {
+                    printk!(Warn, "task {}: no room for a signal frame below {:#x}\n\r",
+                            task::current_id(), frame.old_rsp);
+                    task::kill_current(SIGSEGV);
+                }
+                return restored;
+            }
+        }
+    }
+
+    // sigsuspend woke up for a signal that ran no handler
+    task::with_current(|task| {
+        if let Some(mask) = task.signals.saved_mask.take() {
+            task.signals.set_blocked(mask);
+        }
+    });
+    restored
+}
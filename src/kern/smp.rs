@@ -0,0 +1,398 @@
+//! Symmetric multiprocessing: per-cpu data and application processor (AP)
+//! bring-up.
+//!
+//! Every enabled cpu in the MADT gets a PerCpu with its own GDT, TSS and
+//! double fault stack. In the kernel GS points at the PerCpu, and the user
+//! GS base waits in IA32_KERNEL_GS_BASE. syscall_entry and every interrupt
+//! from ring 3 run `swapgs` on the way in and out, so whatever user code
+//! does to GS never reaches the kernel.
+//!
+//! APs are woken with INIT-SIPI-SIPI into trampoline.asm, copied to
+//! TRAMPOLINE_BASE, which takes them to long mode on the kernel page tables
+//! and calls ap_main. they wait there until task::init starts scheduling,
+//! then turn into idle tasks and take tasks from the shared task list.
+//!
+//! `nosmp` on the command line leaves the APs alone.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use collections::Vec;
+use spin::Once;
+use x86_64;
+use x86_64::registers::msr;
+use x86_64::structures::tss::TaskStateSegment;
+
+use ::kern::acpi;
+use ::kern::arch::cpu;
+use ::kern::bootinfo;
+use ::kern::console::LogLevel::*;
+use ::kern::interrupts::{self, apic, timer};
+use ::kern::interrupts::apic::LocalApic;
+use ::kern::interrupts::gdt::GlobalDescriptorTable;
+use ::kern::interrupts::irq::{self, Irqs};
+use ::kern::memory::{map_physical, MM, PAGE_SIZE};
+use ::kern::memory::frame::Frame;
+use ::kern::memory::paging::{self, ActivePML4Table, Page};
+use ::kern::task::{self, ProcId};
+
+pub const MAX_CPUS: usize = 16;
+
+/// where APs start, page aligned and below 1M
+const TRAMPOLINE_BASE: usize = 0x8000;
+/// TrampolineData follows a short jmp at the start of the trampoline
+const TRAMPOLINE_DATA: usize = 8;
+/// boot stack of an AP, it becomes the stack of its idle task
+const AP_STACK_PAGES: usize = 4;
+/// how long an AP may take to show up after the SIPIs
+const AP_BOOT_TIMEOUT_MS: usize = 100;
+
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+extern {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+}
+
+/// filled in by the boot cpu before each SIPI, see trampoline.asm
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64
+}
+
+/// per-cpu area, reached through GS. syscall_entry uses the first two
+/// fields by offset, keep them in place.
+#[repr(C)]
+pub struct PerCpu {
+    /// gs:0, scratch for the user rsp in syscall_entry
+    user_rsp: AtomicUsize,
+    /// gs:8, kernel stack of the task running here
+    kern_rsp: AtomicUsize,
+    /// gs:16, address of this PerCpu
+    this: usize,
+    pub id: usize,
+    apic_id: AtomicUsize,
+    /// task running here, 0 before tasking starts
+    current: AtomicIsize,
+    idle: AtomicIsize,
+    /// task just switched away from, see task::finish_switch
+    prev: AtomicIsize,
+    /// when the next scheduling tick is due, in clock::now() time
+    next_tick: AtomicUsize,
+    online: AtomicBool,
+    tss: UnsafeCell<TaskStateSegment>,
+    gdt: UnsafeCell<GlobalDescriptorTable>
+}
+
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// PerCpus live as long as the kernel, GDT and TSS point into them
+    fn new(id: usize, apic_id: u32) -> &'static PerCpu {
+        let cpu = Box::into_raw(Box::new(PerCpu {
+            user_rsp: AtomicUsize::new(0),
+            kern_rsp: AtomicUsize::new(0),
+            this: 0,
+            id: id,
+            apic_id: AtomicUsize::new(apic_id as usize),
+            current: AtomicIsize::new(0),
+            idle: AtomicIsize::new(0),
+            prev: AtomicIsize::new(0),
+            next_tick: AtomicUsize::new(0),
+            online: AtomicBool::new(id == 0),
+            tss: UnsafeCell::new(TaskStateSegment::new()),
+            gdt: UnsafeCell::new(GlobalDescriptorTable::new())
+        }));
+
+        unsafe {
+            (*cpu).this = cpu as usize;
+            &*cpu
+        }
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed) as u32
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn current(&self) -> ProcId {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn set_current(&self, pid: ProcId) {
+        self.current.store(pid, Ordering::SeqCst);
+    }
+
+    pub fn idle(&self) -> ProcId {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    pub fn set_idle(&self, pid: ProcId) {
+        self.idle.store(pid, Ordering::Relaxed);
+    }
+
+    pub fn set_prev(&self, pid: ProcId) {
+        self.prev.store(pid, Ordering::Relaxed);
+    }
+
+    pub fn take_prev(&self) -> ProcId {
+        self.prev.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn next_tick(&self) -> u64 {
+        self.next_tick.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn set_next_tick(&self, nanos: u64) {
+        self.next_tick.store(nanos as usize, Ordering::Relaxed);
+    }
+
+    /// stack for syscalls and interrupts from user mode, of the task about to
+    /// run here
+    pub unsafe fn set_kernel_stack(&self, rsp: usize) {
+        self.kern_rsp.store(rsp, Ordering::Relaxed);
+        self.tss_mut().privilege_stack_table[0] = x86_64::VirtualAddress(rsp);
+    }
+
+    pub fn tss(&'static self) -> &'static TaskStateSegment {
+        unsafe { &*self.tss.get() }
+    }
+
+    /// only the owning cpu, or the boot cpu before waking it, may do this
+    pub unsafe fn tss_mut(&self) -> &mut TaskStateSegment {
+        &mut *self.tss.get()
+    }
+
+    pub fn gdt(&'static self) -> &'static GlobalDescriptorTable {
+        unsafe { &*self.gdt.get() }
+    }
+
+    pub unsafe fn gdt_mut(&self) -> &mut GlobalDescriptorTable {
+        &mut *self.gdt.get()
+    }
+}
+
+static CPUS: Once<Vec<&'static PerCpu>> = Once::new();
+/// set once GS of the boot cpu points at its PerCpu
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static SCHEDULING: AtomicBool = AtomicBool::new(false);
+
+/// set up a PerCpu for every enabled cpu in the MADT, the boot cpu is cpu 0.
+/// runs after ACPI and before interrupts.
+pub fn init() {
+    CPUS.call_once(|| {
+        // initial APIC id, the local APIC is not up yet
+        let (_, ebx, _, _) = cpu::cpuid(1);
+        let boot_id = ebx >> 24;
+
+        let mut cpus = Vec::new();
+        cpus.push(PerCpu::new(0, boot_id));
+
+        if let Some(madt) = acpi::get().and_then(|acpi| acpi.madt.as_ref()) {
+            for lapic in madt.cpus.iter().filter(|c| c.enabled && c.apic_id != boot_id) {
+                if cpus.len() == MAX_CPUS {
+                    printk!(Warn, "smp: only {} cpus are supported\n\r", MAX_CPUS);
+                    break;
+                }
+                let id = cpus.len();
+                cpus.push(PerCpu::new(id, lapic.apic_id));
+            }
+        }
+
+        printk!(Info, "smp: {} cpu(s)\n\r", cpus.len());
+        cpus
+    });
+}
+
+/// all known cpus, online or not
+pub fn cpus() -> &'static [&'static PerCpu] {
+    match CPUS.try() {
+        Some(cpus) => &cpus[..],
+        None => &[]
+    }
+}
+
+pub fn bsp() -> &'static PerCpu {
+    cpus().first().map(|&cpu| cpu).expect("smp: not initialized")
+}
+
+/// point GS of the running cpu at cpu, user mode starts with a zero base
+pub unsafe fn install(cpu: &'static PerCpu) {
+    let base = cpu as *const PerCpu as u64;
+    msr::wrmsr(msr::IA32_GS_BASE, base);
+    msr::wrmsr(IA32_KERNEL_GS_BASE, 0);
+
+    if cpu.is_bsp() {
+        INSTALLED.store(true, Ordering::Release);
+    }
+}
+
+/// PerCpu of the running cpu, None before interrupts::init. with interrupts
+/// enabled, the caller may be moved to another cpu right after.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let this: usize;
+    unsafe { asm!("movq %gs:16, $0" : "=r"(this) ::: "volatile"); }
+    Some(unsafe { &*(this as *const PerCpu) })
+}
+
+pub fn this_cpu() -> &'static PerCpu {
+    try_this_cpu().expect("smp: per-cpu area is not set up")
+}
+
+/// whether pid is the idle task of some cpu, those stay where they are
+pub fn is_idle(pid: ProcId) -> bool {
+    cpus().iter().any(|cpu| cpu.idle() == pid)
+}
+
+/// let the APs take tasks, task::init calls this once the task list is ready
+pub fn start_scheduling() {
+    SCHEDULING.store(true, Ordering::Release);
+}
+
+/// INIT, then up to two SIPIs, as the MP spec asks
+unsafe fn start_ap(lapic: &LocalApic, cpu: &'static PerCpu) -> bool {
+    let dest = cpu.apic_id();
+
+    lapic.send_init(dest);
+    timer::pit_wait_ms(10);
+
+    for _ in 0..2 {
+        if cpu.is_online() {
+            break;
+        }
+        lapic.send_startup(dest, (TRAMPOLINE_BASE >> 12) as u8);
+        timer::pit_wait_ms(1);
+    }
+
+    for _ in 0..AP_BOOT_TIMEOUT_MS {
+        if cpu.is_online() {
+            return true;
+        }
+        timer::pit_wait_ms(1);
+    }
+    false
+}
+
+/// wake the APs one at a time, they share the trampoline. needs the local
+/// APIC, so runs after interrupts::init.
+pub fn boot_aps() {
+    let cpus = cpus();
+    if cpus.len() < 2 {
+        return;
+    }
+
+    if bootinfo::has_flag("nosmp") {
+        printk!(Info, "smp: disabled by nosmp\n\r");
+        return;
+    }
+
+    let lapic = match apic::local() {
+        Some(lapic) => lapic,
+        None => {
+            printk!(Warn, "smp: no local APIC, running on the boot cpu only\n\r");
+            return;
+        }
+    };
+    bsp().apic_id.store(lapic.id() as usize, Ordering::Relaxed);
+
+    let cr3 = cpu::cr3();
+    assert!(cr3 < 0x1_0000_0000, "smp: kernel page tables at {:#x} are out of reach of the trampoline", cr3);
+
+    let trampoline = map_physical(TRAMPOLINE_BASE, PAGE_SIZE, paging::WRITABLE | paging::NO_EXECUTE);
+    unsafe {
+        let start = &trampoline_start as *const u8;
+        let len = &trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= PAGE_SIZE, "smp: trampoline is too big");
+        ptr::copy_nonoverlapping(start, trampoline as *mut u8, len);
+    }
+
+    // the AP enables paging while running at TRAMPOLINE_BASE
+    let identity = {
+        let oflags = unsafe { cpu::push_flags() };
+        let mut active = ActivePML4Table::new();
+        let mapped = active.translate(TRAMPOLINE_BASE).is_some();
+        if !mapped {
+            active.identity_map(Frame::from_paddress(TRAMPOLINE_BASE), paging::WRITABLE);
+        }
+        unsafe { cpu::pop_flags(oflags); }
+        !mapped
+    };
+
+    // an AP that timed out may still be running the trampoline later on, so
+    // its data must not change and its mapping must stay
+    let mut stuck = false;
+    for &ap in cpus[1..].iter() {
+        let stack = {
+            let oflags = unsafe { cpu::push_flags() };
+            let stack = {
+                let mut mm = MM.try().unwrap().lock();
+                interrupts::init_cpu_tables(ap, &mut mm);
+                mm.alloc_stack(AP_STACK_PAGES).expect("smp: alloc AP stack failed")
+            };
+            unsafe { cpu::pop_flags(oflags); }
+            stack
+        };
+
+        unsafe {
+            let data = (trampoline + TRAMPOLINE_DATA) as *mut TrampolineData;
+            ptr::write_volatile(data, TrampolineData {
+                cr3: cr3 as u64,
+                stack: stack.top() as u64,
+                entry: ap_main as u64,
+                cpu: ap as *const PerCpu as u64
+            });
+
+            if !start_ap(lapic, ap) {
+                printk!(Warn, "smp: cpu {} (apic {}) did not come up, not waking the rest\n\r",
+                    ap.id, ap.apic_id());
+                stuck = true;
+                break;
+            }
+        }
+    }
+
+    if identity && !stuck {
+        let oflags = unsafe { cpu::push_flags() };
+        ActivePML4Table::new().unmap(Page::from_vaddress(TRAMPOLINE_BASE));
+        unsafe { cpu::pop_flags(oflags); }
+    }
+
+    let online = cpus.iter().filter(|cpu| cpu.is_online()).count();
+    printk!(Info, "smp: {} of {} cpus online\n\r", online, cpus.len());
+}
+
+/// first Rust code on an AP, on its boot stack with interrupts disabled
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    unsafe {
+        interrupts::load_cpu_tables(cpu);
+        apic::init_ap();
+    }
+
+    cpu.online.store(true, Ordering::Release);
+    printk!(Info, "smp: cpu {} (apic {}) online\n\r", cpu.id, cpu.apic_id());
+
+    while !SCHEDULING.load(Ordering::Acquire) {
+        ::kern::util::cpu_relax();
+    }
+
+    task::adopt_idle(cpu);
+    irq::enable(Irqs::TIMER);
+    loop {
+        task::idle();
+    }
+}
@@ -2,8 +2,17 @@ use ::kern::console::LogLevel::*;
 use ::kern::task;
 use ::kern::arch::cpu;
 use ::kern::console::{Console, tty1};
+use ::kern::vfs::{self, Error, Result};
+use ::kern::memory::{PAGE_SIZE, KERNEL_MAPPING, USER_MMAP_BASE, USER_SPACE_END, checked_page_align};
+use ::kern::memory::paging;
+use ::kern::memory::address_space::{Backing, VirtualMemoryArea};
+use ::kern::driver::keymap;
+use ::kern::power;
+use collections::string::String;
+use core::mem::size_of;
+use ::kern::clock;
+use ::kern::signal::{self, SigAction, SigSet, SA_RESTORER};
 
-use core::sync::atomic::Ordering;
 use x86_64::instructions::interrupts;
 
 #[derive(Debug, Clone, Copy)]
@@ -50,33 +59,76 @@ pub enum Syscall {
     WAITPID       =  38,
     FCHDIR        =  39,
     GETCWD        =  40,
+    IOCTL         =  41,
+    SETKEYMAP     =  42,
+    REBOOT        =  43,
+    POWEROFF      =  44,
+    CLOCK_GETTIME =  45,
+    GETTIMEOFDAY  =  46,
+    MUNMAP        =  47,
+    MPROTECT      =  48,
 
-    NR_SYSCALL    =  41
+    NR_SYSCALL    =  49
 }
 
+/// mmap prot
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+/// mmap flags
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// return value goes back to userspace in rax, negative errno when failed
 #[no_mangle]
-pub unsafe extern "C" fn syscall_dispatch(id: usize, args: *const usize) 
+pub unsafe extern "C" fn syscall_dispatch(id: usize, args: *const usize) -> isize
 {
     let args = ::core::slice::from_raw_parts(args, 6);
-    let tid = task::CURRENT_ID.load(Ordering::SeqCst);
+    let tid = task::current_id();
     Console::with(&tty1, 19, 0, || {
         printk!(Info, "syscall({}) tid {}: {:#x} {:#x} {:#x} {:#x} {:#x} {:#x}\n\r", id, tid, 
                 args[0], args[1], args[2], args[3], args[4], args[5]);
     });
 
-    if id == Syscall::NONE as usize || id > Syscall::NR_SYSCALL as usize {
-        panic!("invalid syscall id {}", id);
+    if id == Syscall::NONE as usize || id >= Syscall::NR_SYSCALL as usize {
+        return Error::NotSupported.errno();
     }
 
     let nr: Syscall = ::core::intrinsics::transmute(id);
-    match nr {
-        Syscall::WRITE => {
-            let buf = ::core::slice::from_raw_parts(args[1] as *const u8, args[2]);
-            sys_write(args[0] as isize, buf);
-        },
-        _ => {
-            unimplemented!()
-        }
+    let ret = match nr {
+        Syscall::OPEN => sys_open(args[0], args[1], args[2]),
+        Syscall::CLOSE => sys_close(args[0]),
+        Syscall::READ => sys_read(args[0], args[1], args[2]),
+        Syscall::WRITE => sys_write(args[0], args[1], args[2]),
+        Syscall::IOCTL => sys_ioctl(args[0], args[1], args[2]),
+        Syscall::SBRK => sys_sbrk(args[0] as isize),
+        Syscall::MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        Syscall::MUNMAP => sys_munmap(args[0], args[1]),
+        Syscall::MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        Syscall::SETKEYMAP => sys_setkeymap(args[0], args[1]),
+        Syscall::SLEEP => sys_sleep(args[0]),
+        Syscall::UPTIME => sys_uptime(),
+        Syscall::CLOCK_GETTIME => sys_clock_gettime(args[0], args[1]),
+        Syscall::GETTIMEOFDAY => sys_gettimeofday(args[0]),
+        Syscall::REBOOT => sys_reboot(),
+        Syscall::POWEROFF => sys_poweroff(args[0]),
+        Syscall::KILL => sys_kill(args[0] as isize, args[1]),
+        Syscall::SIGNAL => sys_signal(args[0], args[1], args[2]),
+        Syscall::SIGACTION => sys_sigaction(args[0], args[1], args[2]),
+        Syscall::SIGPENDING => sys_sigpending(args[0]),
+        Syscall::SIGPROCMASK => sys_sigprocmask(args[0], args[1], args[2]),
+        Syscall::SIGSUSPEND => sys_sigsuspend(args[0]),
+        Syscall::SIGRETURN => sys_sigreturn(),
+        _ => Err(Error::NotSupported)
+    };
+
+    match ret {
+        Ok(v) => v as isize,
+        Err(e) => e.errno()
     }
 }
 
@@ -86,8 +138,344 @@ pub fn init()
     
 }
 
-pub fn sys_write(fd: isize, buf: &[u8]) {
-    let msg = ::core::str::from_utf8(buf).unwrap();
-    Console::with(&tty1, 18, 0, || { printk!(Debug, "sys_write {}\n\r", msg); });
+/// end of [ptr, ptr+len) if it lies in user space
+fn user_range(ptr: usize, len: usize) -> Result<usize> {
+    match ptr.checked_add(len) {
+        Some(end) if ptr != 0 && end <= USER_SPACE_END => Ok(end),
+        _ => Err(Error::Fault)
+    }
+}
+
+/// [ptr, ptr+len) has to lie in areas of the current task that allow the
+/// access, or the kernel would fault on it
+fn verify_access(ptr: usize, len: usize, write: bool) -> Result<()> {
+    let end = user_range(ptr, len)?;
+    let ok = task::with_current(|task| {
+        task.space.as_ref().map_or(false, |space| space.permits(ptr, end, write))
+    });
+    if ok { Ok(()) } else { Err(Error::Fault) }
+}
+
+/// make sure the current task may read [ptr, ptr+len)
+pub fn verify_user_area(ptr: usize, len: usize) -> Result<()> {
+    verify_access(ptr, len, false)
+}
+
+/// make sure the current task may write [ptr, ptr+len)
+pub fn verify_user_area_mut(ptr: usize, len: usize) -> Result<()> {
+    verify_access(ptr, len, true)
+}
+
+pub unsafe fn user_slice<'a>(ptr: usize, len: usize) -> Result<&'a [u8]> {
+    verify_user_area(ptr, len)?;
+    Ok(::core::slice::from_raw_parts(ptr as *const u8, len))
+}
+
+pub unsafe fn user_slice_mut<'a>(ptr: usize, len: usize) -> Result<&'a mut [u8]> {
+    verify_user_area_mut(ptr, len)?;
+    Ok(::core::slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+
+pub fn sys_open(path: usize, len: usize, flags: usize) -> Result<usize> {
+    let path = unsafe { user_slice(path, len)? };
+    let path = ::core::str::from_utf8(path).map_err(|_| Error::Invalid)?;
+
+    let file = vfs::open(path, flags)?;
+    task::with_current(|task| task.alloc_fd(file)).map_err(|e| {
+        file.dev.close();
+        e
+    })
+}
+
+pub fn sys_close(fd: usize) -> Result<usize> {
+    let file = task::with_current(|task| task.take_file(fd))?;
+    file.dev.close();
+    Ok(0)
+}
+
+pub fn sys_read(fd: usize, buf: usize, len: usize) -> Result<usize> {
+    let buf = unsafe { user_slice_mut(buf, len)? };
+    let file = task::with_current(|task| task.get_file(fd))?;
+    file.dev.read(buf, file.flags)
+}
+
+pub fn sys_write(fd: usize, buf: usize, len: usize) -> Result<usize> {
+    let buf = unsafe { user_slice(buf, len)? };
+    match task::with_current(|task| task.get_file(fd)) {
+        Ok(file) => file.dev.write(buf),
+        Err(_) if fd == 1 || fd == 2 => {
+            // copied first, a fault must not hit with the console locked
+            let msg = String::from_utf8(buf.to_vec()).map_err(|_| Error::Invalid)?;
+            Console::with(&tty1, 18, 0, || { printk!(Debug, "sys_write {}\n\r", msg); });
+            Ok(buf.len())
+        },
+        Err(e) => Err(e)
+    }
+}
+
+pub fn sys_ioctl(fd: usize, cmd: usize, arg: usize) -> Result<usize> {
+    let file = task::with_current(|task| task.get_file(fd))?;
+    file.dev.ioctl(cmd, arg)
+}
+
+/// switch keyboard layout by name, like "us" or "de"
+pub fn sys_setkeymap(name: usize, len: usize) -> Result<usize> {
+    let name = unsafe { user_slice(name, len)? };
+    let name = ::core::str::from_utf8(name).map_err(|_| Error::Invalid)?;
+    keymap::set_keymap(name).map(|_| 0)
+}
+
+/// sleep for nanos nanoseconds
+pub fn sys_sleep(nanos: usize) -> Result<usize> {
+    if task::sleep_until(clock::now().saturating_add(nanos as u64)) {
+        Ok(0)
+    } else {
+        Err(Error::Interrupted)
+    }
+}
+
+/// nanoseconds since boot
+pub fn sys_uptime() -> Result<usize> {
+    Ok(clock::now() as usize)
+}
+
+pub fn sys_clock_gettime(id: usize, ts: usize) -> Result<usize> {
+    let nanos = match id {
+        clock::CLOCK_REALTIME => clock::realtime(),
+        clock::CLOCK_MONOTONIC => clock::now(),
+        _ => return Err(Error::Invalid)
+    };
+    verify_user_area_mut(ts, size_of::<clock::Timespec>())?;
+    unsafe { ::core::ptr::write(ts as *mut clock::Timespec, clock::Timespec::from_nanos(nanos)); }
+    Ok(0)
+}
+
+/// no timezone argument, the kernel only knows UTC
+pub fn sys_gettimeofday(tv: usize) -> Result<usize> {
+    verify_user_area_mut(tv, size_of::<clock::Timeval>())?;
+    let now = clock::Timeval::from_nanos(clock::realtime());
+    unsafe { ::core::ptr::write(tv as *mut clock::Timeval, now); }
+    Ok(0)
+}
+
+/// only init may reboot or power off the machine
+fn check_init() -> Result<()> {
+    if task::current_id() == task::init_pid() {
+        Ok(())
+    } else {
+        Err(Error::NotPermitted)
+    }
+}
+
+pub fn sys_reboot() -> Result<usize> {
+    check_init()?;
+    power::reboot()
+}
+
+/// a non zero status is handed to QEMU's isa-debug-exit first, so that test
+/// runs can report failures. without that device it's a plain poweroff.
+pub fn sys_poweroff(status: usize) -> Result<usize> {
+    check_init()?;
+    if status != 0 {
+        power::exit_qemu(status as u32);
+    }
+    power::poweroff()
+}
+
+/// move the program break by increment bytes, returns the old break
+pub fn sys_sbrk(increment: isize) -> Result<usize> {
+    task::with_current(|task| {
+        let space = task.space.as_mut().ok_or(Error::Invalid)?;
+        let old = space.brk();
+        let new = if increment < 0 {
+            old.checked_sub(increment.wrapping_neg() as usize)
+        } else {
+            old.checked_add(increment as usize)
+        };
+
+        match new {
+            Some(new) if space.set_brk(new) => Ok(old),
+            _ => Err(Error::NoMemory)
+        }
+    })
+}
+
+/// page flags for PROT_* bits. x86 can't take away read access from a
+/// present page, PROT_NONE areas leave out USER instead.
+fn prot_flags(prot: usize) -> paging::EntryFlags {
+    if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 {
+        return paging::NO_EXECUTE;
+    }
+
+    let mut flags = paging::USER;
+    if prot & PROT_WRITE != 0 {
+        flags |= paging::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= paging::NO_EXECUTE;
+    }
+    flags
+}
+
+/// anonymous mappings, private or shared, and shared mappings of device
+/// memory. nothing is mapped until first access. without MAP_FIXED, addr
+/// is a hint that is taken when free.
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize,
+                fd: usize, offset: usize) -> Result<usize> {
+    if len == 0 || addr % PAGE_SIZE != 0 || offset % PAGE_SIZE != 0 {
+        return Err(Error::Invalid);
+    }
+
+    let shared = match (flags & MAP_SHARED != 0, flags & MAP_PRIVATE != 0) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => return Err(Error::Invalid)
+    };
+
+    let len = checked_page_align(len).ok_or(Error::NoMemory)?;
+    task::with_current(|task| {
+        let backing = if flags & MAP_ANONYMOUS != 0 {
+            Backing::Anonymous
+        } else {
+            // private copies of device memory make no sense
+            if !shared {
+                return Err(Error::NotSupported);
+            }
+            let file = task.get_file(fd)?;
+            file.dev.mmap(offset, len)?;
+            Backing::File { file: file, offset: offset }
+        };
+
+        let space = task.space.as_mut().ok_or(Error::Invalid)?;
+        let start = if flags & MAP_FIXED != 0 {
+            user_range(addr, len)?;
+            space.remove(addr, addr + len);
+            addr
+        } else if user_range(addr, len).is_ok() && space.is_free(addr, addr + len) {
+            addr
+        } else {
+            let user_end = KERNEL_MAPPING.UserMap.end;
+            space.find_free(len, USER_MMAP_BASE, user_end)
+                .or_else(|| space.find_free(len, KERNEL_MAPPING.UserCode.start, USER_MMAP_BASE))
+                .ok_or(Error::NoMemory)?
+        };
+
+        let mut vma = VirtualMemoryArea::new(start, len, prot_flags(prot));
+        vma.backing = backing;
+        vma.shared = shared;
+        let inserted = space.insert(vma);
+        assert!(inserted, "mmap: [{:#x}, {:#x}) is taken", start, start + len);
+        space.merge(start);
+
+        printk!(Debug, "mmap [{:#x}, {:#x}) {:?}\n\r", start, start + len, backing);
+        Ok(start)
+    })
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> Result<usize> {
+    if len == 0 || addr % PAGE_SIZE != 0 {
+        return Err(Error::Invalid);
+    }
+
+    let len = checked_page_align(len).ok_or(Error::Invalid)?;
+    user_range(addr, len)?;
+    task::with_current(|task| {
+        let space = task.space.as_mut().ok_or(Error::Invalid)?;
+        space.remove(addr, addr + len);
+        Ok(0)
+    })
+}
+
+/// the whole range must be mapped
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> Result<usize> {
+    if addr % PAGE_SIZE != 0 {
+        return Err(Error::Invalid);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let len = checked_page_align(len).ok_or(Error::Invalid)?;
+    user_range(addr, len)?;
+    task::with_current(|task| {
+        let space = task.space.as_mut().ok_or(Error::Invalid)?;
+        if !space.covers(addr, addr + len) {
+            return Err(Error::NoMemory);
+        }
+        space.protect(addr, addr + len, prot_flags(prot));
+        Ok(0)
+    })
+}
+
+pub fn sys_kill(pid: isize, sig: usize) -> Result<usize> {
+    signal::kill(pid, sig).map(|_| 0)
+}
+
+/// sets handler with nothing blocked, restorer is where it returns to.
+/// returns the old handler
+pub fn sys_signal(sig: usize, handler: usize, restorer: usize) -> Result<usize> {
+    let action = SigAction {
+        handler: handler,
+        flags: SA_RESTORER,
+        restorer: restorer,
+        mask: 0
+    };
+    signal::set_action(sig, Some(action)).map(|old| old.handler)
+}
+
+/// act and oldact point at SigActions, either may be 0
+pub fn sys_sigaction(sig: usize, act: usize, oldact: usize) -> Result<usize> {
+    let action = if act != 0 {
+        verify_user_area(act, size_of::<SigAction>())?;
+        Some(unsafe { ::core::ptr::read(act as *const SigAction) })
+    } else {
+        None
+    };
+    if oldact != 0 {
+        verify_user_area_mut(oldact, size_of::<SigAction>())?;
+    }
+
+    let old = signal::set_action(sig, action)?;
+    if oldact != 0 {
+        unsafe { ::core::ptr::write(oldact as *mut SigAction, old); }
+    }
+    Ok(0)
+}
+
+pub fn sys_sigpending(set: usize) -> Result<usize> {
+    verify_user_area_mut(set, size_of::<SigSet>())?;
+    unsafe { ::core::ptr::write(set as *mut SigSet, signal::pending()); }
+    Ok(0)
+}
+
+/// set and oldset point at SigSets, either may be 0
+pub fn sys_sigprocmask(how: usize, set: usize, oldset: usize) -> Result<usize> {
+    let mask = if set != 0 {
+        verify_user_area(set, size_of::<SigSet>())?;
+        Some(unsafe { ::core::ptr::read(set as *const SigSet) })
+    } else {
+        None
+    };
+    if oldset != 0 {
+        verify_user_area_mut(oldset, size_of::<SigSet>())?;
+    }
+
+    let old = signal::procmask(how, mask)?;
+    if oldset != 0 {
+        unsafe { ::core::ptr::write(oldset as *mut SigSet, old); }
+    }
+    Ok(0)
 }
 
+/// always fails with Interrupted, after the signal is taken
+pub fn sys_sigsuspend(mask: usize) -> Result<usize> {
+    verify_user_area(mask, size_of::<SigSet>())?;
+    signal::suspend(unsafe { ::core::ptr::read(mask as *const SigSet) });
+    Err(Error::Interrupted)
+}
+
+/// rax comes back from the restored context, not from here
+pub fn sys_sigreturn() -> Result<usize> {
+    signal::sigreturn();
+    Ok(0)
+}
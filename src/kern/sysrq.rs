@@ -0,0 +1,101 @@
+//! Magic SysRq: emergency actions for when the kernel seems stuck.
+//!
+//! Hold Alt + SysRq (Alt + PrintScreen) and press an action key, or send a
+//! break on COM1 followed by the action key. Actions run right in the irq
+//! handler, so they must not wait on locks the interrupted code may hold.
+//! Output bypasses the log level and falls back to serial while the console
+//! is locked, `h` prints the list of actions.
+
+use ::kern::console::{self, tty1, LogLevel};
+use ::kern::interrupts;
+use ::kern::interrupts::idt::ExceptionStackFrame;
+use ::kern::memory::frame;
+use ::kern::power;
+use ::kern::smp;
+use ::kern::task;
+
+struct Action {
+    key: u8,
+    help: &'static str,
+    handler: fn(&ExceptionStackFrame)
+}
+
+const ACTIONS: [Action; 8] = [
+    Action { key: b'b', help: "reboot", handler: reboot },
+    Action { key: b'h', help: "help", handler: help },
+    Action { key: b'l', help: "show-backtrace", handler: backtrace },
+    Action { key: b'm', help: "show-memory-usage", handler: show_memory },
+    Action { key: b'o', help: "poweroff", handler: poweroff },
+    Action { key: b'p', help: "show-registers", handler: show_registers },
+    Action { key: b's', help: "sync", handler: sync },
+    Action { key: b't', help: "show-task-states", handler: show_tasks },
+];
+
+/// run the action bound to key, called from keyboard and serial irqs
+pub fn handle(key: u8, frame: &ExceptionStackFrame) {
+    let key = match key {
+        b'A'...b'Z' => key - b'A' + b'a',
+        _ => key
+    };
+
+    if let b'0'...b'9' = key {
+        let lv = LogLevel::from_usize((key - b'0') as usize);
+        println_nowait!("SysRq : Changing Loglevel");
+        console::set_log_level(lv);
+        println_nowait!("Loglevel set to {:?}", lv);
+        return;
+    }
+
+    match ACTIONS.iter().find(|a| a.key == key) {
+        Some(action) => {
+            println_nowait!("SysRq : {}", action.help);
+            (action.handler)(frame);
+        },
+        None => help(frame)
+    }
+}
+
+fn help(_frame: &ExceptionStackFrame) {
+    print_nowait!("SysRq : HELP : loglevel(0-9)");
+    for action in ACTIONS.iter() {
+        print_nowait!(" {}({})", action.help, action.key as char);
+    }
+    println_nowait!("");
+}
+
+fn reboot(_frame: &ExceptionStackFrame) {
+    power::reboot();
+}
+
+fn poweroff(_frame: &ExceptionStackFrame) {
+    power::poweroff();
+}
+
+fn backtrace(_frame: &ExceptionStackFrame) {
+    unsafe { ::stack_trace(); }
+}
+
+fn show_memory(_frame: &ExceptionStackFrame) {
+    frame::dump_stats();
+}
+
+fn show_registers(frame: &ExceptionStackFrame) {
+    interrupts::dump_registers(frame, unsafe { frame.registers(false) });
+    match smp::try_this_cpu() {
+        Some(cpu) => println_nowait!("cpu {} current pid {}", cpu.id, cpu.current()),
+        None => println_nowait!("current pid 0")
+    }
+}
+
+/// there is no write back cache yet, only the console back buffer
+fn sync(_frame: &ExceptionStackFrame) {
+    match tty1.try_lock() {
+        Some(mut con) => con.refresh(),
+        None => println_nowait!("console is busy")
+    }
+    println_nowait!("Emergency Sync complete");
+}
+
+fn show_tasks(_frame: &ExceptionStackFrame) {
+    task::dump();
+}
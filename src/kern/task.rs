@@ -1,13 +1,16 @@
-use ::kern::memory::inactive::{TemporaryPage, InactivePML4Table};
 use ::kern::memory::stack_allocator::{Stack, StackAllocator};
-use ::kern::memory::{MemoryManager, MM, KERNEL_MAPPING};
+use ::kern::memory::{MemoryManager, MM, PAGE_SIZE, KERNEL_MAPPING, page_align};
+use ::kern::memory::address_space::{AddressSpace, VirtualMemoryArea};
 use ::kern::memory::paging;
 use ::kern::console::LogLevel::*;
 use ::kern::console::{Console, tty1};
 use ::kern::arch::cpu;
 use ::kern::interrupts::{self, idt};
+use ::kern::smp::{self, PerCpu};
+use ::kern::clock;
+use ::kern::signal::{self, Signals};
 
-use core::sync::atomic::{AtomicIsize, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use collections::string::{String, ToString};
 use collections::{BTreeMap, Vec};
 use alloc::arc::Arc;
@@ -17,6 +20,8 @@ use core::ops::{Deref, DerefMut};
 
 use spin::*;
 use ::kern::elf64::*;
+use ::kern::vfs::{self, OpenFile, MAX_FILES};
+use ::kern::driver::video::splash::{self, Stage};
 use x86_64;
 
 pub type ProcId = isize;
@@ -28,9 +33,24 @@ pub enum TaskState {
     Ready,
     Running,
     Sleep,
+    Stopped,
     Zombie
 }
 
+impl TaskState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TaskState::Unused => "unused",
+            TaskState::Created => "created",
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Sleep => "sleep",
+            TaskState::Stopped => "stopped",
+            TaskState::Zombie => "zombie"
+        }
+    }
+}
+
 
 /// context for kernel side task scheduler
 #[derive(Debug, Clone)]
@@ -62,66 +82,6 @@ impl Context {
     }
 }
 
-/// for task 
-#[derive(Debug, Clone)]
-pub struct VirtualMemoryArea {
-    pub start: usize,
-    pub size: usize,
-    pub mapped: bool,
-    pub flags: paging::EntryFlags,
-}
-
-impl VirtualMemoryArea {
-    pub fn new(start: usize, size: usize, flags: paging::EntryFlags) -> VirtualMemoryArea {
-        assert!(!flags.contains(paging::PRESENT));
-
-        VirtualMemoryArea {
-            start: start,
-            size: size,
-            mapped: false,
-            flags: flags
-        }
-    }
-
-    pub fn map_with_data(&self, inactive: &mut InactivePML4Table, data: &[u8]) {
-        let mut active = paging::ActivePML4Table::new();
-        let mut temp_page = TemporaryPage::new(paging::Page::from_vaddress(0xfffff_cafe_beef_000));
-        printk!(Debug, "mapping VirtualMemoryArea {:?} {:?}\n\r", self.get_pages(), self.flags);
-        active.with(inactive, &mut temp_page, |mapper| {
-            for page in self.get_pages() {
-                mapper.map(page, self.flags);
-            }
-        });
-
-        // switching pml4 is heavy
-        let cur_pml4 = paging::switch(inactive.clone());
-        unsafe {
-            ::core::ptr::copy_nonoverlapping(data.as_ptr() as *mut u8,
-                self.start as *mut u8, data.len());
-        }
-        paging::switch(cur_pml4);
-    }
-
-    pub fn map(&self, inactive: &mut InactivePML4Table) {
-        let mut active = paging::ActivePML4Table::new();
-        let mut temp_page = TemporaryPage::new(paging::Page::from_vaddress(0xfffff_cafe_beef_000));
-        printk!(Debug, "mapping VirtualMemoryArea {:?} {:?}\n\r", self.get_pages(), self.flags);
-        active.with(inactive, &mut temp_page, |mapper| {
-            for page in self.get_pages() {
-                mapper.map(page, self.flags);
-            }
-        });
-    }
-
-    pub fn unmap(&mut self, inactive: &mut InactivePML4Table) {
-        unimplemented!()
-    }
-
-    pub fn get_pages(&self) -> paging::PageRange {
-        paging::PageRange::new(self.start, self.start + self.size)
-    }
-}
-
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
 pub struct TLSSegment {
@@ -151,14 +111,16 @@ pub struct Task {
     pub pid: ProcId,
     pub ppid: ProcId,
     pub name: Option<String>,
-    pub cr3: Option<InactivePML4Table>,
     pub kern_stack: Option<Stack>,
-    pub user_stack: Option<VirtualMemoryArea>,
-    pub code: Option<VirtualMemoryArea>,
-    pub data: Option<VirtualMemoryArea>, //including data and bss
+    /// None for kernel tasks, they run on the kernel page tables
+    pub space: Option<AddressSpace>,
     pub exec_entry: usize,
     pub ctx: Context,
     pub state: TaskState,
+    pub files: [Option<OpenFile>; MAX_FILES],
+    /// when a sleeping task may run again, in clock::now() time
+    pub wake_at: u64,
+    pub signals: Signals,
 }
 
 impl Task {
@@ -167,16 +129,51 @@ impl Task {
             pid: 0,
             ppid: 0,
             name: None,
-            cr3: None,
             kern_stack: None,
-            user_stack: None,
-            code: None,
-            data: None,
+            space: None,
             exec_entry: 0,
             state: TaskState::Unused,
             ctx: Context::new(),
+            files: [None; MAX_FILES],
+            wake_at: 0,
+            signals: Signals::new(),
+        }
+    }
+
+    /// install file into the lowest free slot, 0-2 are reserved for stdio
+    pub fn alloc_fd(&mut self, file: OpenFile) -> vfs::Result<usize> {
+        for fd in 3..MAX_FILES {
+            if self.files[fd].is_none() {
+                self.files[fd] = Some(file);
+                return Ok(fd);
+            }
         }
+
+        Err(vfs::Error::TooManyFiles)
+    }
+
+    pub fn get_file(&self, fd: usize) -> vfs::Result<OpenFile> {
+        self.files.get(fd).and_then(|f| *f).ok_or(vfs::Error::BadFd)
+    }
+
+    pub fn take_file(&mut self, fd: usize) -> vfs::Result<OpenFile> {
+        self.files.get_mut(fd).and_then(|f| f.take()).ok_or(vfs::Error::BadFd)
     }
+
+    /// whether the scheduler may pick it at time now
+    pub fn is_runnable(&self, now: u64) -> bool {
+        match self.state {
+            TaskState::Sleep => now >= self.wake_at,
+            TaskState::Unused | TaskState::Stopped | TaskState::Zombie => false,
+            _ => true
+        }
+    }
+}
+
+/// cr3 of kernel tasks
+fn kernel_cr3() -> usize {
+    let mm = MM.try().unwrap().lock();
+    mm.kernelPML4Table.pml4_frame.start_address()
 }
 
 pub const MAX_TASK: isize = 64;
@@ -209,7 +206,7 @@ impl TaskList {
     }
 
     pub fn current(&self) -> Option<&Arc<RwLock<Task>>> {
-        self.get_task(CURRENT_ID.load(Ordering::SeqCst))
+        self.get_task(current_id())
     }
 
     // kernel thread
@@ -236,10 +233,6 @@ impl TaskList {
             let top = mem.as_ptr() as usize;
             Stack::new(top + mem.len(), top)
         });
-        task.cr3 = Some({
-            let mut mm = MM.try().unwrap().lock();
-            mm.kernelPML4Table
-        });
         task.ctx = Context::new();
 
         let kern_rsp = task.kern_stack.as_ref().map(|st| st.top()).unwrap();
@@ -262,14 +255,34 @@ impl TaskList {
             *fp.offset(-5) = task.exec_entry;
             *fp.offset(-6) = start_task as usize;
         }
-        task.ctx.cr3 = task.cr3.as_ref().unwrap().pml4_frame.start_address();
+        task.ctx.cr3 = kernel_cr3();
 
         self.entry(pid).or_insert(Arc::new(RwLock::new(task)));
         self.next_id += 1;
     }
 
-    // user task
-    pub fn load_task(&mut self, name: &str, elf: &Elf64, parent: ProcId) {
+    /// turn the running context into a kernel task, for the idle tasks of
+    /// APs. its context is saved by the first switch away from it.
+    pub fn adopt_running(&mut self, name: String) -> ProcId {
+        let pid = self.next_id;
+        assert!(self.next_id < MAX_TASK, "task id exceeds maximum boundary");
+
+        let mut task = Task::empty();
+        task.pid = pid as isize;
+        task.ppid = 0;
+        task.name = Some(name);
+        task.state = TaskState::Running;
+        task.ctx = Context::new();
+        task.ctx.cr3 = kernel_cr3();
+
+        self.entry(pid).or_insert(Arc::new(RwLock::new(task)));
+        self.next_id += 1;
+        pid
+    }
+
+    /// user task running elf, nothing is created if a segment can't be
+    /// loaded
+    pub fn load_task(&mut self, name: &str, elf: &Elf64, parent: ProcId) -> Result<ProcId> {
         use core::mem::size_of;
 
         let pid = self.next_id;
@@ -281,24 +294,16 @@ impl TaskList {
         task.name = Some(name.to_string());
         task.state = TaskState::Created;
 
-        task.cr3 = Some({
+        let mut space = AddressSpace::new({
             let mut mm = MM.try().unwrap().lock();
             paging::create_address_space(mm.mbinfo)
         });
 
-        task.user_stack = Some({
-            let mut vma = VirtualMemoryArea {
-                start: KERNEL_MAPPING.UserStack.start,
-                size: KERNEL_MAPPING.UserStack.end - KERNEL_MAPPING.UserStack.start + 1,
-                mapped: false,
-                flags: paging::USER | paging::WRITABLE | paging::NO_EXECUTE
-            };
-
-            vma.map(task.cr3.as_mut().unwrap());
-            vma.mapped = true;
-
-            vma
-        });
+        // faulted in as the stack grows
+        space.insert(VirtualMemoryArea::new(
+            KERNEL_MAPPING.UserStack.start,
+            KERNEL_MAPPING.UserStack.end - KERNEL_MAPPING.UserStack.start + 1,
+            paging::USER | paging::WRITABLE | paging::NO_EXECUTE));
 
         {
             printk!(Debug, "load program_headers\n\r");
@@ -307,37 +312,22 @@ impl TaskList {
             for ph in elf.program_headers() {
                 printk!(Debug, "{:?}\n\r", ph);
                 if ph.p_type != PT_LOAD { continue; }
-
-                let sz = ph.p_memsz as usize;
-                let data = unsafe { elf.data.as_ptr().offset(ph.p_offset as isize) };
-                match (ph.p_flags & PF_X) != 0 {
-                    false => {
-                        printk!(Debug, "load data/bss segment\n\r");
-                    }, 
-
-                    true => {
-                        //let code: &[u8; 20] = &*(data as *const [u8; 20]);
-                        //printk!(Debug, "load code segment {:?}\n\r", code);
-                        task.code = Some({
-                            let mut vma = VirtualMemoryArea {
-                                start: KERNEL_MAPPING.UserCode.start,
-                                size: sz,
-                                mapped: false,
-                                flags: paging::USER | paging::WRITABLE
-                            };
-
-                            let data = unsafe { ::core::slice::from_raw_parts(data, sz) };
-                            vma.map_with_data(task.cr3.as_mut().unwrap(), data);
-                            vma.mapped = true;
-
-                            vma
-                        });
-
-                    }
+                if let Err(e) = load_segment(&mut space, elf, ph) {
+                    printk!(Warn, "load: {} has a bad segment {:?}, {:?}\n\r", name, ph, e);
+                    space.destroy();
+                    return Err(e);
                 }
             }
         }
 
+        // the heap follows the highest loaded segment
+        let loaded_end = space.areas()
+            .filter(|vma| vma.start < KERNEL_MAPPING.UserStack.start)
+            .map(|vma| vma.end())
+            .max()
+            .unwrap_or(KERNEL_MAPPING.UserCode.start);
+        space.set_heap_start(loaded_end);
+
 
         task.kern_stack = Some({
             let mem = unsafe {
@@ -360,12 +350,63 @@ impl TaskList {
             });
         }
         
-        task.ctx.cr3 = task.cr3.as_ref().unwrap().pml4_frame.start_address();
-        printk!(Debug, "init cr3 {:?} {}\n\r", task.cr3, task.ctx.cr3);
+        task.ctx.cr3 = space.table().pml4_frame.start_address();
+        printk!(Debug, "init cr3 {:?} {}\n\r", space.table(), task.ctx.cr3);
+        task.space = Some(space);
 
         self.entry(pid).or_insert(Arc::new(RwLock::new(task)));
         self.next_id += 1;
+        Ok(pid)
+    }
+}
+
+/// page flags for the p_flags of a segment, every segment is readable
+fn segment_flags(p_flags: u32) -> paging::EntryFlags {
+    let mut flags = paging::USER;
+    if p_flags & PF_W != 0 {
+        flags |= paging::WRITABLE;
+    }
+    if p_flags & PF_X == 0 {
+        flags |= paging::NO_EXECUTE;
     }
+    flags
+}
+
+/// map a PT_LOAD segment at its p_vaddr: p_filesz bytes from the file,
+/// zeros up to p_memsz.
+fn load_segment(space: &mut AddressSpace, elf: &Elf64, ph: &ProgramHeader) -> Result<()> {
+    let (vaddr, memsz) = (ph.p_vaddr as usize, ph.p_memsz as usize);
+    let (offset, filesz) = (ph.p_offset as usize, ph.p_filesz as usize);
+
+    if filesz > memsz {
+        return Err(ElfError::BadSegment);
+    }
+    if offset.checked_add(filesz).map_or(true, |e| e > elf.data.len()) {
+        return Err(ElfError::OutOfBounds);
+    }
+    if memsz == 0 {
+        return Ok(());
+    }
+
+    let start = vaddr & !(PAGE_SIZE - 1);
+    let end = match vaddr.checked_add(memsz) {
+        Some(end) if start >= KERNEL_MAPPING.UserCode.start &&
+            end <= KERNEL_MAPPING.UserStack.start => page_align(end),
+        _ => return Err(ElfError::BadSegment)
+    };
+
+    if !space.is_free(start, end) {
+        printk!(Warn, "load: segment [{:#x}, {:#x}) overlaps another\n\r", start, end);
+        return Err(ElfError::BadSegment);
+    }
+
+    let mut vma = VirtualMemoryArea::new(start, end - start, segment_flags(ph.p_flags));
+
+    printk!(Debug, "load segment [{:#x}, {:#x}) {:?}\n\r", start, end, vma.flags);
+    vma.map_with_data(space.table_mut(), vaddr - start, &elf.data[offset..offset + filesz]);
+    vma.mapped = true;
+    space.insert(vma);
+    Ok(())
 }
 
 impl Deref for TaskList {
@@ -382,7 +423,131 @@ impl DerefMut for TaskList {
 }
 
 static TASKS: Once<RwLock<TaskList>> = Once::new();
-pub static CURRENT_ID: AtomicIsize = AtomicIsize::new(0);
+
+/// tasks running on some cpu, bit pid (MAX_TASK fits). a task stays here
+/// until the cpu switching away from it has left its stack, so no other cpu
+/// picks it up half saved.
+static ON_CPU: AtomicUsize = AtomicUsize::new(0);
+
+/// mark pid as running, false if it already runs somewhere
+fn claim(pid: ProcId) -> bool {
+    let bit = 1 << pid as usize;
+    ON_CPU.fetch_or(bit, Ordering::AcqRel) & bit == 0
+}
+
+fn release(pid: ProcId) {
+    ON_CPU.fetch_and(!(1 << pid as usize), Ordering::Release);
+}
+
+/// pid of the first user task, which alone may reboot or power off
+static INIT_PID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init_pid() -> ProcId {
+    INIT_PID.load(Ordering::Acquire) as ProcId
+}
+
+/// pid of the task running on this cpu, 0 before tasking starts
+pub fn current_id() -> ProcId {
+    let oflags = unsafe { cpu::push_flags() };
+    let pid = smp::try_this_cpu().map_or(0, |cpu| cpu.current());
+    unsafe { cpu::pop_flags(oflags); }
+    pid
+}
+
+/// make the running context the idle task of cpu, APs do this once
+/// scheduling starts. interrupts should be disabled.
+pub fn adopt_idle(cpu: &PerCpu) {
+    let pid = TaskList::get_mut().adopt_running(format!("idle/{}", cpu.id));
+    claim(pid);
+    cpu.set_idle(pid);
+    cpu.set_current(pid);
+}
+
+/// resolve a page fault at addr of the current task by mapping a zeroed
+/// page, if addr lies in one of its areas and the access is allowed there.
+/// false when the fault is not that kind, called with interrupts disabled.
+/// user memory must not be touched with the task locked, this waits for it.
+pub fn fault_in(addr: usize, write: bool, exec: bool) -> bool {
+    let pid = current_id();
+    if pid == 0 {
+        return false;
+    }
+
+    let tasks = TaskList::get();
+    let task = match tasks.get_task(pid) {
+        Some(task) => task.read(),
+        None => return false
+    };
+
+    match task.space {
+        Some(ref space) => space.fault_in(addr, write, exec),
+        None => false
+    }
+}
+
+/// end the current task killed by a signal, it never runs again. its files
+/// are closed and its address space torn down, the parent gets SIGCHLD.
+pub fn kill_current(sig: usize) -> ! {
+    unsafe { asm!("cli":::: "volatile"); }
+    let (pid, ppid, files, space) = with_current(|task| {
+        task.state = TaskState::Zombie;
+        // what is left of it runs on the kernel tables
+        task.ctx.cr3 = kernel_cr3();
+        let files: Vec<OpenFile> = task.files.iter_mut().filter_map(|f| f.take()).collect();
+        (task.pid, task.ppid, files, task.space.take())
+    });
+    printk!(Warn, "task {} killed by signal {}\n\r", pid, sig);
+
+    for file in files {
+        file.dev.close();
+    }
+    if let Some(space) = space {
+        unsafe { cpu::cr3_set(kernel_cr3()); }
+        space.destroy();
+    }
+
+    // kernel tasks take no signals, a missing parent is fine too
+    let _ = signal::send(ppid, signal::SIGCHLD);
+
+    unsafe { sched(); }
+    unreachable!("zombie task {} scheduled", pid);
+}
+
+/// run f on current task. interrupts are disabled meanwhile, or sched may
+/// find current task locked.
+pub fn with_current<F, R>(f: F) -> R where F: FnOnce(&mut Task) -> R {
+    let oflags = unsafe { cpu::push_flags() };
+    let ret = {
+        let tasks = TaskList::get();
+        let mut current = tasks.current().expect("no current task").write();
+        f(&mut *current)
+    };
+    unsafe { cpu::pop_flags(oflags); }
+    ret
+}
+
+/// print every task with its state, for debugging. locked tasks are reported
+/// as such instead of waited on, since this may run from an interrupt.
+pub fn dump() {
+    let tasks = match TASKS.try().and_then(|t| t.try_read()) {
+        Some(tasks) => tasks,
+        None => {
+            println_nowait!("task list is unavailable");
+            return;
+        }
+    };
+
+    println_nowait!("  PID  PPID STATE     NAME");
+    for (&pid, task) in tasks.iter() {
+        let running = smp::cpus().iter().any(|cpu| cpu.current() == pid);
+        let mark = if running { '*' } else { ' ' };
+        match task.try_read() {
+            Some(t) => println_nowait!("{}{:>4} {:>5} {:<9} {}", mark, pid, t.ppid,
+                                t.state.name(), t.name.as_ref().map_or("", |n| n.as_str())),
+            None => println_nowait!("{}{:>4}       (locked)", mark, pid)
+        }
+    }
+}
 
 fn init_tasks() -> RwLock<TaskList> { RwLock::new(TaskList::new()) }
 
@@ -404,6 +569,8 @@ pub fn init() {
         ];
 
         let mut tasks = TaskList::get_mut();
+        // the first one is the idle task of the boot cpu
+        smp::this_cpu().set_idle(tasks.next_id);
         for (id, &rip) in rips.iter().enumerate() {
             tasks.alloc_kernel_task(names[id], rip);
             //printk!(Info, "{:?}\n\r", task);
@@ -422,8 +589,8 @@ pub fn init() {
             let elf = unsafe {
                 let kernel_base = KERNEL_MAPPING.KernelMap.start;
                 let mut mm = MM.try().unwrap().lock();
-                let init_mod = mm.mbinfo.module_tags().next().unwrap();
-                assert!(init_mod.name() == "init");
+                let init_mod = mm.mbinfo.module_tags().find(|m| m.name() == "init")
+                    .expect("init module is unavailable");
 
                 let (init_start, init_end) = (
                     init_mod.start_address() as usize + kernel_base,
@@ -431,25 +598,32 @@ pub fn init() {
                 );
                 let bytes = ::core::slice::from_raw_parts(init_start as *const u8, 
                     (init_end - init_start) as usize);
-                Elf64::from(bytes)
+                Elf64::parse(bytes).unwrap_or_else(|e| panic!("init module: bad elf {:?}", e))
             };
             printk!(Debug, "{:?}\n\r", elf.header);
 
             let mut tasks = TaskList::get_mut();
-            tasks.load_task(&"init", &elf, 1);
+            let pid = tasks.load_task(&"init", &elf, 1)
+                .unwrap_or_else(|e| panic!("init module: can not load {:?}", e));
+            INIT_PID.store(pid as usize, Ordering::Release);
         }
 
         let init: *mut Task;
         {
             let tasks = TaskList::get();
-            let task_lock = tasks.get_task(4).expect("task 4");
+            let task_lock = tasks.get_task(init_pid()).expect("init task");
             let mut task = task_lock.write();
-            CURRENT_ID.store(task.pid, Ordering::SeqCst);
+            claim(task.pid);
+            smp::this_cpu().set_current(task.pid);
             init = task.deref_mut() as *mut Task;
         }
 
 
+        splash::progress(Stage::Tasks);
+        splash::finish();
+
         printk!(Info, "start_tasking\n\r");
+        smp::start_scheduling();
         unsafe { ret_to_userspace(&mut *init); }
     }
 
@@ -458,10 +632,109 @@ pub fn init() {
 
 pub fn idle() {
     loop {
+        unsafe { asm!("cli":::: "volatile"); }
+        clock::idle_enter();
         unsafe { asm!("sti; hlt":::: "volatile"); }
     }
 }
 
+/// the timer event only has to wake an idle cpu, the scheduler does the rest
+fn wake_sleeper(_pid: usize) {
+}
+
+/// block the current task until clock::now() reaches deadline. false when
+/// a signal cut it short.
+pub fn sleep_until(deadline: u64) -> bool {
+    let oflags = unsafe { cpu::push_flags() };
+
+    let pid = with_current(|task| {
+        task.state = TaskState::Sleep;
+        task.wake_at = deadline;
+        task.pid
+    });
+    let event = clock::add_timer(deadline, wake_sleeper, pid as usize);
+
+    let mut slept = true;
+    while clock::now() < deadline {
+        if signal::interrupted() {
+            slept = false;
+            break;
+        }
+        unsafe { sched(); }
+    }
+
+    clock::cancel_timer(event);
+    with_current(|task| task.state = TaskState::Running);
+    unsafe { cpu::pop_flags(oflags); }
+    slept
+}
+
+/// tasks waiting for an event, one bit per pid as in ON_CPU so that
+/// producers in irq handlers neither allocate nor wait for a list.
+pub struct WaitQueue {
+    waiters: AtomicUsize,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: AtomicUsize::new(0) }
+    }
+
+    /// wake every task waiting, they recheck their condition. safe from irq
+    /// handlers, task locks are only held with interrupts disabled.
+    pub fn wake_all(&self) {
+        let waiters = self.waiters.swap(0, Ordering::AcqRel);
+        if waiters == 0 {
+            return;
+        }
+
+        let oflags = unsafe { cpu::push_flags() };
+        {
+            let tasks = TaskList::get();
+            for pid in (0..MAX_TASK).filter(|&pid| waiters & (1 << pid as usize) != 0) {
+                if let Some(task) = tasks.get_task(pid) {
+                    let mut task = task.write();
+                    if let TaskState::Sleep = task.state {
+                        task.wake_at = 0;
+                    }
+                }
+            }
+        }
+        unsafe { cpu::pop_flags(oflags); }
+    }
+}
+
+/// block the current task on queue until cond holds. false when a signal
+/// cut it short.
+pub fn wait_event<F>(queue: &WaitQueue, cond: F) -> bool where F: Fn() -> bool {
+    let oflags = unsafe { cpu::push_flags() };
+    let bit = 1 << current_id() as usize;
+
+    let mut woken = true;
+    loop {
+        // asleep before cond is checked, a wakeup in between is not lost
+        with_current(|task| {
+            task.state = TaskState::Sleep;
+            task.wake_at = u64::max_value();
+        });
+        queue.waiters.fetch_or(bit, Ordering::AcqRel);
+
+        if cond() {
+            break;
+        }
+        if signal::interrupted() {
+            woken = false;
+            break;
+        }
+        unsafe { sched(); }
+    }
+
+    queue.waiters.fetch_and(!bit, Ordering::AcqRel);
+    with_current(|task| task.state = TaskState::Running);
+    unsafe { cpu::pop_flags(oflags); }
+    woken
+}
+
 pub fn test_thread2() {
     let mut count = 0;
     let busy_wait = || {
@@ -537,7 +810,7 @@ pub unsafe extern "C" fn switch_to(current: &mut Task, next: &mut Task) {
 #[inline(never)]
 #[naked]
 unsafe extern "C" fn start_task() -> ! {
-    asm!("iretq" ::: "memory" : "volatile");
+    asm!("call *$0; iretq" :: "r"(finish_switch as usize) : "memory" : "volatile");
     ::core::intrinsics::unreachable()
 }
 
@@ -554,26 +827,14 @@ unsafe fn ret_to_userspace(init: &mut Task) -> ! {
     };
 
     {
-        use x86_64::registers::msr;
-
         let tlsbase = init.kern_stack.as_ref().map(|st| st.top()).unwrap()
             - ::core::mem::size_of::<TLSSegment>();
         let tls = &*(tlsbase as *const TLSSegment);
-        if tls.kern_rsp != 0 {
-            msr::wrmsr(msr::IA32_GS_BASE, tls.kern_rsp as u64);
-        }
-
-        interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(tls.kern_rsp);
-
-        // alternate way to write rsp0
-        //let rsp0: usize;
-        //asm!("movq %gs:(8), $0":"=r"(rsp0)::"memory":"volatile");
-        //interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(rsp0);
-        //printk!(Debug, "{:?} set TSS.rsp0\n", frame);
+        smp::this_cpu().set_kernel_stack(tls.kern_rsp);
     }
 
 
-    paging::switch(init.cr3.clone().unwrap());
+    paging::switch(init.space.as_ref().unwrap().table());
 
 
     asm!("
@@ -593,68 +854,94 @@ unsafe fn ret_to_userspace(init: &mut Task) -> ! {
     ::core::intrinsics::unreachable()
 }
 
+/// release the task this cpu switched away from, its context is saved by
+/// now. runs on the stack of the task switched to.
+extern "C" fn finish_switch() {
+    let prev = smp::this_cpu().take_prev();
+    if prev != 0 {
+        release(prev);
+    }
+}
+
+/// pick the next task for this cpu. every cpu walks the shared task list
+/// round robin from its current task, skipping idle tasks, tasks running
+/// elsewhere and sleeping tasks. keeps running current when there is nothing
+/// else, unless it is asleep, then the idle task of this cpu runs.
 pub unsafe fn sched() {
     use ::kern::arch::cpu::flags;
     let oflags = flags::flags();
     assert!(!oflags.contains(flags::Flags::IF), "sched: should disable IF\n");
 
-    let id = CURRENT_ID.load(Ordering::SeqCst);
+    let cpu = smp::this_cpu();
+    let id = cpu.current();
     if id == 0 { return  }
 
-    let nid;
+    let now = clock::now();
+    let mut nid = 0;
     let current: *mut Task;
+    let current_runnable;
     let mut next: *mut Task = 0 as *mut Task;
 
     {
         let tasks = TaskList::get();
-        nid = if id + 1 >= tasks.next_id as ProcId { 1 } else { id + 1 };
-        CURRENT_ID.store(nid, Ordering::Release);
-
-        assert_ne!(id, nid, "sched: id should not be equal to nid");
 
         {
             let current_lock = tasks.get_task(id as ProcId).expect("sched: get current task error");
-            let mut guard = current_lock.try_read().expect("sched: current lock failed");
+            // another cpu may be posting it a signal, that is brief
+            let guard = current_lock.read();
             current = guard.deref() as *const Task as *mut Task;
             assert!((*current).pid == id);
+            current_runnable = guard.is_runnable(now);
         }
 
-        {
-            let next_lock = tasks.get_task(nid as ProcId).expect("sched: get next task error");
+        let after = tasks.keys().filter(|&&pid| pid > id);
+        let before = tasks.keys().filter(|&&pid| pid < id);
+        let idle = cpu.idle();
+        // the idle task comes last, and only if current can't go on
+        let fallback = if current_runnable || id == idle { None } else { Some(idle) };
+        for &pid in after.chain(before).filter(|&&pid| !smp::is_idle(pid)).chain(fallback.iter()) {
+            if !claim(pid) {
+                continue;
+            }
+
+            let next_lock = tasks.get_task(pid).expect("sched: get next task error");
             match next_lock.try_write() {
-                Some(mut guard) => {
+                Some(mut guard) if guard.is_runnable(now) => {
+                    if let TaskState::Sleep = guard.state {
+                        guard.state = TaskState::Ready;
+                    }
                     next = guard.deref_mut() as *mut Task;
-                    assert!((*next).pid == nid);
+                    assert!((*next).pid == pid);
+                    nid = pid;
+                    break;
                 },
-                None => {
-                    printk!(Critical, "sched: next({}) lock failed\n\r", nid);
-                }
+                _ => release(pid)
             };
         }
         //now tasklist lock released
     }
 
+    if next.is_null() {
+        return;
+    }
+
     //printk!(Debug, "switch {} {:#x} to {} {:#x}\n", id, (&*current).ctx.rsp, nid, (&*next).ctx.rsp);
     //printk!(Debug, "switch {:?} \n-> {:?}\n", (&*current).ctx, (&*next).ctx);
 
-    if next as usize != 0 {
-        use x86_64::registers::msr;
-        
-        let next = &mut *next;
-        if next.user_stack.is_some() { // which means it's a user task
-            let tlsbase = next.kern_stack.as_ref().map(|st| st.top()).unwrap()
-                - ::core::mem::size_of::<TLSSegment>();
-            let tls = &*(tlsbase as *const TLSSegment);
-            if tls.kern_rsp != 0 {
-                msr::wrmsr(msr::IA32_GS_BASE, tls.kern_rsp as u64);
-            }
-            interrupts::TSS.privilege_stack_table[0] = x86_64::VirtualAddress(tls.kern_rsp);
+    cpu.set_prev(id);
+    cpu.set_current(nid);
 
-            if (*current).ctx.cr3 != next.ctx.cr3 {
-                paging::switch(next.cr3.clone().unwrap());
-            }
-        }
-        switch_to(&mut *current, &mut *next); 
+    let next = &mut *next;
+    if next.space.is_some() {
+        let tlsbase = next.kern_stack.as_ref().map(|st| st.top()).unwrap()
+            - ::core::mem::size_of::<TLSSegment>();
+        let tls = &*(tlsbase as *const TLSSegment);
+        cpu.set_kernel_stack(tls.kern_rsp);
     }
+    // kernel tasks too, no cpu may stay on the tables of a task that dies
+    if ::kern::arch::cpu::cr3() != next.ctx.cr3 {
+        ::kern::arch::cpu::cr3_set(next.ctx.cr3);
+    }
+    switch_to(&mut *current, next);
+    finish_switch();
 }
-
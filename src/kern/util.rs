@@ -13,3 +13,75 @@ pub fn cpu_relax() {
 pub fn cpu_relax() {
 }
 
+
+use collections::Vec;
+
+/// fixed capacity FIFO for events produced by interrupt handlers.
+/// when full, the oldest entry is dropped.
+pub struct RingBuffer<T: Copy + Default> {
+    buf: Vec<T>,
+    head: usize,
+    len: usize
+}
+
+impl<T: Copy + Default> RingBuffer<T> {
+    pub fn new(capacity: usize) -> RingBuffer<T> {
+        assert!(capacity > 0);
+        RingBuffer {
+            buf: vec![T::default(); capacity],
+            head: 0,
+            len: 0
+        }
+    }
+
+    /// return false if an old entry got overwritten
+    pub fn push(&mut self, val: T) -> bool {
+        let cap = self.buf.len();
+        let tail = (self.head + self.len) % cap;
+        self.buf[tail] = val;
+
+        if self.len == cap {
+            self.head = (self.head + 1) % cap;
+            false
+        } else {
+            self.len += 1;
+            true
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let val = self.buf[self.head];
+        self.head = (self.head + 1) % self.buf.len();
+        self.len -= 1;
+        Some(val)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+/// halt until cond holds. cond is checked with interrupts disabled, and
+/// `sti; hlt` can not miss a wakeup since sti takes effect after hlt.
+pub fn wait_for<F>(cond: F) where F: Fn() -> bool {
+    use ::kern::arch::cpu;
+
+    let oflags = unsafe { cpu::push_flags() };
+    while !cond() {
+        unsafe { asm!("sti; hlt; cli" :::: "volatile"); }
+    }
+    unsafe { cpu::pop_flags(oflags); }
+}
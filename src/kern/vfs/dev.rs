@@ -0,0 +1,109 @@
+use super::{Error, Result};
+use ::kern::memory::paging::PhysicalAddress;
+
+use ::kern::arch::cpu;
+use ::kern::util::RingBuffer;
+use ::kern::task::{self, WaitQueue};
+use super::O_NONBLOCK;
+
+use core::mem::size_of;
+use core::ptr;
+use collections::Vec;
+use spin::{Mutex, RwLock};
+
+const DEV_PREFIX: &'static str = "/dev/";
+
+/// character device, all methods except name are optional.
+/// devices are static singletons, so they take &self and
+/// synchronize internally.
+pub trait Device: Sync {
+    fn name(&self) -> &'static str;
+
+    fn open(&self, _flags: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&self) {
+    }
+
+    fn read(&self, _buf: &mut [u8], _flags: usize) -> Result<usize> {
+        Err(Error::NotSupported)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::NotSupported)
+    }
+
+    fn ioctl(&self, _cmd: usize, _arg: usize) -> Result<usize> {
+        Err(Error::NotSupported)
+    }
+
+    /// physical address of device memory at `offset`, which should be
+    /// contiguous for `len` bytes. used by mmap
+    fn mmap(&self, _offset: usize, _len: usize) -> Result<PhysicalAddress> {
+        Err(Error::NotSupported)
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: RwLock<Vec<&'static Device>> = RwLock::new(Vec::new());
+}
+
+pub fn register(dev: &'static Device) {
+    use ::kern::console::LogLevel::*;
+
+    let mut devs = DEVICES.write();
+    assert!(devs.iter().all(|d| d.name() != dev.name()), "device {} registered twice", dev.name());
+    printk!(Info, "register device {}{}\n\r", DEV_PREFIX, dev.name());
+    devs.push(dev);
+}
+
+/// path should be like /dev/fb0
+pub fn lookup(path: &str) -> Option<&'static Device> {
+    if !path.starts_with(DEV_PREFIX) {
+        return None;
+    }
+
+    let name = &path[DEV_PREFIX.len()..];
+    DEVICES.read().iter().find(|d| d.name() == name).map(|&d| d)
+}
+
+/// read helper for event devices: copy as many whole records from queue
+/// as fit into buf. unless O_NONBLOCK, sleeps on waiters until one is
+/// available or a signal comes. producers wake waiters after a push.
+pub fn read_records<T: Copy + Default>(queue: &Mutex<RingBuffer<T>>, waiters: &WaitQueue,
+                                       buf: &mut [u8], flags: usize) -> Result<usize> {
+    let sz = size_of::<T>();
+    if buf.len() < sz {
+        return Err(Error::Invalid);
+    }
+
+    if flags & O_NONBLOCK == 0 && !task::wait_event(waiters, || !queue.lock().is_empty()) {
+        return Err(Error::Interrupted);
+    }
+
+    // producers are irq handlers. buf is user memory, which may fault, so
+    // it's only touched once the queue is unlocked
+    let oflags = unsafe { cpu::push_flags() };
+    let mut records = Vec::new();
+    {
+        let mut queue = queue.lock();
+        while (records.len() + 1) * sz <= buf.len() {
+            match queue.pop() {
+                Some(r) => records.push(r),
+                None => break
+            }
+        }
+    }
+    unsafe { cpu::pop_flags(oflags); }
+
+    let n = records.len() * sz;
+    unsafe {
+        ptr::copy_nonoverlapping(records.as_ptr() as *const u8, buf.as_mut_ptr(), n);
+    }
+
+    match n {
+        0 => Err(Error::WouldBlock),
+        _ => Ok(n)
+    }
+}
@@ -1,3 +1,7 @@
+pub mod dev;
+
+use core::fmt;
+
 pub type NodeId = usize;
 pub const ROOT_ID: NodeId = 1;
 
@@ -30,3 +34,58 @@ pub trait File {
 pub trait FileSystem {
 
 }
+
+/// errors returned by vfs and device operations, values are the same as
+/// linux errno so syscalls can return them as is (negated)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Error {
+    NotPermitted = 1,
+    NoEntry      = 2,
+    NoProcess    = 3,
+    Interrupted  = 4,
+    BadFd        = 9,
+    WouldBlock   = 11,
+    NoMemory     = 12,
+    Fault        = 14,
+    Busy         = 16,
+    Invalid      = 22,
+    TooManyFiles = 24,
+    NotSupported = 38,
+}
+
+impl Error {
+    pub fn errno(&self) -> isize {
+        -(*self as isize)
+    }
+}
+
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// open flags
+pub const O_RDONLY: usize = 0x0000;
+pub const O_WRONLY: usize = 0x0001;
+pub const O_RDWR: usize = 0x0002;
+pub const O_NONBLOCK: usize = 0x0800;
+
+pub const MAX_FILES: usize = 16;
+
+/// an entry of task's file table
+#[derive(Clone, Copy)]
+pub struct OpenFile {
+    pub dev: &'static dev::Device,
+    pub flags: usize,
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpenFile {{ dev: {}, flags: {:#x} }}", self.dev.name(), self.flags)
+    }
+}
+
+/// only device nodes under /dev exist right now
+pub fn open(path: &str, flags: usize) -> Result<OpenFile> {
+    let dev = dev::lookup(path).ok_or(Error::NoEntry)?;
+    dev.open(flags)?;
+    Ok(OpenFile { dev: dev, flags: flags })
+}
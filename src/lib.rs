@@ -39,6 +39,7 @@ use kern::memory;
 use kern::interrupts;
 use kheap_allocator as kheap;
 use kern::driver::video::{Framebuffer, Point, Rgba};
+use kern::driver::video::splash::{self, Stage};
 use kern::task;
 use kern::syscall;
 
@@ -87,6 +88,7 @@ fn display(fb: &mut Framebuffer) {
         fb.draw_str(Point{x:40, y: 550}, b"Loading SOS...", Rgba(0x000000ff), Rgba(0x00ff0000));
         fb.blit_copy(Point{x: 200, y: 100}, Point{x: 40, y: 550},  200, 20);
         fb.blit_copy(Point{x: 150, y: 150}, Point{x: 50, y: 50}, 350, 350);
+        fb.flush();
 
         printk!(Debug, "loop {}\n\r", g);
     }
@@ -134,22 +136,21 @@ pub extern fn kernel_main(mb2_header: usize) {
     };
     printk!(Debug, "_start {:#X}, _end {:#X}, sp top: {:#X}\n\r", pa, pe, sp_top);
 
-    let fb = mbinfo.framebuffer_tag().expect("framebuffer tag is unavailale");
+    kern::bootinfo::init(mbinfo);
+    printk!(Info, "cmdline: {}\n\r", kern::bootinfo::cmdline());
+
+    let fb_tag = mbinfo.framebuffer_tag().expect("framebuffer tag is unavailale");
     let mm = memory::init(mbinfo);
+    kern::ksyms::init(mbinfo);
 
     //if cfg!(feature = "test") { test_kheap_allocator(); }
 
-    {
-        let mut mm = mm.lock();
-        interrupts::init(&mut mm);
-        if cfg!(feature = "test") { interrupts::test_idt(); }
-    }
-
-    if fb.frame_type == multiboot2::FramebufferType::Rgb {
+    if fb_tag.frame_type == multiboot2::FramebufferType::Rgb {
         use kern::arch::cpu;
         //NOTE: if I dont use console in timer, then there is no reason to disable IF here.
         let oflags = unsafe { cpu::push_flags() };
-        let mut fb = Framebuffer::new(&fb);
+        let mut fb = Framebuffer::new(&fb_tag);
+        printk!(Debug, "framebuffer {}x{} pitch {} {:?}\n\r", fb.width, fb.height, fb.pitch, fb.format);
         //if cfg!(feature = "test") { display(&mut fb); }
 
         {
@@ -159,8 +160,29 @@ pub extern fn kernel_main(mb2_header: usize) {
 
         con::clear();
         println!("framebuffer console init.\n\r");
+        kern::driver::video::fbdev::init();
         //if cfg!(feature = "test") { for b in 1..127u8 { print!("{}", b as char); } }
         unsafe { cpu::pop_flags(oflags); }
+
+        splash::init(&fb_tag);
+    }
+    splash::progress(Stage::Memory);
+
+    kern::acpi::init();
+    kern::smp::init();
+
+    {
+        let mut mm = mm.lock();
+        interrupts::init(&mut mm);
+        if cfg!(feature = "test") { interrupts::test_idt(); }
+    }
+    kern::clock::init();
+    kern::driver::rtc::init();
+    kern::smp::boot_aps();
+    splash::progress(Stage::Interrupts);
+
+    if kern::driver::mouse::present() {
+        kern::driver::video::cursor::init();
     }
 
     task::init();
@@ -170,14 +192,14 @@ pub extern fn kernel_main(mb2_header: usize) {
     }
 }
 
-/// Get a stack trace
-/// TODO: extract symbol names from kernel elf
-unsafe fn stack_trace() {
+/// Get a stack trace, with return addresses resolved to kernel symbols
+pub unsafe fn stack_trace() {
     use core::mem;
+    use kern::ksyms::Symbolize;
     let mut rbp: usize;
     asm!("" : "={rbp}"(rbp) : : : "intel", "volatile");
 
-    println!("backtrace: {:>016x}", rbp);
+    println_nowait!("backtrace: {:>016x}", rbp);
     //Maximum 64 frames
     let active_table = memory::paging::ActivePML4Table::new();
     for _frame in 0..64 {
@@ -186,17 +208,17 @@ unsafe fn stack_trace() {
                 active_table.translate(rip_rbp).is_some() {
                 let rip = *(rip_rbp as *const usize);
                 if rip == 0 {
-                    println!(" {:>016x}: EMPTY RETURN", rbp);
+                    println_nowait!(" {:>016x}: EMPTY RETURN", rbp);
                     break;
                 }
-                println!("  {:>016x}: ret rip {:>016x}", rbp, rip);
+                println_nowait!("  {:>016x}: ret rip {:>016x} {}", rbp, rip, Symbolize(rip));
                 rbp = *(rbp as *const usize);
             } else {
-                println!("  {:>016x}: Invalid", rbp);
+                println_nowait!("  {:>016x}: Invalid", rbp);
                 break;
             }
         } else {
-            println!("  {:>016x}: RBP OVERFLOW", rbp);
+            println_nowait!("  {:>016x}: RBP OVERFLOW", rbp);
         }
     }
 }
@@ -206,10 +228,12 @@ extern fn eh_personality() {}
 
 #[lang = "panic_fmt"] 
 #[no_mangle] pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
+    splash::abort();
 	printk!(Critical, "\n\rPanic at {}:{}\n\r", file, line);
     printk!(Critical, "    {}\n\r", fmt);
 
     unsafe { stack_trace(); }
+    kern::power::on_panic();
 
     loop {
         unsafe { asm!("hlt":::: "volatile"); }
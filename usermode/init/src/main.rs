@@ -5,10 +5,42 @@
 
 extern crate libsos2;
 
+use libsos2::syscall::*;
+use libsos2::fb::Framebuffer;
+
+/// draws what kernel's display() used to draw, but from userspace
+pub fn fb_demo() {
+    let mut fb = match Framebuffer::open() {
+        Some(fb) => fb,
+        None => {
+            write(1, b"no framebuffer");
+            return;
+        }
+    };
+
+    let (w, h) = (fb.info.width, fb.info.height);
+    // vertical gradient from green to red
+    for y in 0..h {
+        let g = 0xff - y * 0xff / h;
+        let r = y * 0xff / h;
+        fb.fill_rect(0, y, w, 1, (r << 16) | (g << 8));
+    }
+
+    fb.fill_rect(200, 200, 200, 100, 0x808080);
+    fb.fill_rect(200, 310, 300, 100, 0xa00080);
+    fb.fill_rect(200, 420, 390, 100, 0xe00080);
+
+    let mut i = 1;
+    while i < 10000000 {
+        unsafe {
+            asm!("pause":::"memory":"volatile");
+        }
+        i += 1;
+    }
+    // fb gets closed here and console comes back
+}
+
 pub fn test() {
-    let mut a0 = 1;
-    let mut a1 = 2;
-    let mut a2 = 3;
     let mut a3 = 4;
     let mut a4 = 5;
     let mut a5 = 6;
@@ -16,27 +48,8 @@ pub fn test() {
 
     loop {
         unsafe {
-            asm!("
-                pushq %rcx
-                pushq %r11
-                 syscall
-                 popq %r11
-                 popq %rcx"
-                 :
-                 :"{rax}"(16), // write is 16
-                 "{rdi}"(a0),
-                 "{rsi}"(buf as *const _ as usize),
-                 "{rdx}"(9),
-                 "{r8}"(a3),
-                 "{r9}"(a4),
-                 "{r10}"(a5)
-                 :"rcx", "r11"
-                 :"volatile"
-                 ); 
+            syscall6(SYS_WRITE, 1, buf as *const _ as usize, buf.len(), a3, a4, a5);
         }
-        a0 += 1;
-        a1 += 1;
-        a2 += 1;
         a3 += 1;
         a4 += 1;
         a5 += 1;
@@ -54,6 +67,7 @@ pub fn test() {
 #[no_mangle]
 #[start]
 pub fn start(_argc: isize, _argv: *const *const u8) -> isize {
+    fb_demo();
     test();
     0
 }
@@ -0,0 +1,104 @@
+use core::ptr::write_volatile;
+use syscall::*;
+
+/// ioctl of /dev/fb0: fill a FbInfo
+pub const FBIO_GET_INFO: usize = 0x4600;
+
+/// the same layout as kernel's
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u32,
+    pub red_pos: u8,
+    pub red_size: u8,
+    pub green_pos: u8,
+    pub green_size: u8,
+    pub blue_pos: u8,
+    pub blue_size: u8,
+    pub reserved: [u8; 2],
+    pub size: u32,
+}
+
+impl FbInfo {
+    /// convert 0xRRGGBB into native pixel
+    pub fn encode(&self, rgb: u32) -> u32 {
+        fn channel(v: u32, pos: u8, size: u8) -> u32 {
+            let v = if size >= 8 { v << (size - 8) } else { v >> (8 - size) };
+            v << pos
+        }
+
+        channel((rgb >> 16) & 0xff, self.red_pos, self.red_size) |
+            channel((rgb >> 8) & 0xff, self.green_pos, self.green_size) |
+            channel(rgb & 0xff, self.blue_pos, self.blue_size)
+    }
+}
+
+/// mmapped /dev/fb0, unmapped and closed when dropped
+pub struct Framebuffer {
+    fd: usize,
+    base: *mut u8,
+    pub info: FbInfo,
+}
+
+impl Framebuffer {
+    pub fn open() -> Option<Framebuffer> {
+        let fd = open("/dev/fb0", O_RDWR);
+        if fd < 0 {
+            return None;
+        }
+        let fd = fd as usize;
+
+        let mut info = FbInfo::default();
+        if ioctl(fd, FBIO_GET_INFO, &mut info as *mut _ as usize) < 0 {
+            close(fd);
+            return None;
+        }
+
+        let base = mmap(0, info.size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        if base < 0 {
+            close(fd);
+            return None;
+        }
+
+        Some(Framebuffer { fd: fd, base: base as *mut u8, info: info })
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, rgb: u32) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
+        let bytes_pp = ((self.info.bpp + 7) / 8) as isize;
+        let v = self.info.encode(rgb);
+        unsafe {
+            let p = self.base.offset(y as isize * self.info.pitch as isize + x as isize * bytes_pp);
+            match bytes_pp {
+                4 => write_volatile(p as *mut u32, v),
+                3 => {
+                    write_volatile(p, v as u8);
+                    write_volatile(p.offset(1), (v >> 8) as u8);
+                    write_volatile(p.offset(2), (v >> 16) as u8);
+                },
+                _ => write_volatile(p as *mut u16, v as u16),
+            }
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, rgb: u32) {
+        for j in y..y+h {
+            for i in x..x+w {
+                self.put_pixel(i, j, rgb);
+            }
+        }
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        munmap(self.base as usize, self.info.size as usize);
+        close(self.fd);
+    }
+}
@@ -0,0 +1,115 @@
+//! Global allocator for user programs, on top of sbrk and mmap.
+//!
+//! Requests up to a page go to power-of-two size classes. A block of a
+//! class is carved from the program break aligned to its size, so any
+//! alignment up to the size holds, and freed blocks go onto a free list
+//! of their class. Bigger requests get their own anonymous mapping.
+//! User tasks are single threaded, so there is no locking.
+
+use alloc::heap::{Alloc, AllocErr, Layout};
+use core::ptr;
+
+use syscall::*;
+
+const PAGE_SIZE: usize = 4096;
+const MIN_CLASS: usize = 4; // 16 bytes
+const MAX_CLASS: usize = 12; // a page
+const NR_CLASSES: usize = MAX_CLASS - MIN_CLASS + 1;
+
+/// a freed block, linked through its first word
+struct FreeBlock {
+    next: *mut FreeBlock
+}
+
+struct Heap {
+    free: [*mut FreeBlock; NR_CLASSES],
+    /// unused part of the break, [top, end)
+    top: usize,
+    end: usize
+}
+
+static mut HEAP: Heap = Heap {
+    free: [0 as *mut FreeBlock; NR_CLASSES],
+    top: 0,
+    end: 0
+};
+
+/// size class of layout, None if it needs its own mapping
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = ::core::cmp::max(layout.size(), layout.align());
+    let size = ::core::cmp::max(size, 1 << MIN_CLASS).next_power_of_two();
+    let class = size.trailing_zeros() as usize;
+    if class <= MAX_CLASS { Some(class - MIN_CLASS) } else { None }
+}
+
+impl Heap {
+    /// take a fresh block of 2^(class + MIN_CLASS) bytes from the break
+    unsafe fn carve(&mut self, class: usize) -> *mut u8 {
+        let size = 1 << (class + MIN_CLASS);
+        if self.end == 0 {
+            let brk = sbrk(0);
+            if brk < 0 {
+                return ptr::null_mut();
+            }
+            self.top = brk as usize;
+            self.end = brk as usize;
+        }
+
+        let start = (self.top + size - 1) & !(size - 1);
+        if start + size > self.end {
+            // grow by whole pages, the kernel maps them on first touch
+            let grow = (start + size - self.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            if sbrk(grow as isize) < 0 {
+                return ptr::null_mut();
+            }
+            self.end += grow;
+        }
+
+        self.top = start + size;
+        start as *mut u8
+    }
+
+    unsafe fn alloc(&mut self, class: usize) -> *mut u8 {
+        let head = self.free[class];
+        if !head.is_null() {
+            self.free[class] = (*head).next;
+            return head as *mut u8;
+        }
+        self.carve(class)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, class: usize) {
+        let block = ptr as *mut FreeBlock;
+        (*block).next = self.free[class];
+        self.free[class] = block;
+    }
+}
+
+pub struct Allocator;
+
+unsafe impl<'a> Alloc for &'a Allocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let ptr = match class_of(&layout) {
+            Some(class) => HEAP.alloc(class),
+            None if layout.align() <= PAGE_SIZE => {
+                let addr = mmap(0, layout.size(), PROT_READ | PROT_WRITE,
+                                MAP_PRIVATE | MAP_ANONYMOUS, 0, 0);
+                if addr < 0 { ptr::null_mut() } else { addr as *mut u8 }
+            },
+            None => ptr::null_mut()
+        };
+
+        if ptr.is_null() {
+            Err(AllocErr::Exhausted { request: layout })
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => HEAP.dealloc(ptr, class),
+            None => { munmap(ptr as usize, layout.size()); }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use core::mem::{size_of, uninitialized};
+use syscall::*;
+
+/// KeyPacket.status bits
+pub const KB_PRESS: u16 = 0x0010;
+pub const KB_RELEASE: u16 = 0x0020;
+pub const KB_SHIFT_DOWN: u16 = 0x0100;
+pub const KB_CTRL_DOWN: u16 = 0x0200;
+pub const KB_ALT_DOWN: u16 = 0x0400;
+
+/// the same layout as kernel's, keycode is the raw KeyCode value
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KeyPacket {
+    /// milliseconds since boot
+    pub timestamp: u64,
+    pub keycode: u16,
+    pub status: u16,
+    /// character from current keymap, 0 if none
+    pub ch: u8,
+}
+
+impl KeyPacket {
+    pub fn pressed(&self) -> bool {
+        self.status & KB_PRESS != 0
+    }
+}
+
+/// /dev/kbd, closed when dropped
+pub struct Keyboard {
+    fd: usize,
+}
+
+impl Keyboard {
+    pub fn open(nonblock: bool) -> Option<Keyboard> {
+        let flags = if nonblock { O_RDONLY | O_NONBLOCK } else { O_RDONLY };
+        let fd = open("/dev/kbd", flags);
+        if fd < 0 {
+            return None;
+        }
+        Some(Keyboard { fd: fd as usize })
+    }
+
+    /// None if nothing is pending in non-blocking mode
+    pub fn next(&mut self) -> Option<KeyPacket> {
+        let mut packet: KeyPacket = unsafe { uninitialized() };
+        let buf = unsafe {
+            ::core::slice::from_raw_parts_mut(&mut packet as *mut _ as *mut u8, size_of::<KeyPacket>())
+        };
+
+        match read(self.fd, buf) {
+            n if n as usize == size_of::<KeyPacket>() => Some(packet),
+            _ => None
+        }
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
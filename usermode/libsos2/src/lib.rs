@@ -2,13 +2,25 @@
 #![feature(const_fn)]
 #![feature(unique)]
 #![feature(asm)]
-//#![feature(alloc, collections)]
+#![feature(alloc, collections)]
+#![feature(global_allocator)]
+#![feature(allocator_api)]
 #![feature(naked_functions)]
 #![no_std]
 
 extern crate rlibc;
-//extern crate alloc;
-//#[macro_use] extern crate collections;
+extern crate alloc;
+#[macro_use] extern crate collections;
+
+pub mod syscall;
+pub mod fb;
+pub mod input;
+pub mod heap;
+pub mod signal;
+
+/// lets user programs use Box, Vec and String
+#[global_allocator]
+static ALLOCATOR: heap::Allocator = heap::Allocator;
 
 #[allow(dead_code)]
 fn busy_wait () {
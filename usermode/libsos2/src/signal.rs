@@ -0,0 +1,144 @@
+//! Signals. Handlers return through `restorer`, which sigaction and signal
+//! hand to the kernel, into sigreturn.
+
+use syscall::*;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGSTKFLT: usize = 16;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGXCPU: usize = 24;
+pub const SIGXFSZ: usize = 25;
+pub const SIGVTALRM: usize = 26;
+pub const SIGPROF: usize = 27;
+pub const SIGWINCH: usize = 28;
+pub const SIGIO: usize = 29;
+pub const SIGPWR: usize = 30;
+pub const SIGSYS: usize = 31;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+pub const SA_RESTORER: usize = 0x04000000;
+pub const SA_NODEFER: usize = 0x40000000;
+pub const SA_RESETHAND: usize = 0x80000000;
+
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// bit sig - 1 for sig
+pub type SigSet = u64;
+
+pub fn sigmask(sig: usize) -> SigSet {
+    1 << (sig - 1)
+}
+
+/// a handler gets the signal number and a pointer to the interrupted
+/// context, which it may change before returning
+pub type Handler = extern "C" fn(usize, *mut SigContext);
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub restorer: usize,
+    pub mask: SigSet
+}
+
+impl SigAction {
+    pub fn new(handler: Handler, mask: SigSet, flags: usize) -> SigAction {
+        SigAction {
+            handler: handler as usize,
+            flags: flags,
+            restorer: 0,
+            mask: mask
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SigContext {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64
+}
+
+/// handlers return here, with rsp right above the return address
+#[naked]
+unsafe extern "C" fn restorer() -> ! {
+    asm!("movq $0, %rax
+          syscall" :: "i"(SYS_SIGRETURN) : "rax" : "volatile");
+    loop {}
+}
+
+pub fn kill(pid: isize, sig: usize) -> isize {
+    unsafe { syscall3(SYS_KILL, pid as usize, sig, 0) }
+}
+
+/// handler may be SIG_DFL or SIG_IGN too. returns the old handler
+pub fn signal(sig: usize, handler: usize) -> isize {
+    unsafe { syscall3(SYS_SIGNAL, sig, handler, restorer as usize) }
+}
+
+pub fn sigaction(sig: usize, act: Option<&SigAction>, oldact: Option<&mut SigAction>) -> isize {
+    let act = act.map(|act| SigAction {
+        flags: act.flags | SA_RESTORER,
+        restorer: restorer as usize,
+        ..*act
+    });
+    let act = act.as_ref().map_or(0, |act| act as *const SigAction as usize);
+    let oldact = oldact.map_or(0, |old| old as *mut SigAction as usize);
+    unsafe { syscall3(SYS_SIGACTION, sig, act, oldact) }
+}
+
+pub fn sigpending(set: &mut SigSet) -> isize {
+    unsafe { syscall3(SYS_SIGPENDING, set as *mut SigSet as usize, 0, 0) }
+}
+
+pub fn sigprocmask(how: usize, set: Option<&SigSet>, oldset: Option<&mut SigSet>) -> isize {
+    let set = set.map_or(0, |set| set as *const SigSet as usize);
+    let oldset = oldset.map_or(0, |old| old as *mut SigSet as usize);
+    unsafe { syscall3(SYS_SIGPROCMASK, how, set, oldset) }
+}
+
+/// wait with mask blocked for a signal, always fails with EINTR
+pub fn sigsuspend(mask: &SigSet) -> isize {
+    unsafe { syscall3(SYS_SIGSUSPEND, mask as *const SigSet as usize, 0, 0) }
+}
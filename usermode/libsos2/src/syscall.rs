@@ -0,0 +1,157 @@
+/// syscall numbers, keep in sync with kernel
+pub const SYS_READ: usize = 5;
+pub const SYS_KILL: usize = 6;
+pub const SYS_SBRK: usize = 12;
+pub const SYS_SLEEP: usize = 13;
+pub const SYS_UPTIME: usize = 14;
+pub const SYS_OPEN: usize = 15;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_CLOSE: usize = 21;
+pub const SYS_MMAP: usize = 25;
+pub const SYS_SIGNAL: usize = 32;
+pub const SYS_SIGACTION: usize = 33;
+pub const SYS_SIGPENDING: usize = 34;
+pub const SYS_SIGPROCMASK: usize = 35;
+pub const SYS_SIGSUSPEND: usize = 36;
+pub const SYS_SIGRETURN: usize = 37;
+pub const SYS_IOCTL: usize = 41;
+pub const SYS_SETKEYMAP: usize = 42;
+pub const SYS_REBOOT: usize = 43;
+pub const SYS_POWEROFF: usize = 44;
+pub const SYS_CLOCK_GETTIME: usize = 45;
+pub const SYS_GETTIMEOFDAY: usize = 46;
+pub const SYS_MUNMAP: usize = 47;
+pub const SYS_MPROTECT: usize = 48;
+
+pub const O_RDONLY: usize = 0x0000;
+pub const O_WRONLY: usize = 0x0001;
+pub const O_RDWR: usize = 0x0002;
+pub const O_NONBLOCK: usize = 0x0800;
+
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64
+}
+
+/// args go in rdi, rsi, rdx, r8, r9, r10. result comes back in rax,
+/// negative errno when failed
+#[inline(always)]
+pub unsafe fn syscall6(nr: usize, a0: usize, a1: usize, a2: usize,
+                       a3: usize, a4: usize, a5: usize) -> isize {
+    let ret: isize;
+    asm!("
+        pushq %rcx
+        pushq %r11
+         syscall
+         popq %r11
+         popq %rcx"
+         :"={rax}"(ret)
+         :"{rax}"(nr),
+         "{rdi}"(a0),
+         "{rsi}"(a1),
+         "{rdx}"(a2),
+         "{r8}"(a3),
+         "{r9}"(a4),
+         "{r10}"(a5)
+         :"rcx", "r11", "memory"
+         :"volatile");
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(nr: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    syscall6(nr, a0, a1, a2, 0, 0, 0)
+}
+
+pub fn open(path: &str, flags: usize) -> isize {
+    unsafe { syscall3(SYS_OPEN, path.as_ptr() as usize, path.len(), flags) }
+}
+
+pub fn close(fd: usize) -> isize {
+    unsafe { syscall3(SYS_CLOSE, fd, 0, 0) }
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> isize {
+    unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    unsafe { syscall3(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len()) }
+}
+
+pub fn ioctl(fd: usize, cmd: usize, arg: usize) -> isize {
+    unsafe { syscall3(SYS_IOCTL, fd, cmd, arg) }
+}
+
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    unsafe { syscall6(SYS_MMAP, addr, len, prot, flags, fd, offset) }
+}
+
+/// move the program break by increment bytes, returns the old break or a
+/// negative errno
+pub fn sbrk(increment: isize) -> isize {
+    unsafe { syscall3(SYS_SBRK, increment as usize, 0, 0) }
+}
+
+pub fn munmap(addr: usize, len: usize) -> isize {
+    unsafe { syscall3(SYS_MUNMAP, addr, len, 0) }
+}
+
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    unsafe { syscall3(SYS_MPROTECT, addr, len, prot) }
+}
+
+/// layout name like "us", "uk" or "de"
+pub fn setkeymap(name: &str) -> isize {
+    unsafe { syscall3(SYS_SETKEYMAP, name.as_ptr() as usize, name.len(), 0) }
+}
+
+/// nanoseconds
+pub fn sleep(nanos: usize) -> isize {
+    unsafe { syscall3(SYS_SLEEP, nanos, 0, 0) }
+}
+
+/// nanoseconds since boot
+pub fn uptime() -> isize {
+    unsafe { syscall3(SYS_UPTIME, 0, 0, 0) }
+}
+
+pub fn clock_gettime(id: usize, ts: &mut Timespec) -> isize {
+    unsafe { syscall3(SYS_CLOCK_GETTIME, id, ts as *mut Timespec as usize, 0) }
+}
+
+/// UTC, there is no timezone
+pub fn gettimeofday(tv: &mut Timeval) -> isize {
+    unsafe { syscall3(SYS_GETTIMEOFDAY, tv as *mut Timeval as usize, 0, 0) }
+}
+
+pub fn reboot() -> isize {
+    unsafe { syscall3(SYS_REBOOT, 0, 0, 0) }
+}
+
+/// under QEMU with isa-debug-exit, a non zero status exits with (status << 1) | 1
+pub fn poweroff(status: usize) -> isize {
+    unsafe { syscall3(SYS_POWEROFF, status, 0, 0) }
+}